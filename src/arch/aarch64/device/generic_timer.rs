@@ -1,4 +1,5 @@
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use super::ic_for_chip;
 use crate::{
@@ -15,6 +16,10 @@ use crate::{
 };
 use fdt::Fdt;
 
+/// Whether the virtual timer is used, set once the timer is initialized.
+static USE_VIRTUAL_TIMER: AtomicBool = AtomicBool::new(false);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 bitflags! {
     struct TimerCtrlFlags: u32 {
         const ENABLE = 1 << 0;
@@ -50,6 +55,8 @@ pub struct GenericTimer {
     pub use_virtual_timer: bool,
     pub clk_freq: u32,
     pub reload_count: u32,
+    /// Counter value at which the next scheduler tick is due.
+    next_tick: u64,
 }
 
 impl GenericTimer {
@@ -58,6 +65,7 @@ impl GenericTimer {
             use_virtual_timer: false,
             clk_freq: 0,
             reload_count: 0,
+            next_tick: 0,
         }
     }
     pub fn init(&mut self) {
@@ -69,6 +77,10 @@ impl GenericTimer {
         let clk_freq = unsafe { control_regs::cntfrq_el0() };
         self.clk_freq = clk_freq;
         self.reload_count = clk_freq / 100;
+        USE_VIRTUAL_TIMER.store(self.use_virtual_timer, Ordering::Relaxed);
+        crate::arch::time::init();
+        INITIALIZED.store(true, Ordering::Release);
+        self.next_tick = crate::arch::time::counter() + u64::from(self.reload_count);
         self.reload_count();
     }
 
@@ -111,32 +123,77 @@ impl GenericTimer {
         }
     }
 
+    /// Program the timer to fire at the next scheduler tick.
     pub fn reload_count(&mut self) {
-        if self.use_virtual_timer {
-            unsafe { control_regs::vtmr_tval_write(self.reload_count) };
-        } else {
-            unsafe { control_regs::ptmr_tval_write(self.reload_count) };
-        }
-        let mut ctrl = self.read_tmr_ctrl();
-        ctrl.insert(TimerCtrlFlags::ENABLE);
-        ctrl.remove(TimerCtrlFlags::IMASK);
-        self.write_tmr_ctrl(ctrl);
+        let remaining = self.next_tick.saturating_sub(crate::arch::time::counter());
+        write_tval(
+            self.use_virtual_timer,
+            remaining.min(u64::from(u32::MAX)) as u32,
+        );
+    }
+}
+
+fn write_tval(use_virtual_timer: bool, tval: u32) {
+    if use_virtual_timer {
+        unsafe { control_regs::vtmr_tval_write(tval) };
+    } else {
+        unsafe { control_regs::ptmr_tval_write(tval) };
+    }
+    let mut ctrl = TimerCtrlFlags::from_bits_truncate(if use_virtual_timer {
+        unsafe { control_regs::vtmr_ctrl() }
+    } else {
+        unsafe { control_regs::ptmr_ctrl() }
+    });
+    ctrl.insert(TimerCtrlFlags::ENABLE);
+    ctrl.remove(TimerCtrlFlags::IMASK);
+    if use_virtual_timer {
+        unsafe { control_regs::vtmr_ctrl_write(ctrl.bits()) };
+    } else {
+        unsafe { control_regs::ptmr_ctrl_write(ctrl.bits()) };
+    }
+}
+
+/// Make the timer fire no later than `deadline`, in counter ticks, unless it already will.
+pub fn program_oneshot(deadline: u64) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    let use_virtual_timer = USE_VIRTUAL_TIMER.load(Ordering::Relaxed);
+
+    // TVAL counts down to the programmed compare value, as a signed 32-bit value.
+    let remaining = if use_virtual_timer {
+        unsafe { control_regs::vtmr_tval() }
+    } else {
+        unsafe { control_regs::ptmr_tval() }
+    } as i32;
+    let wanted = deadline.saturating_sub(crate::arch::time::counter());
+
+    if remaining < 0 || wanted < remaining as u64 {
+        write_tval(use_virtual_timer, wanted.min(u64::from(u32::MAX)) as u32);
     }
 }
 
 impl InterruptHandler for GenericTimer {
     fn irq_handler(&mut self, irq: u32, token: &mut CleanLockToken) {
         self.clear_irq();
-        {
+
+        // The timer also fires early for timer deadlines, which are not scheduler ticks.
+        let now = crate::arch::time::counter();
+        let ticked = now >= self.next_tick;
+        if ticked {
             *time::OFFSET.lock() += self.clk_freq as u128;
+            self.next_tick = now + u64::from(self.reload_count);
         }
+        self.reload_count();
 
         timeout::trigger(token);
-        context::switch::tick(token);
+
+        if ticked {
+            context::switch::tick(token);
+        }
 
         unsafe {
             trigger(irq, token);
         }
-        self.reload_count();
     }
 }
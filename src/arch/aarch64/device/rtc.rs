@@ -1,7 +1,11 @@
 use crate::{dtb::get_mmio_address, time};
-use core::ptr::read_volatile;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Once;
 
 static RTC_DR: usize = 0x000;
+static RTC_LR: usize = 0x008;
+
+static RTC: Once<Pl031rtc> = Once::new();
 
 pub unsafe fn init(fdt: &fdt::Fdt) {
     if let Some(node) = fdt.find_compatible(&["arm,pl031"]) {
@@ -11,7 +15,7 @@ pub unsafe fn init(fdt: &fdt::Fdt) {
             .and_then(|region| get_mmio_address(fdt, &node, &region))
         {
             Some(phys) => {
-                let mut rtc = Pl031rtc { phys };
+                let rtc = RTC.call_once(|| Pl031rtc { phys });
                 info!("PL031 RTC at {:#x}", rtc.phys);
                 *time::START.lock() = (rtc.time() as u128) * time::NANOS_PER_SEC;
             }
@@ -24,7 +28,12 @@ pub unsafe fn init(fdt: &fdt::Fdt) {
     }
 }
 
-struct Pl031rtc {
+/// The PL031 RTC, if there is one.
+pub fn rtc() -> Option<&'static Pl031rtc> {
+    RTC.get()
+}
+
+pub struct Pl031rtc {
     pub phys: usize,
 }
 
@@ -33,8 +42,17 @@ impl Pl031rtc {
         unsafe { read_volatile((crate::PHYS_OFFSET + self.phys + reg) as *const u32) }
     }
 
-    pub fn time(&mut self) -> u64 {
+    unsafe fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((crate::PHYS_OFFSET + self.phys + reg) as *mut u32, value) }
+    }
+
+    pub fn time(&self) -> u64 {
         let seconds = unsafe { self.read(RTC_DR) } as u64;
         seconds
     }
+
+    /// Load a new time into the counter.
+    pub fn set_time(&self, seconds: u32) {
+        unsafe { self.write(RTC_LR, seconds) };
+    }
 }
@@ -25,6 +25,8 @@ pub unsafe fn kstop(token: &mut CleanLockToken) -> ! {
     unsafe {
         println!("kstop");
 
+        crate::time::sync_rtc_on_shutdown();
+
         asm!("hvc   #0",
              in("x0")  0x8400_0008_usize,
              options(noreturn),
@@ -1,11 +1,74 @@
-use crate::time::NANOS_PER_SEC;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub fn monotonic_absolute() -> u128 {
-    //TODO: aarch64 generic timer counter
-    let ticks: usize;
+use crate::{
+    clocksource::{self, ClockSource, ClockSourceFlags},
+    syscall::error::{Error, Result, EINVAL, ENODEV},
+    time::NANOS_PER_SEC,
+};
+
+static USER_COUNTER: AtomicBool = AtomicBool::new(false);
+
+/// Current value of the generic timer counter.
+pub fn counter() -> u64 {
+    let ticks: u64;
     unsafe { core::arch::asm!("mrs {}, cntpct_el0", out(reg) ticks) };
-    let freq: usize;
+    ticks
+}
+
+fn frequency() -> u64 {
+    let freq: u64;
     unsafe { core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq) };
+    freq
+}
+
+/// The clock used before any clock source is registered.
+pub fn monotonic_absolute() -> u128 {
+    counter() as u128 * NANOS_PER_SEC / frequency() as u128
+}
+
+static ARCH_TIMER: ClockSource = ClockSource {
+    name: "arch_sys_counter",
+    rating: 400,
+    flags: ClockSourceFlags::empty(),
+    read: || Some(monotonic_absolute()),
+};
+
+/// Register the generic timer counter as a clock source, and let userspace read it.
+pub fn init() {
+    clocksource::register(&ARCH_TIMER);
+    enable_user_counter();
+}
+
+/// Allow userspace to read the physical counter, which the monotonic clock is based on.
+fn enable_user_counter() {
+    let mut cntkctl: u64;
+    unsafe { core::arch::asm!("mrs {}, cntkctl_el1", out(reg) cntkctl) };
+    // EL0PCTEN
+    cntkctl |= 1 << 0;
+    unsafe { core::arch::asm!("msr cntkctl_el1, {}", in(reg) cntkctl) };
+    USER_COUNTER.store(true, Ordering::Relaxed);
+}
+
+/// Frequency of the counter, if userspace can read it to compute the monotonic clock.
+pub fn user_counter_frequency() -> Option<u64> {
+    USER_COUNTER.load(Ordering::Relaxed).then(frequency)
+}
+
+/// Make the timer interrupt fire no later than `deadline`, in monotonic nanoseconds.
+pub fn program_oneshot(deadline: u128) {
+    let ticks = deadline * frequency() as u128 / NANOS_PER_SEC;
+    super::device::generic_timer::program_oneshot(ticks.min(u64::MAX as u128) as u64);
+}
+
+/// Read the PL031 RTC, in seconds since the Unix epoch.
+pub fn rtc_time() -> Result<u64> {
+    let rtc = super::device::rtc::rtc().ok_or(Error::new(ENODEV))?;
+    Ok(rtc.time())
+}
 
-    ticks as u128 * NANOS_PER_SEC / freq as u128
+/// Set the PL031 RTC, in seconds since the Unix epoch.
+pub fn set_rtc_time(time: u64) -> Result<()> {
+    let rtc = super::device::rtc::rtc().ok_or(Error::new(ENODEV))?;
+    rtc.set_time(u32::try_from(time).map_err(|_| Error::new(EINVAL))?);
+    Ok(())
 }
@@ -20,7 +20,10 @@ use spin::Mutex;
 
 pub struct Clint {
     freq: u64,
+    /// Time at which the timer interrupt of each hart is programmed to fire.
     next_event: Vec<u64>,
+    /// Time at which the next scheduler tick of each hart is due.
+    next_tick: Vec<u64>,
 }
 
 pub static CLINT: Mutex<Option<Clint>> = Mutex::new(None);
@@ -35,7 +38,7 @@ struct ClintConnector {
 
 impl InterruptHandler for ClintConnector {
     fn irq_handler(&mut self, _irq: u32, token: &mut CleanLockToken) {
-        CLINT
+        let ticked = CLINT
             .lock()
             .as_mut()
             .unwrap()
@@ -44,7 +47,9 @@ impl InterruptHandler for ClintConnector {
             // a bit of hack, but it is a really bad idea to call scheduler
             // from inside clint irq handler
             timeout::trigger(token);
-            context::switch::tick(token);
+            if ticked {
+                context::switch::tick(token);
+            }
         }
     }
 }
@@ -67,6 +72,7 @@ impl Clint {
         let mut me = Self {
             freq: freq as u64,
             next_event: Vec::new(),
+            next_tick: Vec::new(),
         };
         let mut interrupts = node
             .property("interrupts-extended")
@@ -109,13 +115,17 @@ impl Clint {
             hart_id += 1;
         }
         me.next_event.resize_with(hart_id, || 0);
+        me.next_tick.resize_with(hart_id, || 0);
         me
     }
 
-    pub(crate) fn irq_handler(self: &mut Self, hart_id: usize, irq: usize) {
+    /// Returns whether a timer interrupt was a scheduler tick, rather than an earlier timer
+    /// deadline.
+    pub(crate) fn irq_handler(self: &mut Self, hart_id: usize, irq: usize) -> bool {
         match irq {
             IRQ_IPI => {
                 println!("IPI interrupt at {}", hart_id);
+                false
             }
             IRQ_TIMER => {
                 let mtime: usize;
@@ -126,9 +136,14 @@ impl Clint {
                     )
                 };
 
-                self.next_event[hart_id] =
-                    max(self.next_event[hart_id], mtime as u64) + self.freq / TICKS_PER_SECOND;
+                let ticked = mtime as u64 >= self.next_tick[hart_id];
+                if ticked {
+                    self.next_tick[hart_id] =
+                        max(self.next_tick[hart_id], mtime as u64) + self.freq / TICKS_PER_SECOND;
+                }
+                self.next_event[hart_id] = self.next_tick[hart_id];
                 sbi_rt::set_timer(self.next_event[hart_id]).expect("SBI timer cannot be set!");
+                ticked
             }
             _ => {
                 panic!("Unexpected CLINT irq")
@@ -145,6 +160,18 @@ impl Clint {
             )
         };
         self.next_event[hart] = mtime as u64 + (self.freq / TICKS_PER_SECOND);
+        self.next_tick[hart] = self.next_event[hart];
         sbi_rt::set_timer(self.next_event[hart]).expect("SBI timer cannot be set!");
     }
+
+    /// Make the timer interrupt of `hart` fire no later than `deadline`, in timer ticks.
+    pub fn program_oneshot(self: &mut Self, hart: usize, deadline: u64) {
+        let Some(next_event) = self.next_event.get_mut(hart) else {
+            return;
+        };
+        if deadline < *next_event {
+            *next_event = deadline;
+            sbi_rt::set_timer(deadline).expect("SBI timer cannot be set!");
+        }
+    }
 }
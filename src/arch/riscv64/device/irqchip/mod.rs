@@ -42,3 +42,13 @@ pub unsafe fn init_clint(fdt: &Fdt) {
     *clint::CLINT.lock() = Some(clint);
     clint::CLINT.lock().as_mut().unwrap().init(0);
 }
+
+/// Make the timer interrupt of the current hart fire no later than `deadline`, in timer ticks.
+pub fn program_timer(deadline: u64) {
+    // The timer interrupt handler takes this lock, so don't spin on it if interrupted.
+    if let Some(mut clint) = clint::CLINT.try_lock() {
+        if let Some(clint) = clint.as_mut() {
+            clint.program_oneshot(crate::cpu_id().get() as usize, deadline);
+        }
+    }
+}
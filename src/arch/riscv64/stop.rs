@@ -11,5 +11,6 @@ pub unsafe fn emergency_reset() -> ! {
 
 pub unsafe fn kstop(token: &mut CleanLockToken) -> ! {
     println!("kstop");
+    crate::time::sync_rtc_on_shutdown();
     unimplemented!()
 }
@@ -3,12 +3,36 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
+use crate::{
+    clocksource::{self, ClockSource, ClockSourceFlags},
+    syscall::error::{Error, Result, ENODEV},
+};
+
 static MTIME_FREQ_HZ: AtomicUsize = AtomicUsize::new(0);
 
+static RISCV_TIMER: ClockSource = ClockSource {
+    name: "riscv_clocksource",
+    rating: 300,
+    flags: ClockSourceFlags::empty(),
+    read: || Some(monotonic_absolute()),
+};
+
 pub fn init(freq_hz: usize) {
     MTIME_FREQ_HZ.store(freq_hz, Ordering::Relaxed);
+    clocksource::register(&RISCV_TIMER);
+    // Allow userspace to use rdtime, for the time page.
+    unsafe { asm!("csrs scounteren, {}", in(reg) 1usize << 1) };
 }
 
+/// Frequency of the counter, if userspace can read it to compute the monotonic clock.
+pub fn user_counter_frequency() -> Option<u64> {
+    match MTIME_FREQ_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        freq_hz => Some(freq_hz as u64),
+    }
+}
+
+/// The clock used before any clock source is registered.
 pub fn monotonic_absolute() -> u128 {
     let freq_hz = MTIME_FREQ_HZ.load(Ordering::Relaxed);
     if freq_hz > 0 {
@@ -24,3 +48,22 @@ pub fn monotonic_absolute() -> u128 {
         0
     }
 }
+
+/// Make the timer interrupt fire no later than `deadline`, in monotonic nanoseconds.
+pub fn program_oneshot(deadline: u128) {
+    let freq_hz = MTIME_FREQ_HZ.load(Ordering::Relaxed);
+    if freq_hz > 0 {
+        let ticks = deadline * freq_hz as u128 / 1_000_000_000u128;
+        super::device::irqchip::program_timer(ticks.min(u64::MAX as u128) as u64);
+    }
+}
+
+/// There is no RTC driver on this architecture.
+pub fn rtc_time() -> Result<u64> {
+    Err(Error::new(ENODEV))
+}
+
+/// There is no RTC driver on this architecture.
+pub fn set_rtc_time(_time: u64) -> Result<()> {
+    Err(Error::new(ENODEV))
+}
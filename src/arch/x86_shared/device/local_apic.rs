@@ -1,6 +1,7 @@
 use core::{
     cell::SyncUnsafeCell,
     ptr::{read_volatile, write_volatile},
+    sync::atomic::{self, AtomicU64, Ordering},
 };
 use x86::msr::*;
 
@@ -33,6 +34,12 @@ pub unsafe fn the_local_apic() -> &'static mut LocalApic {
     unsafe { &mut *LOCAL_APIC.get() }
 }
 
+/// Vector of the local APIC timer interrupt.
+const TIMER_VECTOR: u32 = 48;
+
+/// Frequency of the TSC in Hz if the TSC-deadline timer is used, 0 otherwise.
+static TSC_DEADLINE_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(active_table: &mut KernelMapper) {
     unsafe {
         the_local_apic().init(active_table);
@@ -281,6 +288,59 @@ impl LocalApic {
             self.set_lvt_error(vector);
         }
     }
+    unsafe fn setup_tsc_deadline_timer(&mut self) {
+        unsafe {
+            self.set_lvt_timer(TIMER_VECTOR | ((LvtTimerMode::TscDeadline as u32) << 17));
+            // The LVT write must be visible before IA32_TSC_DEADLINE is written, which is not
+            // serializing in xAPIC mode.
+            atomic::fence(Ordering::SeqCst);
+        }
+    }
+}
+
+/// Enable the TSC-deadline timer if the CPU supports it and the TSC frequency is known.
+pub fn init_tsc_deadline() {
+    if !cpuid()
+        .get_feature_info()
+        .is_some_and(|feature_info| feature_info.has_tsc_deadline())
+    {
+        debug!("TSC-deadline timer not supported");
+        return;
+    }
+    // Deadlines are computed on one CPU and may be armed on another, which requires an invariant
+    // TSC, whose frequency is only known in that case.
+    match crate::arch::time::tsc_frequency() {
+        Some(frequency) => {
+            debug!("TSC-deadline timer enabled");
+            TSC_DEADLINE_FREQUENCY.store(frequency, Ordering::Relaxed);
+        }
+        None => debug!("TSC-deadline timer not used, TSC frequency unknown"),
+    }
+}
+
+/// Arm the TSC-deadline timer of the current CPU to fire at `deadline`, in monotonic
+/// nanoseconds. Returns false if the TSC-deadline timer is not used.
+pub fn program_tsc_deadline(deadline: u128) -> bool {
+    let frequency = TSC_DEADLINE_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return false;
+    }
+
+    let misc = &PercpuBlock::current().misc_arch_info;
+    if !misc.tsc_deadline_enabled.get() {
+        unsafe { the_local_apic().setup_tsc_deadline_timer() };
+        misc.tsc_deadline_enabled.set(true);
+    }
+
+    let remaining = deadline.saturating_sub(crate::time::monotonic());
+    let ticks = (remaining * u128::from(frequency) / crate::time::NANOS_PER_SEC)
+        .try_into()
+        .unwrap_or(u64::MAX);
+    // Writing 0 disarms the timer, so a deadline that has already passed must still be nonzero.
+    let tsc = unsafe { x86::time::rdtsc() }.saturating_add(ticks).max(1);
+    unsafe { wrmsr(IA32_TSC_DEADLINE, tsc) };
+
+    true
 }
 
 #[repr(u8)]
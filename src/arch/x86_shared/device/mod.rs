@@ -8,6 +8,7 @@ pub mod ioapic;
 pub mod local_apic;
 pub mod pic;
 pub mod pit;
+pub mod rtc;
 pub mod serial;
 #[cfg(feature = "system76_ec_debug")]
 pub mod system76_ec;
@@ -57,17 +58,21 @@ pub unsafe fn init_noncore() {
         debug!("Initializing system timer");
 
         #[cfg(feature = "x86_kvm_pv")]
-        if tsc::init() {
-            debug!("TSC used as system clock source");
-        }
+        let kvm_clock = tsc::init();
+        #[cfg(not(feature = "x86_kvm_pv"))]
+        let kvm_clock = false;
 
-        if init_hpet() {
+        let hpet = init_hpet();
+        if hpet {
             debug!("HPET used as system timer");
         } else {
             pit::init();
             debug!("PIT used as system timer");
         }
 
+        crate::arch::time::init(hpet, kvm_clock);
+        local_apic::init_tsc_deadline();
+
         debug!("Finished initializing devices");
     }
 }
@@ -83,6 +88,7 @@ pub unsafe fn init_ap() {
 
 pub struct ArchPercpuMisc {
     pub apic_id_opt: Cell<Option<local_apic::ApicId>>,
+    pub tsc_deadline_enabled: Cell<bool>,
     #[cfg(feature = "x86_kvm_pv")]
     pub tsc_info: tsc::TscPercpu,
 }
@@ -91,6 +97,7 @@ impl ArchPercpuMisc {
     pub const fn default() -> Self {
         Self {
             apic_id_opt: Cell::new(None),
+            tsc_deadline_enabled: Cell::new(false),
             #[cfg(feature = "x86_kvm_pv")]
             tsc_info: tsc::TscPercpu::default(),
         }
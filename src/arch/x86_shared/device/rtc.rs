@@ -0,0 +1,186 @@
+//! CMOS real-time clock
+
+use spin::Mutex;
+
+use crate::syscall::{
+    error::{Error, Result, EINVAL, EIO},
+    io::{Io, Pio},
+};
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const HOURS_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Without the FADT there is no reliable century register, so the years are assumed to be
+// 2000-2099.
+const FIRST_YEAR: u64 = 2000;
+const LAST_YEAR: u64 = 2099;
+
+struct Cmos {
+    address: Pio<u8>,
+    data: Pio<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        self.address.write(reg);
+        self.data.read()
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        self.address.write(reg);
+        self.data.write(value);
+    }
+
+    fn read_fields(&mut self) -> [u8; 6] {
+        while self.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|reg| self.read(reg))
+    }
+}
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    address: Pio::new(0x70),
+    data: Pio::new(0x71),
+});
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date in the proleptic Gregorian calendar of a number of days since 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Read the RTC, in seconds since the Unix epoch.
+pub fn time() -> Result<u64> {
+    let mut cmos = CMOS.lock();
+
+    // Read until two reads agree, in case an update happened in between.
+    let mut fields = cmos.read_fields();
+    loop {
+        let again = cmos.read_fields();
+        if again == fields {
+            break;
+        }
+        fields = again;
+    }
+    let status_b = cmos.read(STATUS_B);
+    drop(cmos);
+
+    let [mut seconds, mut minutes, mut hours, mut day, mut month, mut year] = fields;
+    let pm = status_b & STATUS_B_24_HOUR == 0 && hours & HOURS_PM != 0;
+    hours &= !HOURS_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        [seconds, minutes, hours, day, month, year] =
+            [seconds, minutes, hours, day, month, year].map(from_bcd);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hours = hours % 12 + if pm { 12 } else { 0 };
+    }
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year > 99 {
+        return Err(Error::new(EIO));
+    }
+
+    let days = days_from_civil(
+        FIRST_YEAR + u64::from(year),
+        u64::from(month),
+        u64::from(day),
+    );
+    Ok(days * SECONDS_PER_DAY
+        + u64::from(hours) * 3600
+        + u64::from(minutes) * 60
+        + u64::from(seconds))
+}
+
+/// Set the RTC to a time in seconds since the Unix epoch.
+pub fn set_time(time: u64) -> Result<()> {
+    let days = time / SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+    if !(FIRST_YEAR..=LAST_YEAR).contains(&year) {
+        return Err(Error::new(EINVAL));
+    }
+    let seconds_of_day = time % SECONDS_PER_DAY;
+    // 1970-01-01 was a Thursday, and the RTC counts weekdays from Sunday = 1.
+    let weekday = (days + 4) % 7 + 1;
+
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(STATUS_B);
+    let encode = |value: u64| {
+        let value = value as u8;
+        if status_b & STATUS_B_BINARY == 0 {
+            to_bcd(value)
+        } else {
+            value
+        }
+    };
+
+    let mut hours = seconds_of_day / 3600;
+    let mut pm = 0;
+    if status_b & STATUS_B_24_HOUR == 0 {
+        if hours >= 12 {
+            pm = HOURS_PM;
+        }
+        hours = match hours % 12 {
+            0 => 12,
+            hours => hours,
+        };
+    }
+
+    // Stop updates while the fields are written, so that they change together.
+    cmos.write(STATUS_B, status_b | STATUS_B_SET);
+    cmos.write(SECONDS, encode(seconds_of_day % 60));
+    cmos.write(MINUTES, encode(seconds_of_day / 60 % 60));
+    cmos.write(HOURS, encode(hours) | pm);
+    cmos.write(WEEKDAY, encode(weekday));
+    cmos.write(DAY, encode(day));
+    cmos.write(MONTH, encode(month));
+    cmos.write(YEAR, encode(year - FIRST_YEAR));
+    cmos.write(STATUS_B, status_b & !STATUS_B_SET);
+
+    Ok(())
+}
@@ -1,5 +1,8 @@
 use crate::{
-    context, device::local_apic::the_local_apic, percpu::PercpuBlock, sync::CleanLockToken,
+    context::{self, timeout},
+    device::local_apic::the_local_apic,
+    percpu::PercpuBlock,
+    sync::CleanLockToken,
 };
 
 interrupt!(wakeup, || {
@@ -22,7 +25,11 @@ interrupt!(switch, || {
 interrupt!(pit, || {
     unsafe { the_local_apic().eoi() };
 
-    // Switch after a sufficient amount of time since the last switch.
     let mut token = unsafe { CleanLockToken::new() };
+
+    // Timers are kept per CPU, so every CPU fires its own.
+    timeout::trigger(&mut token);
+
+    // Switch after a sufficient amount of time since the last switch.
     context::switch::tick(&mut token);
 });
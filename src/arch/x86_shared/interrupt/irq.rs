@@ -303,8 +303,10 @@ interrupt!(ata2, || {
 });
 
 interrupt!(lapic_timer, || {
-    println!("Local apic timer interrupt");
+    // Only armed in TSC-deadline mode, for the earliest timer of this CPU.
     unsafe { lapic_eoi() };
+    let mut token = unsafe { CleanLockToken::new() };
+    timeout::trigger(&mut token);
 });
 #[cfg(feature = "profiling")]
 interrupt!(aux_timer, || {
@@ -93,6 +93,8 @@ pub unsafe fn kstop(token: &mut CleanLockToken) -> ! {
     unsafe {
         info!("Running kstop()");
 
+        crate::time::sync_rtc_on_shutdown();
+
         #[cfg(feature = "acpi")]
         userspace_acpi_shutdown(token);
 
@@ -1,7 +1,19 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 #[cfg(feature = "acpi")]
 use super::device::hpet;
-use super::device::pit;
+use super::device::{pit, rtc};
+use crate::{
+    arch::cpuid::cpuid,
+    clocksource::{self, ClockSource, ClockSourceFlags},
+    syscall::error::Result,
+    time::NANOS_PER_SEC,
+};
+
+/// Frequency of the TSC in Hz if it is invariant and its frequency is known, 0 otherwise.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
 
+/// The clock used before any clock source is registered.
 pub fn monotonic_absolute() -> u128 {
     // The paravirtualized TSC is already guaranteed to be monotonic, and thus doesn't need to be
     // readjusted.
@@ -12,36 +24,213 @@ pub fn monotonic_absolute() -> u128 {
 
     *crate::time::OFFSET.lock() + hpet_or_pit()
 }
-fn hpet_or_pit() -> u128 {
+
+#[cfg(feature = "x86_kvm_pv")]
+static KVM_CLOCK: ClockSource = ClockSource {
+    name: "kvm-clock",
+    rating: 400,
+    flags: ClockSourceFlags::empty(),
+    read: super::device::tsc::monotonic_absolute,
+};
+
+static TSC: ClockSource = ClockSource {
+    name: "tsc",
+    rating: 300,
+    flags: ClockSourceFlags::MUST_VERIFY,
+    read: || {
+        let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+        let ticks = unsafe { x86::time::rdtsc() };
+        (frequency != 0).then(|| u128::from(ticks) * NANOS_PER_SEC / u128::from(frequency))
+    },
+};
+
+#[cfg(feature = "acpi")]
+static HPET: ClockSource = ClockSource {
+    name: "hpet",
+    rating: 250,
+    flags: ClockSourceFlags::WATCHDOG,
+    read: || Some(*crate::time::OFFSET.lock() + hpet_elapsed()?),
+};
+
+static PIT: ClockSource = ClockSource {
+    name: "pit",
+    rating: 110,
+    flags: ClockSourceFlags::WATCHDOG,
+    read: || Some(*crate::time::OFFSET.lock() + pit_elapsed()),
+};
+
+/// Determine the TSC frequency and register the clock sources. Must be called after the system
+/// timer has been initialized, with interrupts disabled.
+pub unsafe fn init(hpet: bool, kvm_clock: bool) {
+    let cpuid = cpuid();
+    // Only an invariant TSC runs at a constant rate and is synchronized between CPUs.
+    let invariant = cpuid
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|info| info.has_invariant_tsc());
+    if invariant {
+        let frequency = cpuid
+            .get_tsc_info()
+            .and_then(|info| info.tsc_frequency())
+            .or_else(|| unsafe { calibrate_tsc(hpet) })
+            .unwrap_or(0);
+        debug!("TSC at {} kHz", frequency / 1000);
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    } else {
+        debug!("TSC is not invariant");
+    }
+
+    #[cfg(feature = "x86_kvm_pv")]
+    if kvm_clock {
+        clocksource::register(&KVM_CLOCK);
+    }
+    #[cfg(not(feature = "x86_kvm_pv"))]
+    let _ = kvm_clock;
+
+    if tsc_frequency().is_some() {
+        clocksource::register(&TSC);
+    }
+
+    #[cfg(feature = "acpi")]
+    if hpet {
+        clocksource::register(&HPET);
+    }
+    if !hpet {
+        clocksource::register(&PIT);
+    }
+}
+
+/// Frequency of the TSC in Hz, if it is invariant and its frequency is known.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Measure the TSC frequency against the HPET main counter if the HPET is the system timer, or
+/// against the PIT otherwise.
+unsafe fn calibrate_tsc(hpet: bool) -> Option<u64> {
+    // Calibrate over about 20 milliseconds.
+    const CALIBRATION_FS: u128 = 20_000_000_000_000;
+
     #[cfg(feature = "acpi")]
-    if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
-        //TODO: handle rollover?
-        //TODO: improve performance
-
-        // Current count
-        let counter = unsafe { hpet.read_u64(hpet::MAIN_COUNTER_OFFSET) };
-        // Comparator holds next interrupt count
-        let comparator = unsafe { hpet.read_u64(hpet::T0_COMPARATOR_OFFSET) };
-        // Get period in femtoseconds
-        let capability = unsafe { hpet.read_u64(hpet::CAPABILITY_OFFSET) };
-
-        // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
-        // period_fs and hence a divide by zero calculating the divisor - workaround it while we
-        // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
-        let mut period_fs = capability >> 32;
+    if hpet && let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
+        let period_fs = unsafe { hpet.read_u64(hpet::CAPABILITY_OFFSET) } >> 32;
         if period_fs == 0 {
-            period_fs = 10_000_000;
+            return None;
         }
+        let ticks = (CALIBRATION_FS / u128::from(period_fs)) as u64;
 
-        // Calculate divisor
-        let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
-        // Calculate last interrupt
-        let last_interrupt = comparator.saturating_sub(divisor);
-        // Calculate ticks since last interrupt
-        let elapsed = counter.saturating_sub(last_interrupt);
-        // Calculate nanoseconds since last interrupt
-        return (elapsed as u128 * period_fs as u128) / 1_000_000;
+        let start = unsafe { hpet.read_u64(hpet::MAIN_COUNTER_OFFSET) };
+        let tsc_start = unsafe { x86::time::rdtsc() };
+        let mut elapsed;
+        loop {
+            elapsed = unsafe { hpet.read_u64(hpet::MAIN_COUNTER_OFFSET) }.wrapping_sub(start);
+            if elapsed >= ticks {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        let tsc_elapsed = unsafe { x86::time::rdtsc() } - tsc_start;
+
+        let elapsed_fs = u128::from(elapsed) * u128::from(period_fs);
+        return Some((u128::from(tsc_elapsed) * 1_000_000_000_000_000 / elapsed_fs) as u64);
     }
+
+    #[cfg(not(feature = "acpi"))]
+    let _ = hpet;
+
+    // The PIT counts up to its divisor and then wraps around, so count whole periods.
+    let periods = CALIBRATION_FS.div_ceil(pit::RATE * 1_000_000) as u64;
+    let wait_for_wrap = || {
+        let mut last = unsafe { pit::read() };
+        loop {
+            let now = unsafe { pit::read() };
+            if now < last {
+                break;
+            }
+            last = now;
+            core::hint::spin_loop();
+        }
+    };
+
+    wait_for_wrap();
+    let tsc_start = unsafe { x86::time::rdtsc() };
+    for _ in 0..periods {
+        wait_for_wrap();
+    }
+    let tsc_elapsed = unsafe { x86::time::rdtsc() } - tsc_start;
+
+    let elapsed_fs = u128::from(periods) * pit::RATE * 1_000_000;
+    Some((u128::from(tsc_elapsed) * 1_000_000_000_000_000 / elapsed_fs) as u64)
+}
+
+/// Frequency of the TSC, if it is the current clock source. The PIT and HPET counters cannot be
+/// read from userspace, so the time page cannot be used with them.
+pub fn user_counter_frequency() -> Option<u64> {
+    tsc_frequency().filter(|_| clocksource::is_current(&TSC))
+}
+
+/// Read the CMOS RTC, in seconds since the Unix epoch.
+pub fn rtc_time() -> Result<u64> {
+    rtc::time()
+}
+
+/// Set the CMOS RTC, in seconds since the Unix epoch.
+pub fn set_rtc_time(time: u64) -> Result<()> {
+    rtc::set_time(time)
+}
+
+/// Arm the TSC-deadline timer of the current CPU, if it is used. Otherwise, the periodic PIT
+/// interrupt checks the timers of every CPU on each tick.
+pub fn program_oneshot(deadline: u128) {
+    super::device::local_apic::program_tsc_deadline(deadline);
+}
+
+fn hpet_or_pit() -> u128 {
+    #[cfg(feature = "acpi")]
+    if let Some(elapsed) = hpet_elapsed() {
+        return elapsed;
+    }
+    pit_elapsed()
+}
+
+/// Nanoseconds since the last HPET interrupt, if there is a HPET.
+#[cfg(feature = "acpi")]
+fn hpet_elapsed() -> Option<u128> {
+    let hpet = crate::acpi::ACPI_TABLE.hpet.read();
+    let hpet = hpet.as_ref()?;
+
+    //TODO: handle rollover?
+    //TODO: improve performance
+
+    // Current count
+    let counter = unsafe { hpet.read_u64(hpet::MAIN_COUNTER_OFFSET) };
+    // Comparator holds next interrupt count
+    let comparator = unsafe { hpet.read_u64(hpet::T0_COMPARATOR_OFFSET) };
+    // Get period in femtoseconds
+    let capability = unsafe { hpet.read_u64(hpet::CAPABILITY_OFFSET) };
+
+    // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
+    // period_fs and hence a divide by zero calculating the divisor - workaround it while we
+    // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
+    let mut period_fs = capability >> 32;
+    if period_fs == 0 {
+        period_fs = 10_000_000;
+    }
+
+    // Calculate divisor
+    let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
+    // Calculate last interrupt
+    let last_interrupt = comparator.saturating_sub(divisor);
+    // Calculate ticks since last interrupt
+    let elapsed = counter.saturating_sub(last_interrupt);
+    // Calculate nanoseconds since last interrupt
+    Some((elapsed as u128 * period_fs as u128) / 1_000_000)
+}
+
+/// Nanoseconds since the last PIT interrupt.
+fn pit_elapsed() -> u128 {
     // Read ticks since last interrupt
     let elapsed = unsafe { pit::read() };
     // Calculate nanoseconds since last interrupt
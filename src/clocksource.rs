@@ -0,0 +1,299 @@
+//! Registry of the clock sources the monotonic clock can be read from.
+//!
+//! Every architecture registers the counters it can read, each with a rating, and the highest
+//! rated one is used unless another one was selected through `sys:clocksource`. Clock sources
+//! that may stop or drift, like the TSC, are periodically compared against a watchdog clock
+//! source, and are no longer used once they disagree.
+//!
+//! Switching clock sources adds an offset to the new one so that the monotonic clock stays
+//! continuous. This includes the switch from the boot clock of the architecture to the first
+//! clock source, as they do not necessarily count from the same origin.
+
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+
+use crate::{
+    context::timeout,
+    sync::CleanLockToken,
+    syscall::{
+        error::{Error, Result, EINVAL},
+        flag::CLOCK_MONOTONIC,
+    },
+    time::{self, NANOS_PER_SEC},
+};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct ClockSourceFlags: u32 {
+        /// The clock source must be checked against the watchdog before it can be trusted.
+        const MUST_VERIFY = 1 << 0;
+        /// The clock source can be used as the watchdog of other clock sources.
+        const WATCHDOG = 1 << 1;
+    }
+}
+
+pub struct ClockSource {
+    pub name: &'static str,
+    /// Higher rated clock sources are preferred.
+    pub rating: u32,
+    pub flags: ClockSourceFlags,
+    /// Read the clock source in nanoseconds, or None if it cannot be read on this CPU.
+    pub read: fn() -> Option<u128>,
+}
+
+const MAX_CLOCK_SOURCES: usize = 8;
+const NONE: usize = usize::MAX;
+
+/// Interval at which clock sources are checked against the watchdog.
+const WATCHDOG_INTERVAL: u128 = NANOS_PER_SEC / 2;
+/// Largest difference between a clock source and the watchdog over one interval. The watchdog
+/// clock sources only advance with the periodic timer interrupt, so this must be larger than a
+/// late tick.
+const WATCHDOG_THRESHOLD: u128 = 10_000_000;
+
+struct Slot {
+    source: Once<&'static ClockSource>,
+    /// Added to the clock source when it is the current one. Only changed while it is not.
+    offset: AtomicI64,
+    unstable: AtomicBool,
+}
+
+static SLOTS: [Slot; MAX_CLOCK_SOURCES] = [const {
+    Slot {
+        source: Once::new(),
+        offset: AtomicI64::new(0),
+        unstable: AtomicBool::new(false),
+    }
+}; MAX_CLOCK_SOURCES];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Index of the clock source in use, or NONE to use the boot clock of the architecture.
+static CURRENT: AtomicUsize = AtomicUsize::new(NONE);
+/// Index of the clock source selected through `sys:clocksource`, or NONE to use the ratings.
+static SELECTED: AtomicUsize = AtomicUsize::new(NONE);
+/// Serializes registering and switching clock sources. Monotonic clock readers do not take it.
+static SWITCH_LOCK: Mutex<()> = Mutex::new(());
+
+struct Watchdog {
+    /// Index of the watchdog clock source the samples were taken with.
+    source: usize,
+    /// Last reading of every clock source being verified, with the watchdog reading.
+    samples: [Option<(u128, u128)>; MAX_CLOCK_SOURCES],
+}
+
+static WATCHDOG: Mutex<Watchdog> = Mutex::new(Watchdog {
+    source: NONE,
+    samples: [None; MAX_CLOCK_SOURCES],
+});
+
+fn sources() -> impl Iterator<Item = (usize, &'static Slot, &'static ClockSource)> {
+    SLOTS[..COUNT.load(Ordering::Acquire)]
+        .iter()
+        .enumerate()
+        .filter_map(|(index, slot)| Some((index, slot, *slot.source.get()?)))
+}
+
+fn is_usable(index: usize) -> bool {
+    SLOTS
+        .get(index)
+        .is_some_and(|slot| slot.source.is_completed() && !slot.unstable.load(Ordering::Relaxed))
+}
+
+/// Read the current clock source, or None if the boot clock should be used.
+pub fn read() -> Option<u128> {
+    let slot = SLOTS.get(CURRENT.load(Ordering::Acquire))?;
+    let time = (slot.source.get()?.read)()?;
+    Some((time as i128 + i128::from(slot.offset.load(Ordering::Relaxed))).max(0) as u128)
+}
+
+/// Add a clock source, and switch to it if it is the best one.
+pub fn register(source: &'static ClockSource) {
+    let _guard = SWITCH_LOCK.lock();
+
+    let index = COUNT.load(Ordering::Relaxed);
+    let Some(slot) = SLOTS.get(index) else {
+        warn!("Too many clock sources, ignoring {}", source.name);
+        return;
+    };
+    slot.source.call_once(|| source);
+    COUNT.store(index + 1, Ordering::Release);
+    debug!(
+        "Registered clock source {} ({})",
+        source.name, source.rating
+    );
+
+    reselect();
+}
+
+/// Switch to the selected clock source, or to the highest rated one. Must hold `SWITCH_LOCK`.
+fn reselect() {
+    let selected = SELECTED.load(Ordering::Relaxed);
+    let best = if is_usable(selected) {
+        Some(selected)
+    } else {
+        sources()
+            .filter(|(_, slot, _)| !slot.unstable.load(Ordering::Relaxed))
+            .max_by_key(|(_, _, source)| source.rating)
+            .map(|(index, _, _)| index)
+    };
+    let Some(best) = best else {
+        return;
+    };
+
+    let current = CURRENT.load(Ordering::Relaxed);
+    if best == current {
+        return;
+    }
+
+    let slot = &SLOTS[best];
+    let Some(source) = slot.source.get() else {
+        return;
+    };
+    let now = time::monotonic();
+    let Some(time) = (source.read)() else {
+        return;
+    };
+    let offset = (now as i128 - time as i128).clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+    slot.offset.store(offset, Ordering::Relaxed);
+    CURRENT.store(best, Ordering::Release);
+
+    info!("Switched to clock source {}", source.name);
+    // The time page is filled in at boot, after the first clock source is registered.
+    if current != NONE {
+        time::update_time_page();
+    }
+}
+
+/// Whether the clock source is the one in use.
+pub fn is_current(source: &'static ClockSource) -> bool {
+    SLOTS
+        .get(CURRENT.load(Ordering::Acquire))
+        .and_then(|slot| slot.source.get())
+        .is_some_and(|current| core::ptr::eq(*current, source))
+}
+
+/// The offset added to the current clock source.
+pub fn offset() -> i64 {
+    SLOTS
+        .get(CURRENT.load(Ordering::Acquire))
+        .map_or(0, |slot| slot.offset.load(Ordering::Relaxed))
+}
+
+/// Start checking the clock sources that need to be verified against the watchdog.
+pub fn init_watchdog(token: &mut CleanLockToken) {
+    if sources().any(|(_, _, source)| source.flags.contains(ClockSourceFlags::MUST_VERIFY)) {
+        schedule_watchdog(token);
+    }
+}
+
+fn schedule_watchdog(token: &mut CleanLockToken) {
+    timeout::register_callback(
+        CLOCK_MONOTONIC,
+        time::monotonic() + WATCHDOG_INTERVAL,
+        watchdog,
+        (0, 0),
+        token,
+    );
+}
+
+fn watchdog(_: usize, _: u128, token: &mut CleanLockToken) {
+    if check_sources() {
+        // If a clock source is being switched on this CPU, try again on the next interval.
+        if let Some(_guard) = SWITCH_LOCK.try_lock() {
+            reselect();
+        }
+    }
+    schedule_watchdog(token);
+}
+
+/// Compare the clock sources that must be verified against the watchdog, and mark those that
+/// drifted as unstable. Returns true if a clock source may have to be switched.
+fn check_sources() -> bool {
+    let mut watchdog = WATCHDOG.lock();
+
+    let Some((wd_index, _, wd_source)) = sources()
+        .filter(|(_, slot, source)| {
+            source.flags.contains(ClockSourceFlags::WATCHDOG)
+                && !source.flags.contains(ClockSourceFlags::MUST_VERIFY)
+                && !slot.unstable.load(Ordering::Relaxed)
+        })
+        .max_by_key(|(_, _, source)| source.rating)
+    else {
+        return false;
+    };
+    if watchdog.source != wd_index {
+        watchdog.source = wd_index;
+        watchdog.samples = [None; MAX_CLOCK_SOURCES];
+    }
+    let Some(wd_now) = (wd_source.read)() else {
+        return false;
+    };
+
+    let mut changed = false;
+    for (index, slot, source) in sources() {
+        if !source.flags.contains(ClockSourceFlags::MUST_VERIFY)
+            || slot.unstable.load(Ordering::Relaxed)
+        {
+            continue;
+        }
+        let Some(now) = (source.read)() else {
+            continue;
+        };
+        if let Some((last, wd_last)) = watchdog.samples[index].replace((now, wd_now)) {
+            let elapsed = now as i128 - last as i128;
+            let wd_elapsed = wd_now as i128 - wd_last as i128;
+            if elapsed.abs_diff(wd_elapsed) > WATCHDOG_THRESHOLD {
+                warn!(
+                    "Clock source {} is unstable: {} ns elapsed, {} ns on {}",
+                    source.name, elapsed, wd_elapsed, wd_source.name
+                );
+                slot.unstable.store(true, Ordering::Relaxed);
+                changed = true;
+            }
+        }
+    }
+    changed || !is_usable(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Select a clock source by name, or go back to using the highest rated one with `auto`.
+pub fn sys_clocksource(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
+    let name = buf.trim_ascii();
+
+    let _guard = SWITCH_LOCK.lock();
+    let selected = if name == b"auto" {
+        NONE
+    } else {
+        let (index, _, _) = sources()
+            .find(|(_, _, source)| source.name.as_bytes() == name)
+            .ok_or(Error::new(EINVAL))?;
+        if !is_usable(index) {
+            return Err(Error::new(EINVAL));
+        }
+        index
+    };
+    SELECTED.store(selected, Ordering::Relaxed);
+    reselect();
+
+    Ok(buf.len())
+}
+
+pub struct ClockSourceInfo {
+    pub name: &'static str,
+    pub rating: u32,
+    pub flags: ClockSourceFlags,
+    pub current: bool,
+    pub selected: bool,
+    pub unstable: bool,
+}
+
+pub fn info() -> impl Iterator<Item = ClockSourceInfo> {
+    let current = CURRENT.load(Ordering::Relaxed);
+    let selected = SELECTED.load(Ordering::Relaxed);
+    sources().map(move |(index, slot, source)| ClockSourceInfo {
+        name: source.name,
+        rating: source.rating,
+        flags: source.flags,
+        current: index == current,
+        selected: index == selected,
+        unstable: slot.unstable.load(Ordering::Relaxed),
+    })
+}
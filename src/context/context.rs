@@ -11,7 +11,7 @@ use syscall::{SigProcControl, Sigcontrol, UPPER_FDTBL_TAG};
 use crate::{
     arch::{interrupt::InterruptStack, paging::PAGE_SIZE},
     common::aligned_box::AlignedBox,
-    context::{self, arch, file::FileDescriptor},
+    context::{self, arch, file::FileDescriptor, timeout::TimerId},
     cpu_set::{LogicalCpuId, LogicalCpuSet},
     cpu_stats,
     ipi::{ipi, IpiKind, IpiTarget},
@@ -20,6 +20,7 @@ use crate::{
     percpu::PercpuBlock,
     scheme::{CallerCtx, FileHandle, SchemeId, SchemeNamespace},
     sync::CleanLockToken,
+    syscall::filter::Filters,
 };
 
 use crate::syscall::error::{Error, Result, EAGAIN, EBADF, EEXIST, EINVAL, EMFILE, ESRCH};
@@ -71,6 +72,11 @@ pub enum HardBlockedReason {
 
 const CONTEXT_NAME_CAPAC: usize = 32;
 
+/// Number of I/O priority levels, where 0 is the most urgent.
+pub const IO_PRIORITY_LEVELS: u8 = 8;
+/// I/O priority of new contexts. Only root can set a more urgent one.
+pub const DEFAULT_IO_PRIORITY: u8 = 4;
+
 #[derive(Debug)]
 pub enum SyscallFrame {
     Free(RaiiFrame),
@@ -114,6 +120,8 @@ pub struct Context {
     pub syscall_tail: SyscallFrame,
     /// Context should wake up at specified time
     pub wake: Option<u128>,
+    /// Timer that wakes the context up at `wake`, cancelled once it is no longer needed
+    pub wake_timer: Option<TimerId>,
     /// The architecture specific context
     pub arch: arch::Context,
     /// Kernel FX - used to store SIMD and FPU registers on context switch
@@ -143,6 +151,16 @@ pub struct Context {
     pub euid: u32,
     pub egid: u32,
     pub pid: usize,
+    /// Pid of the process a kernel context runs operations for, shown to schemes as the caller
+    /// instead of `pid`.
+    pub acting_for: Option<usize>,
+
+    /// Priority of the requests of this context to userspace schemes, up to
+    /// `IO_PRIORITY_LEVELS - 1`, with lower values served first.
+    pub io_priority: u8,
+
+    /// Syscall filters, inherited by the contexts this one sets up.
+    pub syscall_filters: Filters,
 }
 
 #[derive(Debug)]
@@ -179,6 +197,7 @@ impl Context {
             syscall_head: SyscallFrame::Free(RaiiFrame::allocate()?),
             syscall_tail: SyscallFrame::Free(RaiiFrame::allocate()?),
             wake: None,
+            wake_timer: None,
             arch: arch::Context::new(),
             kfx: AlignedBox::<[u8], { arch::KFX_ALIGN }>::try_zeroed_slice(crate::arch::kfx_size())?,
             kstack: None,
@@ -194,6 +213,9 @@ impl Context {
             euid: 0,
             egid: 0,
             pid: 0,
+            acting_for: None,
+            io_priority: DEFAULT_IO_PRIORITY,
+            syscall_filters: Filters::default(),
 
             #[cfg(feature = "syscall_debug")]
             syscall_debug_info: crate::syscall::debug::SyscallDebugInfo::default(),
@@ -421,9 +443,13 @@ impl Context {
         CallerCtx {
             uid: self.euid,
             gid: self.egid,
-            pid: self.pid,
+            pid: self.caller_pid(),
         }
     }
+    /// The pid schemes see as the caller of the requests of this context.
+    pub fn caller_pid(&self) -> usize {
+        self.acting_for.unwrap_or(self.pid)
+    }
 }
 
 /// Wrapper struct for borrowing the syscall head or tail buf.
@@ -4,7 +4,7 @@ use crate::{
     event,
     scheme::{self, SchemeId},
     sync::CleanLockToken,
-    syscall::error::{Error, Result, EBADF},
+    syscall::error::{Error, Result, ENODEV},
 };
 use alloc::sync::Arc;
 use spin::RwLock;
@@ -27,6 +27,9 @@ bitflags! {
     #[derive(Clone, Copy, Debug)]
     pub struct InternalFlags: u32 {
         const POSITIONED = 1;
+        /// Opened through a name bound to a subpath of its scheme. Paths relative to it are
+        /// refused, as the scheme could resolve them outside of the subpath.
+        const BOUND = 1 << 1;
     }
 }
 impl FileDescription {
@@ -72,11 +75,11 @@ impl FileDescription {
     /// Try closing a file, although at this point the description will be destroyed anyway, if
     /// doing so fails.
     pub fn try_close(self, token: &mut CleanLockToken) -> Result<()> {
-        event::unregister_file(self.scheme, self.number);
+        event::unregister_file(self.scheme, self.number, token);
 
         let scheme = scheme::schemes(token.token())
             .get(self.scheme)
-            .ok_or(Error::new(EBADF))?
+            .ok_or(Error::new(ENODEV))?
             .clone();
 
         scheme.close(self.number, token)
@@ -22,6 +22,7 @@ use crate::{
     percpu::PercpuBlock,
     scheme::{self, KernelSchemes},
     sync::CleanLockToken,
+    time,
 };
 
 use super::{context::HardBlockedReason, file::FileDescription};
@@ -159,6 +160,16 @@ impl AddrSpaceWrapper {
         let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
 
         for (grant_base, grant_info) in guard.grants.iter() {
+            // The new address space already has its own mapping of the time page.
+            if matches!(grant_info.provider, Provider::AllocatedShared { .. })
+                && grant_info.page_count == 1
+                && this_mapper
+                    .translate(grant_base.start_address())
+                    .is_some_and(|(phys, _)| phys == time::time_page_frame().base())
+            {
+                continue;
+            }
+
             let new_grant = match grant_info.provider {
                 // No, your temporary UserScheme mappings will not be kept across forks.
                 Provider::External {
@@ -551,12 +562,25 @@ impl AddrSpace {
     }
 
     pub fn new() -> Result<Self> {
-        Ok(Self {
+        let mut this = Self {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
             used_by: LogicalCpuSet::empty(),
-        })
+        };
+
+        // Map the time page, so that userspace can read the clocks without syscalls.
+        let time_page = Grant::allocated_shared_one_page(
+            time::time_page_frame(),
+            Page::containing_address(VirtualAddress::new(time::TIME_PAGE_ADDRESS)),
+            page_flags(MapFlags::PROT_READ),
+            &mut this.table.utable,
+            &mut NopFlusher,
+            false,
+        )?;
+        this.grants.insert(time_page);
+
+        Ok(this)
     }
     fn munmap_inner(
         this_grants: &mut UserGrants,
@@ -1122,7 +1146,7 @@ impl Grant {
         page: Page,
         flags: PageFlags<RmmA>,
         mapper: &mut PageMapper,
-        flusher: &mut Flusher,
+        flusher: &mut impl GenericFlusher,
         is_pinned: bool,
     ) -> Result<Grant> {
         let info = get_page_info(frame).expect("needs page info");
@@ -1162,6 +1186,69 @@ impl Grant {
         })
     }
 
+    /// Share frames owned by the kernel, which need not be contiguous, at consecutive pages
+    /// starting at `base`.
+    pub fn allocated_shared(
+        frames: &[Frame],
+        base: Page,
+        flags: PageFlags<RmmA>,
+        mapper: &mut PageMapper,
+        flusher: &mut impl GenericFlusher,
+    ) -> Result<Grant> {
+        for (i, &frame) in frames.iter().enumerate() {
+            let info = get_page_info(frame).expect("needs page info");
+            if info.add_ref(RefKind::Shared).is_err() {
+                Self::unmap_allocated_shared(&frames[..i], base, mapper, flusher);
+                return Err(Error::new(ENOMEM));
+            }
+
+            let Some(result) =
+                (unsafe { mapper.map_phys(base.next_by(i).start_address(), frame.base(), flags) })
+            else {
+                if info.remove_ref().is_none() {
+                    unsafe { deallocate_frame(frame) };
+                }
+                Self::unmap_allocated_shared(&frames[..i], base, mapper, flusher);
+                return Err(Error::new(ENOMEM));
+            };
+            unsafe {
+                result.ignore();
+            }
+            flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+        }
+
+        Ok(Grant {
+            base,
+            info: GrantInfo {
+                page_count: frames.len(),
+                flags,
+                mapped: true,
+                provider: Provider::AllocatedShared {
+                    is_pinned_userscheme_borrow: false,
+                },
+            },
+        })
+    }
+
+    /// Undo a partial `allocated_shared`, where `frames` were mapped starting at `base`.
+    fn unmap_allocated_shared(
+        frames: &[Frame],
+        base: Page,
+        mapper: &mut PageMapper,
+        flusher: &mut impl GenericFlusher,
+    ) {
+        for (i, &frame) in frames.iter().enumerate() {
+            unsafe {
+                let (_, _, flush) = mapper
+                    .unmap_phys(base.next_by(i).start_address(), true)
+                    .expect("pages mapped by allocated_shared must still be present");
+                flush.ignore();
+            }
+            // Drops the reference taken for the mapping once the TLBs are flushed.
+            flusher.queue(frame, None, TlbShootdownActions::FREE);
+        }
+    }
+
     pub fn physmap(
         phys: Frame,
         span: PageSpan,
@@ -2631,9 +2718,10 @@ fn correct_inner<'l>(
                 .ok_or(PfError::Segv)?;
 
             let offset = file_ref.base_offset as u64 + (pages_from_grant_start * PAGE_SIZE) as u64;
+            // The scheme handler may have gone away.
             user_inner
                 .request_fmap(scheme_number, offset, 1, flags, token)
-                .unwrap();
+                .map_err(|_| PfError::Segv)?;
 
             let context_lock = crate::context::current();
             context_lock
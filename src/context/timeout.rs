@@ -1,7 +1,23 @@
-use alloc::collections::VecDeque;
-use spin::Once;
+//! Per-CPU timer queues, used for `time:` timeouts and to wake up sleeping contexts.
+//!
+//! Every CPU keeps its timers in min-heaps ordered by deadline, one per clock, so registering a
+//! timer is `O(log n)` and expiring timers only looks at the earliest ones. Whenever a timer is
+//! registered or fired, the CPU's timer interrupt is reprogrammed to fire no later than the
+//! earliest deadline, on architectures that support one-shot timers.
+//!
+//! Cancelled timers are only forgotten at first, and their heap entries skipped when they expire.
+//! Once the skipped entries outnumber the live timers, the heaps are rebuilt without them.
+
+use alloc::{
+    collections::BinaryHeap,
+    sync::{Arc, Weak},
+};
+use core::cmp::Ordering;
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 
 use crate::{
+    context::ContextLock,
+    cpu_set::MAX_CPU_COUNT,
     event,
     scheme::SchemeId,
     sync::{CleanLockToken, LockToken, Mutex, MutexGuard, L0, L1},
@@ -12,26 +28,190 @@ use crate::{
     time,
 };
 
-#[derive(Debug)]
-struct Timeout {
-    pub scheme_id: SchemeId,
-    pub event_id: usize,
-    pub clock: usize,
-    pub time: u128,
+enum TimerAction {
+    /// Trigger a read event on a `time:` handle.
+    Event {
+        scheme_id: SchemeId,
+        event_id: usize,
+    },
+    /// Wake up a context that is sleeping until this deadline.
+    Wake(Weak<ContextLock>),
+    /// Call a function with the given arguments.
+    Callback {
+        func: fn(usize, u128, &mut CleanLockToken),
+        args: (usize, u128),
+    },
 }
 
-type Registry = VecDeque<Timeout>;
+/// A timer that can be cancelled until it fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    cpu: usize,
+    seq: u64,
+    clock: usize,
+    time: u128,
+}
+
+impl TimerId {
+    /// Whether the deadline has passed, after which cancelling the timer has no effect.
+    pub fn has_expired(&self) -> bool {
+        let now = match self.clock {
+            CLOCK_REALTIME => time::realtime(),
+            _ => time::monotonic(),
+        };
+        self.time <= now
+    }
+}
 
-static REGISTRY: Once<Mutex<L1, Registry>> = Once::new();
+/// Heap entry of a timer, whose action is kept in `TimerQueue::actions` until it is cancelled.
+struct Timer {
+    /// Deadline in nanoseconds, on the clock of the heap the timer is in.
+    time: u128,
+    /// Registration order, so that timers with the same deadline fire in order.
+    seq: u64,
+}
 
-/// Initialize registry, called if needed
-fn init_registry() -> Mutex<L1, Registry> {
-    Mutex::new(Registry::new())
+// BinaryHeap is a max-heap, so the earliest timer must compare as the greatest.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .time
+            .cmp(&self.time)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+impl Eq for Timer {}
 
-/// Get the global timeouts list
-fn registry(token: LockToken<'_, L0>) -> MutexGuard<'_, L1, Registry> {
-    REGISTRY.call_once(init_registry).lock(token)
+/// Minimum number of cancelled entries before the heaps are rebuilt without them.
+const MIN_CANCELLED_TO_COMPACT: usize = 64;
+
+struct TimerQueue {
+    monotonic: BinaryHeap<Timer>,
+    realtime: BinaryHeap<Timer>,
+    /// Actions of the timers that have neither fired nor been cancelled, by sequence number.
+    actions: HashMap<u64, TimerAction>,
+    next_seq: u64,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            monotonic: BinaryHeap::new(),
+            realtime: BinaryHeap::new(),
+            actions: HashMap::with_hasher(DefaultHashBuilder::new()),
+            next_seq: 0,
+        }
+    }
+
+    fn push(&mut self, clock: usize, time: u128, action: TimerAction) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.actions.insert(seq, action);
+        let heap = match clock {
+            CLOCK_REALTIME => &mut self.realtime,
+            _ => &mut self.monotonic,
+        };
+        heap.push(Timer { time, seq });
+        seq
+    }
+
+    fn cancel(&mut self, seq: u64) {
+        if self.actions.remove(&seq).is_none() {
+            return;
+        }
+
+        let live = self.actions.len();
+        let cancelled = self.monotonic.len() + self.realtime.len() - live;
+        if cancelled >= MIN_CANCELLED_TO_COMPACT && cancelled > live {
+            // Contexts that exited while sleeping will not be woken up either.
+            self.actions.retain(|_, action| match action {
+                TimerAction::Wake(context) => context.strong_count() > 0,
+                _ => true,
+            });
+            let actions = &self.actions;
+            self.monotonic
+                .retain(|timer| actions.contains_key(&timer.seq));
+            self.realtime
+                .retain(|timer| actions.contains_key(&timer.seq));
+        }
+    }
+
+    /// Remove a timer that has expired, if there is one, with its action unless it was cancelled.
+    fn pop_expired(&mut self, mono: u128, real: u128) -> Option<(Timer, Option<TimerAction>)> {
+        let timer = if self
+            .monotonic
+            .peek()
+            .is_some_and(|timer| timer.time <= mono)
+        {
+            self.monotonic.pop()?
+        } else if self.realtime.peek().is_some_and(|timer| timer.time <= real) {
+            self.realtime.pop()?
+        } else {
+            return None;
+        };
+        let action = self.actions.remove(&timer.seq);
+        Some((timer, action))
+    }
+
+    /// The earliest deadline, in monotonic nanoseconds. This may be that of a cancelled timer,
+    /// which only causes a spurious interrupt.
+    fn next_deadline(&self) -> Option<u128> {
+        let mono = self.monotonic.peek().map(|timer| timer.time);
+        let real = self.realtime.peek().map(|timer| {
+            // Realtime deadlines are converted using the current offset, and are checked again
+            // when the timer fires in case the offset changed.
+            timer.time.saturating_sub(time::realtime_offset())
+        });
+
+        match (mono, real) {
+            (Some(mono), Some(real)) => Some(mono.min(real)),
+            (mono, real) => mono.or(real),
+        }
+    }
+}
+
+static QUEUES: [Mutex<L1, TimerQueue>; MAX_CPU_COUNT as usize] =
+    [const { Mutex::new(TimerQueue::new()) }; MAX_CPU_COUNT as usize];
+
+/// Get the timer queue of the current CPU
+fn queue(token: LockToken<'_, L0>) -> MutexGuard<'_, L1, TimerQueue> {
+    QUEUES[crate::cpu_id().get() as usize].lock(token)
+}
+
+fn insert(clock: usize, time: u128, action: TimerAction, token: &mut CleanLockToken) -> TimerId {
+    let cpu = crate::cpu_id().get() as usize;
+    let seq = queue(token.token()).push(clock, time, action);
+    reprogram(token);
+    TimerId {
+        cpu,
+        seq,
+        clock,
+        time,
+    }
+}
+
+/// Cancel a timer, unless it has already fired.
+pub fn cancel(id: TimerId, token: &mut CleanLockToken) {
+    QUEUES[id.cpu].lock(token.token()).cancel(id.seq);
+}
+
+/// Make sure the timer interrupt of the current CPU fires by the earliest deadline.
+fn reprogram(token: &mut CleanLockToken) {
+    let deadline = queue(token.token()).next_deadline();
+    if let Some(deadline) = deadline {
+        crate::arch::time::program_oneshot(deadline);
+    }
 }
 
 pub fn register(
@@ -40,48 +220,90 @@ pub fn register(
     clock: usize,
     time: TimeSpec,
     token: &mut CleanLockToken,
-) {
-    let mut registry = registry(token.token());
-    registry.push_back(Timeout {
-        scheme_id,
-        event_id,
+) -> TimerId {
+    let mut time = (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128);
+    if clock != CLOCK_MONOTONIC && clock != CLOCK_REALTIME {
+        println!("timeout::register: unknown clock {}", clock);
+        time = 0;
+    }
+
+    insert(
         clock,
-        time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
-    });
+        time,
+        TimerAction::Event {
+            scheme_id,
+            event_id,
+        },
+        token,
+    )
+}
+
+/// Call `func(args.0, args.1)` from the timer interrupt at `time`, in nanoseconds on `clock`.
+pub fn register_callback(
+    clock: usize,
+    time: u128,
+    func: fn(usize, u128, &mut CleanLockToken),
+    args: (usize, u128),
+    token: &mut CleanLockToken,
+) -> TimerId {
+    insert(clock, time, TimerAction::Callback { func, args }, token)
 }
 
+/// Wake up `context` at `time`, in monotonic nanoseconds, if it is still sleeping until then.
+/// This replaces the previous wake-up timer of the context, if any.
+pub fn register_wake(context: &Arc<ContextLock>, time: u128, token: &mut CleanLockToken) {
+    let id = insert(
+        CLOCK_MONOTONIC,
+        time,
+        TimerAction::Wake(Arc::downgrade(context)),
+        token,
+    );
+    let old = context.write(token.token()).wake_timer.replace(id);
+    if let Some(old) = old {
+        cancel(old, token);
+    }
+}
+
+/// Cancel the wake-up timer of `context`, once it no longer sleeps.
+pub fn cancel_wake(context: &Arc<ContextLock>, token: &mut CleanLockToken) {
+    let old = context.write(token.token()).wake_timer.take();
+    if let Some(old) = old {
+        cancel(old, token);
+    }
+}
+
+/// Fire the expired timers of the current CPU. Called from the timer interrupt.
 pub fn trigger(token: &mut CleanLockToken) {
     let mono = time::monotonic();
     let real = time::realtime();
 
-    let mut i = 0;
     loop {
-        let mut registry = registry(token.token());
-        let timeout = if i < registry.len() {
-            let trigger = match registry[i].clock {
-                CLOCK_MONOTONIC => {
-                    let time = registry[i].time;
-                    mono >= time
-                }
-                CLOCK_REALTIME => {
-                    let time = registry[i].time;
-                    real >= time
-                }
-                clock => {
-                    println!("timeout::trigger: unknown clock {}", clock);
-                    true
-                }
-            };
-
-            if trigger {
-                registry.remove(i).unwrap()
-            } else {
-                i += 1;
-                continue;
-            }
-        } else {
+        let Some((timer, action)) = queue(token.token()).pop_expired(mono, real) else {
             break;
         };
-        event::trigger(timeout.scheme_id, timeout.event_id, EVENT_READ);
+        let Some(action) = action else {
+            continue;
+        };
+
+        match action {
+            TimerAction::Event {
+                scheme_id,
+                event_id,
+            } => event::trigger(scheme_id, event_id, EVENT_READ),
+            TimerAction::Wake(context) => {
+                let Some(context) = context.upgrade() else {
+                    continue;
+                };
+                let mut context = context.write(token.token());
+                // The context may have been woken up already, and might be sleeping again.
+                if context.wake == Some(timer.time) {
+                    context.wake = None;
+                    context.unblock();
+                }
+            }
+            TimerAction::Callback { func, args } => func(args.0, args.1, token),
+        }
     }
+
+    reprogram(token);
 }
@@ -1,5 +1,8 @@
-use alloc::{sync::Arc, vec::Vec};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use hashbrown::{HashMap, HashSet};
 use spin::Once;
 
@@ -7,11 +10,11 @@ use crate::{
     context,
     scheme::{self, GlobalSchemes, SchemeId},
     sync::{
-        CleanLockToken, LockToken, RwLock, RwLockReadGuard, RwLockWriteGuard, WaitQueue, L0, L1,
+        CleanLockToken, LockToken, RwLock, RwLockReadGuard, RwLockWriteGuard, WaitCondition, L0, L1,
     },
     syscall::{
         data::Event,
-        error::{Error, Result, EBADF},
+        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENODEV, ENOENT},
         flag::EventFlags,
         usercopy::UserSliceWo,
     },
@@ -19,26 +22,267 @@ use crate::{
 
 int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 
+bitflags! {
+    /// Delivery modes, passed in the upper bits of `Event::flags` when registering. Without any
+    /// of these, registrations are edge-triggered: an event is reported each time the scheme
+    /// signals a change.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EventMode: usize {
+        /// Level-triggered. After an event is read, the file is polled again on the next read of
+        /// the queue, and the event is reported again for as long as the file remains ready.
+        const LEVEL = 1 << 16;
+        /// Disarm the registration once an event has been reported, until it is rearmed.
+        const ONESHOT = 1 << 17;
+        /// Rearm an existing one-shot registration, keeping its flags, mode and data.
+        const REARM = 1 << 18;
+    }
+}
+
+/// Number of events an event queue can hold, unless specified when opening it.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+/// Largest number of events an event queue can hold.
+pub const MAX_QUEUE_CAPACITY: usize = 4096;
+
+/// Id of the event that is read in place of the events dropped while the queue was full. Its
+/// `data` is the number of dropped events, and since it is unknown which files were affected,
+/// readers should check all of them.
+pub const EVENT_OVERFLOW_ID: usize = usize::MAX;
+
+/// Limit on how deeply `trigger` follows event queues that are registered in other event queues.
+const MAX_TRIGGER_DEPTH: usize = 8;
+
+/// A fixed-capacity queue of pending events, which never allocates after being created.
+struct EventRing {
+    events: VecDeque<Event>,
+    /// Sequence number of each pending event, keyed by its id and data. Events are numbered in the
+    /// order they were queued, so that the position of an event is its number minus `head`.
+    pending: HashMap<(usize, usize), usize>,
+    /// Sequence number of the event at the front.
+    head: usize,
+    capacity: usize,
+    /// Number of events dropped since the last read, because the ring was full.
+    dropped: usize,
+}
+impl EventRing {
+    fn new(capacity: usize) -> EventRing {
+        EventRing {
+            events: VecDeque::with_capacity(capacity),
+            pending: HashMap::with_capacity(capacity),
+            head: 0,
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.dropped == 0
+    }
+
+    /// Queue an event, merging it into a pending event for the same id and data if there is one.
+    fn push(&mut self, event: Event) {
+        if let Some(&seq) = self.pending.get(&(event.id, event.data)) {
+            self.events[seq.wrapping_sub(self.head)].flags |= event.flags;
+        } else if self.events.len() < self.capacity {
+            let seq = self.head.wrapping_add(self.events.len());
+            self.pending.insert((event.id, event.data), seq);
+            self.events.push_back(event);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<Event> {
+        let event = self.events.pop_front()?;
+        self.pending.remove(&(event.id, event.data));
+        self.head = self.head.wrapping_add(1);
+        Some(event)
+    }
+
+    /// Put back the event that was just popped.
+    fn push_front(&mut self, event: Event) {
+        self.head = self.head.wrapping_sub(1);
+        self.pending.insert((event.id, event.data), self.head);
+        self.events.push_front(event);
+    }
+}
+
 pub struct EventQueue {
     id: EventQueueId,
-    queue: WaitQueue<Event>,
+    ring: spin::Mutex<EventRing>,
+    condition: WaitCondition, // signals whether the ring is nonempty
+    /// Level-triggered registrations of this queue, keyed by event id and data.
+    level_triggered: spin::Mutex<HashMap<(usize, usize), RegKey>>,
+    /// Level-triggered events that have been read, to be polled on the next read.
+    repoll: spin::Mutex<Vec<(RegKey, QueueKey)>>,
+    /// Files this queue has registrations on, so that they can be removed when it is dropped.
+    registrations: spin::Mutex<HashSet<RegKey>>,
 }
 
 impl EventQueue {
-    pub fn new(id: EventQueueId) -> EventQueue {
+    pub fn new(id: EventQueueId, capacity: usize) -> EventQueue {
         EventQueue {
             id,
-            queue: WaitQueue::new(),
+            ring: spin::Mutex::new(EventRing::new(capacity)),
+            condition: WaitCondition::new(),
+            level_triggered: spin::Mutex::new(HashMap::new()),
+            repoll: spin::Mutex::new(Vec::new()),
+            registrations: spin::Mutex::new(HashSet::new()),
         }
     }
 
     pub fn is_currently_empty(&self) -> bool {
-        self.queue.is_currently_empty()
+        self.ring.lock().is_empty()
+    }
+
+    /// Queue an event. Returns whether the queue became readable, i.e. was empty before.
+    fn send(&self, event: Event, token: &mut CleanLockToken) -> bool {
+        let became_readable = {
+            let mut ring = self.ring.lock();
+            let was_empty = ring.is_empty();
+            ring.push(event);
+            was_empty
+        };
+
+        // Readers only wait while the queue is empty.
+        if became_readable {
+            self.condition.notify(token);
+        }
+        became_readable
+    }
+
+    /// Remove all registrations of this queue, when it is closed.
+    ///
+    /// This is not done when the queue is dropped, since the last reference may be dropped by
+    /// `trigger` while it holds the registry lock.
+    pub fn unregister_all(&self) {
+        let reg_keys = mem::take(&mut *self.registrations.lock());
+        unregister_queue(self.id, reg_keys);
+        self.level_triggered.lock().clear();
     }
 
     pub fn read(&self, buf: UserSliceWo, block: bool, token: &mut CleanLockToken) -> Result<usize> {
-        self.queue
-            .receive_into_user(buf, block, "EventQueue::read", token)
+        self.repoll(token);
+
+        loop {
+            let mut ring = self.ring.lock();
+
+            if ring.is_empty() {
+                if block {
+                    if !self.condition.wait(ring, "EventQueue::read", token) {
+                        return Err(Error::new(EINTR));
+                    }
+                    continue;
+                } else if buf.is_empty() {
+                    return Ok(0);
+                } else if buf.len() < mem::size_of::<Event>() {
+                    return Err(Error::new(EINVAL));
+                } else {
+                    return Err(Error::new(EAGAIN));
+                }
+            }
+
+            let mut chunks = buf.in_exact_chunks(mem::size_of::<Event>());
+            let mut bytes_read = 0;
+
+            if ring.dropped > 0 {
+                let chunk = chunks.next().ok_or(Error::new(EINVAL))?;
+                chunk.copy_exactly(&Event {
+                    id: EVENT_OVERFLOW_ID,
+                    flags: EventFlags::empty(),
+                    data: ring.dropped,
+                })?;
+                ring.dropped = 0;
+                bytes_read += mem::size_of::<Event>();
+            }
+
+            let level_triggered = self.level_triggered.lock();
+
+            for chunk in chunks {
+                let Some(event) = ring.pop_front() else {
+                    break;
+                };
+                if let Err(error) = chunk.copy_exactly(&event) {
+                    ring.push_front(event);
+                    if bytes_read > 0 {
+                        break;
+                    }
+                    return Err(error);
+                }
+                bytes_read += mem::size_of::<Event>();
+
+                // Poll level-triggered events again on the next read.
+                if let Some(&reg_key) = level_triggered.get(&(event.id, event.data)) {
+                    self.repoll.lock().push((
+                        reg_key,
+                        QueueKey {
+                            queue: self.id,
+                            id: event.id,
+                            data: event.data,
+                        },
+                    ));
+                }
+            }
+
+            return Ok(bytes_read);
+        }
+    }
+
+    /// Report the level-triggered events that were read previously again, if still ready.
+    fn repoll(&self, token: &mut CleanLockToken) {
+        let repoll = mem::take(&mut *self.repoll.lock());
+        let mut became_readable = false;
+
+        for (reg_key, queue_key) in repoll {
+            let flags = {
+                let registry = registry();
+                match registry
+                    .get(&reg_key)
+                    .and_then(|queue_list| queue_list.get(&queue_key))
+                {
+                    Some(registration)
+                        if registration.mode.contains(EventMode::LEVEL)
+                            && registration.is_armed() =>
+                    {
+                        registration.flags
+                    }
+                    _ => continue,
+                }
+            };
+
+            let Some(scheme) = scheme::schemes(token.token()).get(reg_key.scheme).cloned() else {
+                continue;
+            };
+            let Ok(ready) = scheme.fevent(reg_key.number, flags, token) else {
+                continue;
+            };
+
+            let common_flags = ready & flags;
+            if common_flags.is_empty()
+                || !registry()
+                    .get(&reg_key)
+                    .and_then(|queue_list| queue_list.get(&queue_key))
+                    .is_some_and(Registration::try_fire)
+            {
+                continue;
+            }
+
+            became_readable |= self.send(
+                Event {
+                    id: queue_key.id,
+                    flags: common_flags,
+                    data: queue_key.data,
+                },
+                token,
+            );
+        }
+
+        if became_readable {
+            trigger(
+                GlobalSchemes::Event.scheme_id(),
+                self.id.into(),
+                EventFlags::EVENT_READ,
+            );
+        }
     }
 
     pub fn write(&self, events: &[Event], token: &mut CleanLockToken) -> Result<usize> {
@@ -58,16 +302,35 @@ impl EventQueue {
                 let description = file.description.read();
                 (description.scheme, description.number)
             };
+            let reg_key = RegKey { scheme, number };
+            let queue_key = QueueKey {
+                queue: self.id,
+                id: event.id,
+                data: event.data,
+            };
+            let flags = EventFlags::from_bits_truncate(event.flags.bits());
+            let mode = EventMode::from_bits_truncate(event.flags.bits());
+
+            if mode.contains(EventMode::REARM) {
+                rearm(&reg_key, &queue_key)?;
+            } else {
+                let mut level_triggered = self.level_triggered.lock();
+                if mode.contains(EventMode::LEVEL) && !flags.is_empty() {
+                    level_triggered.insert((event.id, event.data), reg_key);
+                } else {
+                    level_triggered.remove(&(event.id, event.data));
+                }
+                drop(level_triggered);
 
-            register(
-                RegKey { scheme, number },
-                QueueKey {
-                    queue: self.id,
-                    id: event.id,
-                    data: event.data,
-                },
-                event.flags,
-            );
+                let registered = register(reg_key, queue_key, flags, mode);
+
+                let mut registrations = self.registrations.lock();
+                if registered {
+                    registrations.insert(reg_key);
+                } else {
+                    registrations.remove(&reg_key);
+                }
+            }
 
             let flags = sync(RegKey { scheme, number }, token)?;
             if !flags.is_empty() {
@@ -107,20 +370,41 @@ pub fn queues_mut(token: LockToken<'_, L0>) -> RwLockWriteGuard<'_, L1, EventQue
     QUEUES.call_once(init_queues).write(token)
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct RegKey {
     pub scheme: SchemeId,
     pub number: usize,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct QueueKey {
     pub queue: EventQueueId,
     pub id: usize,
     pub data: usize,
 }
 
-type Registry = HashMap<RegKey, HashMap<QueueKey, EventFlags>>;
+#[derive(Debug)]
+pub struct Registration {
+    pub flags: EventFlags,
+    pub mode: EventMode,
+    /// Cleared once a one-shot registration has reported an event, until it is rearmed.
+    armed: AtomicBool,
+}
+impl Registration {
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::Acquire)
+    }
+    /// Returns whether an event may be reported, disarming the registration if it is one-shot.
+    fn try_fire(&self) -> bool {
+        if self.mode.contains(EventMode::ONESHOT) {
+            self.armed.swap(false, Ordering::AcqRel)
+        } else {
+            self.is_armed()
+        }
+    }
+}
+
+type Registry = HashMap<RegKey, HashMap<QueueKey, Registration>>;
 
 static REGISTRY: Once<spin::RwLock<Registry>> = Once::new();
 
@@ -130,7 +414,7 @@ fn init_registry() -> spin::RwLock<Registry> {
 }
 
 /// Get the global schemes list, const
-fn registry() -> spin::RwLockReadGuard<'static, Registry> {
+pub fn registry() -> spin::RwLockReadGuard<'static, Registry> {
     REGISTRY.call_once(init_registry).read()
 }
 
@@ -139,18 +423,49 @@ pub fn registry_mut() -> spin::RwLockWriteGuard<'static, Registry> {
     REGISTRY.call_once(init_registry).write()
 }
 
-pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: EventFlags) {
+/// Register or, if `flags` is empty, unregister `queue_key` for events on a file. Returns whether
+/// the queue still has registrations on the file afterwards.
+pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: EventFlags, mode: EventMode) -> bool {
     let mut registry = registry_mut();
 
-    let entry = registry.entry(reg_key).or_default();
-
     if flags.is_empty() {
+        let Some(entry) = registry.get_mut(&reg_key) else {
+            return false;
+        };
         entry.remove(&queue_key);
+
+        let registered = entry.keys().any(|key| key.queue == queue_key.queue);
+        if entry.is_empty() {
+            registry.remove(&reg_key);
+        }
+        registered
     } else {
-        entry.insert(queue_key, flags);
+        let entry = registry.entry(reg_key).or_default();
+        entry.insert(
+            queue_key,
+            Registration {
+                flags,
+                mode: mode & (EventMode::LEVEL | EventMode::ONESHOT),
+                armed: AtomicBool::new(true),
+            },
+        );
+        true
     }
 }
 
+/// Rearm a one-shot registration, without changing it otherwise.
+pub fn rearm(reg_key: &RegKey, queue_key: &QueueKey) -> Result<()> {
+    let registry = registry();
+
+    let registration = registry
+        .get(reg_key)
+        .and_then(|queue_list| queue_list.get(queue_key))
+        .ok_or(Error::new(ENOENT))?;
+    registration.armed.store(true, Ordering::Release);
+
+    Ok(())
+}
+
 pub fn sync(reg_key: RegKey, token: &mut CleanLockToken) -> Result<EventFlags> {
     let mut flags = EventFlags::empty();
 
@@ -158,84 +473,127 @@ pub fn sync(reg_key: RegKey, token: &mut CleanLockToken) -> Result<EventFlags> {
         let registry = registry();
 
         if let Some(queue_list) = registry.get(&reg_key) {
-            for (_queue_key, &queue_flags) in queue_list.iter() {
-                flags |= queue_flags;
+            for (_queue_key, registration) in queue_list.iter() {
+                flags |= registration.flags;
             }
         }
     }
 
     let scheme = scheme::schemes(token.token())
         .get(reg_key.scheme)
-        .ok_or(Error::new(EBADF))?
+        .ok_or(Error::new(ENODEV))?
         .clone();
 
     scheme.fevent(reg_key.number, flags, token)
 }
 
-pub fn unregister_file(scheme: SchemeId, number: usize) {
+/// Remove all registrations on a file that is being closed.
+pub fn unregister_file(scheme: SchemeId, number: usize, token: &mut CleanLockToken) {
+    let reg_key = RegKey { scheme, number };
+
+    let Some(queue_list) = registry_mut().remove(&reg_key) else {
+        return;
+    };
+
+    // Let the registered queues forget about the file as well.
+    let mut queue_ids = queue_list.keys().map(|key| key.queue).collect::<Vec<_>>();
+    queue_ids.sort_unstable();
+    queue_ids.dedup();
+
+    for queue_id in queue_ids {
+        let Some(queue) = queues(token.token()).get(&queue_id).cloned() else {
+            continue;
+        };
+        queue.registrations.lock().remove(&reg_key);
+        queue
+            .level_triggered
+            .lock()
+            .retain(|_, level_key| *level_key != reg_key);
+    }
+}
+
+/// Remove all registrations of a queue on the given files.
+pub fn unregister_queue(queue_id: EventQueueId, reg_keys: impl IntoIterator<Item = RegKey>) {
     let mut registry = registry_mut();
 
-    registry.remove(&RegKey { scheme, number });
-}
+    for reg_key in reg_keys {
+        let Some(queue_list) = registry.get_mut(&reg_key) else {
+            continue;
+        };
+        queue_list.retain(|queue_key, _| queue_key.queue != queue_id);
 
-//TODO: Implement unregister_queue
-// pub fn unregister_queue(scheme: SchemeId, number: usize) {
-//
-// }
+        if queue_list.is_empty() {
+            registry.remove(&reg_key);
+        }
+    }
+}
 
 fn trigger_inner(
     scheme: SchemeId,
     number: usize,
     flags: EventFlags,
-    todo: &mut Vec<EventQueueId>,
+    depth: usize,
     token: &mut CleanLockToken,
 ) {
     let registry = registry();
-    if let Some(queue_list) = registry.get(&RegKey { scheme, number }) {
-        for (queue_key, &queue_flags) in queue_list.iter() {
-            let common_flags = flags & queue_flags;
-            if !common_flags.is_empty() {
-                let queue_opt = {
-                    let queues = queues(token.token());
-                    queues.get(&queue_key.queue).cloned()
-                };
-                if let Some(queue) = queue_opt {
-                    queue.queue.send(
-                        Event {
-                            id: queue_key.id,
-                            flags: common_flags,
-                            data: queue_key.data,
-                        },
-                        token,
-                    );
-                    todo.push(queue_key.queue);
-                }
-            }
+    let Some(queue_list) = registry.get(&RegKey { scheme, number }) else {
+        return;
+    };
+
+    for (queue_key, registration) in queue_list.iter() {
+        let common_flags = flags & registration.flags;
+        if common_flags.is_empty() || !registration.try_fire() {
+            continue;
+        }
+
+        let queue_opt = {
+            let queues = queues(token.token());
+            queues.get(&queue_key.queue).cloned()
+        };
+        let Some(queue) = queue_opt else {
+            continue;
+        };
+
+        let became_readable = queue.send(
+            Event {
+                id: queue_key.id,
+                flags: common_flags,
+                data: queue_key.data,
+            },
+            token,
+        );
+
+        // Queues that were already readable have already been reported to the queues watching
+        // them. This also ends cycles of queues watching each other.
+        if became_readable && depth < MAX_TRIGGER_DEPTH {
+            trigger_inner(
+                GlobalSchemes::Event.scheme_id(),
+                queue_key.queue.into(),
+                EventFlags::EVENT_READ,
+                depth + 1,
+                token,
+            );
         }
     }
 }
 
+/// Report an event on a file to the queues registered for it. This does not allocate, so that it
+/// can be called frequently, e.g. for every received packet.
 pub fn trigger(scheme: SchemeId, number: usize, flags: EventFlags) {
     //TODO: propogate this lock token
     let mut token = unsafe { CleanLockToken::new() };
 
-    // First trigger with the original file
-    let mut todo = Vec::new();
-    trigger_inner(scheme, number, flags, &mut todo, &mut token);
+    trigger_inner(scheme, number, flags, 0, &mut token);
+}
 
-    // Handle triggers on queues
-    //TODO: can this be done with limited allocations?
-    let mut done = HashSet::new();
-    while let Some(queue_id) = todo.pop() {
-        if !done.contains(&queue_id) {
-            trigger_inner(
-                GlobalSchemes::Event.scheme_id(),
-                queue_id.into(),
-                EventFlags::EVENT_READ,
-                &mut todo,
-                &mut token,
-            );
-            done.insert(queue_id);
-        }
+/// Trigger events on every file of a scheme that is registered in an event queue.
+pub fn trigger_all(scheme: SchemeId, flags: EventFlags) {
+    let numbers: Vec<usize> = registry()
+        .keys()
+        .filter(|key| key.scheme == scheme)
+        .map(|key| key.number)
+        .collect();
+    for number in numbers {
+        trigger(scheme, number, flags);
     }
 }
@@ -89,6 +89,9 @@ mod cpu_set;
 /// Stats for the CPUs
 mod cpu_stats;
 
+/// Clock sources
+mod clocksource;
+
 /// Context management
 mod context;
 
@@ -184,6 +187,10 @@ fn kmain(bootstrap: Bootstrap) -> ! {
     //Initialize global schemes, such as `acpi:`.
     scheme::init_globals();
 
+    //Publish the clock parameters to userspace, before any address space maps the time page.
+    time::update_time_page();
+    clocksource::init_watchdog(&mut token);
+
     info!("BSP: {} CPUs", cpu_count());
     debug!("Env: {:?}", ::core::str::from_utf8(bootstrap.env));
 
@@ -30,7 +30,10 @@ use crate::syscall::{
     usercopy::UserSliceWo,
 };
 
-use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+use super::{
+    cap::{self, CapKind},
+    CallerCtx, GlobalSchemes, KernelScheme, OpenResult,
+};
 
 /// A scheme used to access the RSDT or XSDT, which is needed for e.g. `acpid` to function.
 pub struct AcpiScheme;
@@ -114,12 +117,17 @@ impl KernelScheme for AcpiScheme {
         &self,
         path: &str,
         flags: usize,
-        ctx: CallerCtx,
+        _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         let path = path.trim_start_matches('/');
 
-        if ctx.uid != 0 {
+        let rights = if path == "kstop" {
+            cap::ACPI_KSTOP
+        } else {
+            cap::ACPI_TABLES
+        };
+        if !cap::held(CapKind::Acpi, rights, token) {
             return Err(Error::new(EACCES));
         }
         if flags & O_CREAT == O_CREAT {
@@ -0,0 +1,291 @@
+//! Capabilities for privileged kernel operations.
+//!
+//! A capability is a file descriptor of `kernel.cap:`, opened as `kernel.cap:<scheme>`, granting a
+//! set of rights on a kernel scheme, whose meaning depends on the scheme. Holding the file
+//! descriptor is what grants the rights: instead of checking the uid of the caller, these schemes
+//! look for a capability with the rights an operation needs in the file table of the caller.
+//!
+//! Capabilities are delegated with `SYS_SENDFD`, and dropped by closing them. Duplicating one with a
+//! hexadecimal mask as the buffer, e.g. `dup(cap, "1")`, gives a capability with only the rights
+//! that are also in the mask. New capabilities of a scheme can only be opened by contexts holding
+//! one with all of its rights.
+//!
+//! The first userspace context starts with a capability with all rights for every scheme, in the
+//! upper file table, from which it hands them out to drivers. Their paths, as given by `fpath`, are
+//! `kernel.cap:<scheme>/<rights>`.
+//!
+//! As a compatibility switch for userspace that still relies on running as root, writing `1` to
+//! `sys:cap_root_compat` grants every right to contexts with euid 0, without a capability. It is
+//! off by default, and needs the `SYS_WRITE` right to be changed.
+
+use alloc::{format, sync::Arc, vec::Vec};
+use core::{
+    str,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+
+use crate::{
+    context::{
+        self,
+        file::{FileDescription, FileDescriptor, InternalFlags},
+    },
+    sync::{CleanLockToken, RwLock, L1},
+    syscall::{
+        data::Stat,
+        error::*,
+        flag::{MODE_FILE, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+/// `irq:` - open interrupt handles.
+pub const IRQ_HANDLE: u32 = 1 << 0;
+
+/// `memory:` - borrow physical memory, and translate virtual addresses.
+pub const MEMORY_PHYS: u32 = 1 << 0;
+/// `memory:` - allocate physically contiguous memory, or memory that is not write-back.
+pub const MEMORY_TYPED: u32 = 1 << 1;
+
+/// `kernel.acpi:` - read the ACPI tables.
+#[cfg(feature = "acpi")]
+pub const ACPI_TABLES: u32 = 1 << 0;
+/// `kernel.acpi:` - receive shutdown requests from `kstop`.
+#[cfg(feature = "acpi")]
+pub const ACPI_KSTOP: u32 = 1 << 1;
+
+/// `sys:` - write system settings, such as the time and the call timeouts.
+pub const SYS_WRITE: u32 = 1 << 0;
+/// `sys:` - shut down or reset the system through `kstop`.
+pub const SYS_KSTOP: u32 = 1 << 1;
+
+/// A kernel scheme that capabilities grant rights on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CapKind {
+    Irq,
+    Memory,
+    #[cfg(feature = "acpi")]
+    Acpi,
+    Sys,
+}
+
+impl CapKind {
+    const ALL: &[CapKind] = &[
+        CapKind::Irq,
+        CapKind::Memory,
+        #[cfg(feature = "acpi")]
+        CapKind::Acpi,
+        CapKind::Sys,
+    ];
+
+    fn from_name(name: &str) -> Option<CapKind> {
+        Some(match name {
+            "irq" => CapKind::Irq,
+            "memory" => CapKind::Memory,
+            #[cfg(feature = "acpi")]
+            "kernel.acpi" => CapKind::Acpi,
+            "sys" => CapKind::Sys,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CapKind::Irq => "irq",
+            CapKind::Memory => "memory",
+            #[cfg(feature = "acpi")]
+            CapKind::Acpi => "kernel.acpi",
+            CapKind::Sys => "sys",
+        }
+    }
+
+    /// Every right of the scheme, which new capabilities are opened with.
+    fn all_rights(self) -> u32 {
+        match self {
+            CapKind::Irq => IRQ_HANDLE,
+            CapKind::Memory => MEMORY_PHYS | MEMORY_TYPED,
+            #[cfg(feature = "acpi")]
+            CapKind::Acpi => ACPI_TABLES | ACPI_KSTOP,
+            CapKind::Sys => SYS_WRITE | SYS_KSTOP,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Cap {
+    kind: CapKind,
+    rights: u32,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+/// Whether contexts with euid 0 hold every right, see `sys:cap_root_compat`.
+static ROOT_COMPAT: AtomicBool = AtomicBool::new(false);
+static CAPS: RwLock<L1, HashMap<usize, Cap>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+fn cap(id: usize, token: &mut CleanLockToken) -> Result<Cap> {
+    CAPS.read(token.token())
+        .get(&id)
+        .copied()
+        .ok_or(Error::new(EBADF))
+}
+
+fn insert(cap: Cap, token: &mut CleanLockToken) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CAPS.write(token.token()).insert(id, cap);
+    id
+}
+
+/// Whether the current context has a file descriptor of a capability with all of `rights` on
+/// `kind`, or has euid 0 while `sys:cap_root_compat` is enabled.
+pub fn held(kind: CapKind, rights: u32, token: &mut CleanLockToken) -> bool {
+    let files = {
+        let context = context::current();
+        let context = context.read(token.token());
+        if context.euid == 0 && ROOT_COMPAT.load(Ordering::Relaxed) {
+            return true;
+        }
+        Arc::clone(&context.files)
+    };
+    let grants = |cap: &Cap| cap.kind == kind && cap.rights & rights == rights;
+
+    // Usually no capability grants the rights at all, and the files need not be looked at.
+    let caps = CAPS.read(token.token());
+    if !caps.values().any(grants) {
+        return false;
+    }
+    let cap_scheme = GlobalSchemes::Cap.scheme_id();
+    files.read().iter().flatten().any(|file| {
+        let description = file.description.read();
+        description.scheme == cap_scheme && caps.get(&description.number).is_some_and(grants)
+    })
+}
+
+/// Give the current context, the first in userspace, a capability with all rights for every
+/// scheme, in the upper file table.
+pub fn bootstrap(token: &mut CleanLockToken) {
+    let files = CapKind::ALL
+        .iter()
+        .map(|&kind| {
+            let number = insert(
+                Cap {
+                    kind,
+                    rights: kind.all_rights(),
+                },
+                token,
+            );
+            FileDescriptor {
+                description: Arc::new(spin::RwLock::new(FileDescription {
+                    offset: 0,
+                    scheme: GlobalSchemes::Cap.scheme_id(),
+                    number,
+                    flags: O_RDONLY as u32,
+                    internal_flags: InternalFlags::empty(),
+                })),
+                cloexec: false,
+            }
+        })
+        .collect();
+
+    context::current()
+        .read(token.token())
+        .bulk_insert_files_upper(files)
+        .expect("failed to give capabilities to the bootstrap context");
+}
+
+/// Get whether `sys:cap_root_compat` is enabled.
+pub fn sys_root_compat_resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    Ok(format!("{}\n", u8::from(ROOT_COMPAT.load(Ordering::Relaxed))).into_bytes())
+}
+
+/// Enable or disable `sys:cap_root_compat`, with `1` or `0`.
+pub fn sys_root_compat(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
+    let enable = match buf.trim_ascii() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err(Error::new(EINVAL)),
+    };
+    ROOT_COMPAT.store(enable, Ordering::Relaxed);
+    Ok(buf.len())
+}
+
+pub struct CapScheme;
+
+impl KernelScheme for CapScheme {
+    fn kopen(
+        &self,
+        path: &str,
+        _flags: usize,
+        _ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        let kind = CapKind::from_name(path.trim_matches('/')).ok_or(Error::new(ENOENT))?;
+        if !held(kind, kind.all_rights(), token) {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = insert(
+            Cap {
+                kind,
+                rights: kind.all_rights(),
+            },
+            token,
+        );
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn kdup(
+        &self,
+        old_id: usize,
+        user_buf: UserSliceRo,
+        _ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        let old = cap(old_id, token)?;
+
+        let mut buf = [0_u8; 8];
+        if user_buf.len() > buf.len() {
+            return Err(Error::new(EINVAL));
+        }
+        let len = user_buf.copy_common_bytes_to_slice(&mut buf)?;
+        // An empty mask gives a capability without any right.
+        let mask = match str::from_utf8(&buf[..len]).map(|mask| mask.trim_end_matches('\0')) {
+            Ok("") => 0,
+            Ok(mask) => u32::from_str_radix(mask, 16).map_err(|_| Error::new(EINVAL))?,
+            Err(_) => return Err(Error::new(EINVAL)),
+        };
+
+        let id = insert(
+            Cap {
+                kind: old.kind,
+                rights: old.rights & mask,
+            },
+            token,
+        );
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
+        let cap = cap(id, token)?;
+        let path = format!("kernel.cap:{}/{:x}", cap.kind.name(), cap.rights);
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+        cap(id, token)?;
+        buf.copy_exactly(&Stat {
+            st_mode: 0o400 | MODE_FILE,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        CAPS.write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+}
@@ -0,0 +1,202 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+use spin::Mutex;
+
+use crate::{
+    context::file::InternalFlags,
+    event,
+    sync::{CleanLockToken, RwLock, WaitCondition, L1},
+    syscall::{
+        data::Stat,
+        error::*,
+        flag::{EventFlags, EVENT_READ, EVENT_WRITE, MODE_FILE, O_NONBLOCK},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+/// Largest value a counter can hold.
+const MAX_VALUE: u64 = u64::MAX - 1;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+static COUNTERS: RwLock<L1, HashMap<usize, Arc<Counter>>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+struct Counter {
+    value: Mutex<u64>,
+    semaphore: bool,
+    read_condition: WaitCondition, // signals whether the value is nonzero
+    write_condition: WaitCondition, // signals whether the value has decreased
+}
+
+fn counter(id: usize, token: &mut CleanLockToken) -> Result<Arc<Counter>> {
+    COUNTERS
+        .read(token.token())
+        .get(&id)
+        .map(Arc::clone)
+        .ok_or(Error::new(EBADF))
+}
+
+/// Opening `counter:` creates a counter, optionally followed by `semaphore` and/or an initial
+/// value, e.g. `counter:semaphore/3`. Writing an 8-byte native-endian value adds it to the counter.
+/// Reading returns the counter and resets it to zero, or in semaphore mode, returns 1 and
+/// decrements it. Reads block while the counter is zero, and writes block while adding the value
+/// would exceed `u64::MAX - 1`.
+pub struct CounterScheme;
+
+impl KernelScheme for CounterScheme {
+    fn kopen(
+        &self,
+        path: &str,
+        _flags: usize,
+        _ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        let mut semaphore = false;
+        let mut value = 0;
+
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            match part {
+                "semaphore" => semaphore = true,
+                _ => value = part.parse::<u64>().map_err(|_| Error::new(ENOENT))?,
+            }
+        }
+        if value > MAX_VALUE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        COUNTERS.write(token.token()).insert(
+            id,
+            Arc::new(Counter {
+                value: Mutex::new(value),
+                semaphore,
+                read_condition: WaitCondition::new(),
+                write_condition: WaitCondition::new(),
+            }),
+        );
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn fcntl(
+        &self,
+        _id: usize,
+        _cmd: usize,
+        _arg: usize,
+        _token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fevent(
+        &self,
+        id: usize,
+        flags: EventFlags,
+        token: &mut CleanLockToken,
+    ) -> Result<EventFlags> {
+        let counter = counter(id, token)?;
+        let value = *counter.value.lock();
+
+        let mut ready = EventFlags::empty();
+        if flags.contains(EVENT_READ) && value > 0 {
+            ready |= EVENT_READ;
+        }
+        if flags.contains(EVENT_WRITE) && value < MAX_VALUE {
+            ready |= EVENT_WRITE;
+        }
+        Ok(ready)
+    }
+
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        COUNTERS
+            .write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))
+            .and(Ok(()))
+    }
+
+    fn kread(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        flags: u32,
+        _stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let counter = counter(id, token)?;
+        let buf = buf.limit(size_of::<u64>()).ok_or(Error::new(EINVAL))?;
+
+        loop {
+            let mut value = counter.value.lock();
+
+            if *value > 0 {
+                let read = if counter.semaphore { 1 } else { *value };
+                buf.copy_from_slice(&read.to_ne_bytes())?;
+                *value -= read;
+                drop(value);
+
+                event::trigger(GlobalSchemes::Counter.scheme_id(), id, EVENT_WRITE);
+                counter.write_condition.notify(token);
+
+                return Ok(size_of::<u64>());
+            }
+
+            if flags & O_NONBLOCK as u32 != 0 {
+                return Err(Error::new(EAGAIN));
+            } else if !counter.read_condition.wait(value, "Counter::read", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        flags: u32,
+        _stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let counter = counter(id, token)?;
+        let added = buf.read_u64()?;
+        if added > MAX_VALUE {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            let mut value = counter.value.lock();
+
+            if *value <= MAX_VALUE - added {
+                *value += added;
+                drop(value);
+
+                if added > 0 {
+                    event::trigger(GlobalSchemes::Counter.scheme_id(), id, EVENT_READ);
+                    counter.read_condition.notify(token);
+                }
+
+                return Ok(size_of::<u64>());
+            }
+
+            if flags & O_NONBLOCK as u32 != 0 {
+                return Err(Error::new(EAGAIN));
+            } else if !counter.write_condition.wait(value, "Counter::write", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+        counter(id, token)?;
+
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o600,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+}
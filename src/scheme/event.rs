@@ -4,7 +4,10 @@ use syscall::{EventFlags, O_NONBLOCK};
 
 use crate::{
     context::file::InternalFlags,
-    event::{next_queue_id, queues, queues_mut, EventQueue, EventQueueId},
+    event::{
+        next_queue_id, queues, queues_mut, EventQueue, EventQueueId, DEFAULT_QUEUE_CAPACITY,
+        MAX_QUEUE_CAPACITY,
+    },
     sync::CleanLockToken,
     syscall::{
         data::Event,
@@ -15,28 +18,44 @@ use crate::{
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 
+/// Opening `event:` creates an event queue. The path may specify how many distinct events the
+/// queue can hold, e.g. `event:1024`; events reported while it is full are counted and read as a
+/// single event with id `EVENT_OVERFLOW_ID`.
 pub struct EventScheme;
 
 impl KernelScheme for EventScheme {
     fn kopen(
         &self,
-        _path: &str,
+        path: &str,
         _flags: usize,
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
+        let path = path.trim_matches('/');
+        let capacity = if path.is_empty() {
+            DEFAULT_QUEUE_CAPACITY
+        } else {
+            path.parse::<usize>().map_err(|_| Error::new(ENOENT))?
+        };
+        if capacity == 0 || capacity > MAX_QUEUE_CAPACITY {
+            return Err(Error::new(EINVAL));
+        }
+
         let id = next_queue_id();
-        queues_mut(token.token()).insert(id, Arc::new(EventQueue::new(id)));
+        queues_mut(token.token()).insert(id, Arc::new(EventQueue::new(id, capacity)));
 
         Ok(OpenResult::SchemeLocal(id.get(), InternalFlags::empty()))
     }
 
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
         let id = EventQueueId::from(id);
-        queues_mut(token.token())
+        let queue = queues_mut(token.token())
             .remove(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(()))
+            .ok_or(Error::new(EBADF))?;
+
+        queue.unregister_all();
+
+        Ok(())
     }
 
     fn kread(
@@ -0,0 +1,119 @@
+//! Fair queueing of the requests to a userspace scheme between caller processes.
+//!
+//! Each worker of a scheme only has a few requests waiting to be read by the scheme handler at a
+//! time. The others wait in a `FairQueue`, which serves the caller processes in turn, taking up
+//! to `IO_PRIORITY_LEVELS - priority` requests from each, so that a process submitting many
+//! requests does not delay the ones of other processes by more than a turn.
+//!
+//! Schemes see the I/O priority of each request in `Sqe::_rsvd`, from 0, the most urgent, to
+//! `IO_PRIORITY_LEVELS - 1`, and can use it to order their own work. The pid of the caller is in
+//! `Sqe::caller`, and its euid and egid are in the last argument of every request but close and
+//! cancel messages.
+
+use alloc::collections::VecDeque;
+use syscall::schemev2::{Opcode, Sqe};
+
+use crate::context::context::IO_PRIORITY_LEVELS;
+
+/// The I/O priority of the caller of a request, stored in the otherwise reserved field of the
+/// `Sqe`.
+pub fn priority(sqe: &Sqe) -> u8 {
+    sqe._rsvd.min(u16::from(IO_PRIORITY_LEVELS - 1)) as u8
+}
+
+pub fn set_priority(sqe: &mut Sqe, priority: u8) {
+    sqe._rsvd = u16::from(priority.min(IO_PRIORITY_LEVELS - 1));
+}
+
+struct CallerQueue {
+    pid: u64,
+    sqes: VecDeque<Sqe>,
+    /// Requests this caller can still have taken in its current turn.
+    credit: u8,
+}
+
+#[derive(Default)]
+pub struct FairQueue {
+    /// The callers with queued requests, in the order they are served.
+    callers: VecDeque<CallerQueue>,
+    len: usize,
+}
+
+impl FairQueue {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of caller processes with queued requests.
+    pub fn callers(&self) -> usize {
+        self.callers.len()
+    }
+
+    pub fn push(&mut self, sqe: Sqe) {
+        self.len += 1;
+        if let Some(caller) = self
+            .callers
+            .iter_mut()
+            .find(|caller| caller.pid == sqe.caller)
+        {
+            caller.sqes.push_back(sqe);
+            return;
+        }
+        self.callers.push_back(CallerQueue {
+            pid: sqe.caller,
+            sqes: VecDeque::from([sqe]),
+            credit: 0,
+        });
+    }
+
+    /// Take the next request, from the caller whose turn it is.
+    pub fn pop(&mut self) -> Option<Sqe> {
+        let caller = self.callers.front_mut()?;
+        let sqe = caller.sqes.pop_front()?;
+        self.len -= 1;
+
+        // A turn starts with the first request, and lasts longer the more urgent it is.
+        if caller.credit == 0 {
+            caller.credit = IO_PRIORITY_LEVELS - priority(&sqe);
+        }
+        caller.credit -= 1;
+
+        if caller.sqes.is_empty() {
+            self.callers.pop_front();
+        } else if caller.credit == 0 {
+            self.callers.rotate_left(1);
+        }
+        Some(sqe)
+    }
+
+    /// Remove the queued request with a tag, if it has not been taken yet. Close messages are not
+    /// responded to, so their tags are not unique.
+    pub fn remove(&mut self, tag: u32) -> Option<Sqe> {
+        let (i, j) = self.callers.iter().enumerate().find_map(|(i, caller)| {
+            let j = caller
+                .sqes
+                .iter()
+                .position(|sqe| sqe.tag == tag && sqe.opcode != Opcode::CloseMsg as u8)?;
+            Some((i, j))
+        })?;
+        let sqe = self.callers[i].sqes.remove(j)?;
+        if self.callers[i].sqes.is_empty() {
+            self.callers.remove(i);
+        }
+        self.len -= 1;
+        Some(sqe)
+    }
+
+    /// Take every queued request, in the order they would have been taken.
+    pub fn take_all(&mut self) -> VecDeque<Sqe> {
+        let mut sqes = VecDeque::with_capacity(self.len);
+        while let Some(sqe) = self.pop() {
+            sqes.push_back(sqe);
+        }
+        sqes
+    }
+}
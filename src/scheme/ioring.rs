@@ -0,0 +1,546 @@
+//! Asynchronous file operations through shared-memory rings.
+//!
+//! Opening `ioring:` creates a ring pair bound to the address space, file table and credentials of
+//! the opener, which maps it with `fmap` (see `scheme::ring`). Userspace writes `IoSqe`s to the
+//! submission queue and then calls `fsync` on the handle. The kernel queues the operations to
+//! worker contexts, which run them against the file table of the opener as the corresponding
+//! syscalls would, and write an `IoCqe` to the completion queue for each of them, so the submitter
+//! never blocks on the schemes.
+//!
+//! Completions trigger `EVENT_READ` on the handle, and wake futex waiters on `cq_tail` if
+//! `CQ_NEED_WAKEUP` is set in `cq_flags`. Reading the handle returns the number of completions
+//! waiting in the completion queue, as an 8-byte native-endian value, blocking while there are
+//! none.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+};
+use core::{
+    mem::{self, offset_of},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+use spin::Mutex;
+
+use crate::{
+    context::{
+        self,
+        context::FdTbl,
+        file::InternalFlags,
+        memory::{AddrSpace, AddrSpaceWrapper},
+        Status,
+    },
+    event,
+    scheme::{
+        ring::{cq_entries, sq_entries, RingHeader, RingPages, CQ_NEED_WAKEUP},
+        stats::SchemeOp,
+        FileHandle, SchemeNamespace,
+    },
+    sync::{CleanLockToken, RwLock, WaitCondition, WaitQueue, L1},
+    syscall::{
+        call,
+        data::{Map, Stat},
+        error::*,
+        exit_this_context, file_op_generic_ext,
+        filter::{self, Filters},
+        flag::{CallFlags, EventFlags, EVENT_READ, MODE_FILE, O_NONBLOCK},
+        futex,
+        number::{SYS_CALL, SYS_OPENAT, SYS_READ, SYS_WRITE},
+        openat, sys_read, sys_write,
+        usercopy::{UserSlice, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+/// Read `len` bytes into `addr`, at `offset`, or at the file offset if it is `u64::MAX`.
+pub const IORING_OP_READ: u8 = 0;
+/// Write `len` bytes from `addr`, at `offset`, or at the file offset if it is `u64::MAX`.
+pub const IORING_OP_WRITE: u8 = 1;
+/// Open the path of `len` bytes at `addr` relative to `fd`, with `offset` as the open flags.
+pub const IORING_OP_OPENAT: u8 = 2;
+/// Call `fd` with the payload of `len` bytes at `addr`, `offset` as the call flags, and
+/// `meta_len` `u64`s of metadata at `meta_addr`.
+pub const IORING_OP_CALL: u8 = 3;
+
+/// A file operation submitted by userspace.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct IoSqe {
+    pub opcode: u8,
+    pub _rsvd: [u8; 3],
+    /// The fcntl flags of `IORING_OP_OPENAT`.
+    pub fcntl_flags: u32,
+    pub fd: u64,
+    pub addr: u64,
+    pub len: u64,
+    pub offset: u64,
+    pub meta_addr: u64,
+    pub meta_len: u64,
+    /// Copied to the completion.
+    pub user_data: u64,
+}
+
+/// The result of a file operation, encoded like the return value of a syscall.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct IoCqe {
+    pub user_data: u64,
+    pub result: u64,
+}
+
+/// Largest number of worker contexts of a ring.
+const MAX_WORKERS: usize = 16;
+
+/// Largest number of operations taken from the submission queue that have not yet been
+/// completed, so that completions waiting for room in the completion queue are bounded.
+const MAX_IN_FLIGHT: usize = cq_entries::<IoCqe>() as usize;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+static RINGS: RwLock<L1, HashMap<usize, Arc<IoRing>>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+/// Rings of the worker contexts that have been spawned but have not started yet, by context.
+static STARTING: Mutex<BTreeMap<usize, Arc<IoRing>>> = Mutex::new(BTreeMap::new());
+
+struct CompletionQueue {
+    /// Kernel copy of `cq_tail`.
+    tail: u32,
+    /// Completions waiting for room in the completion queue.
+    overflow: VecDeque<IoCqe>,
+}
+
+struct IoRing {
+    id: usize,
+    pages: RingPages,
+    /// Kernel copy of `sq_head`.
+    sq_head: Mutex<u32>,
+    cq: Mutex<CompletionQueue>,
+    /// Signals that completions were written to the completion queue.
+    completion_condition: WaitCondition,
+    /// Operations waiting for a worker.
+    pending: WaitQueue<IoSqe>,
+    in_flight: AtomicUsize,
+    workers: AtomicUsize,
+    /// Workers not running an operation.
+    idle: AtomicUsize,
+    closed: AtomicBool,
+
+    addr_space: Weak<AddrSpaceWrapper>,
+    files: Weak<spin::RwLock<FdTbl>>,
+    ens: SchemeNamespace,
+    syscall_filters: Filters,
+    euid: u32,
+    egid: u32,
+    pid: usize,
+}
+
+fn ring(id: usize, token: &mut CleanLockToken) -> Result<Arc<IoRing>> {
+    RINGS
+        .read(token.token())
+        .get(&id)
+        .map(Arc::clone)
+        .ok_or(Error::new(EBADF))
+}
+
+impl IoRing {
+    /// Take the submitted operations and hand them to the workers.
+    fn enter(self: &Arc<Self>, token: &mut CleanLockToken) -> Result<()> {
+        let header = self.pages.header();
+        let queued = {
+            let mut head = self.sq_head.lock();
+            let tail = header.sq_tail.load(Ordering::Acquire);
+            if tail.wrapping_sub(*head) > sq_entries::<IoSqe>() {
+                return Err(Error::new(EINVAL));
+            }
+
+            let mut pending = self.pending.inner.lock();
+            while *head != tail && self.in_flight.load(Ordering::Relaxed) < MAX_IN_FLIGHT {
+                pending.push_back(self.pages.read_sqe(*head));
+                self.in_flight.fetch_add(1, Ordering::Relaxed);
+                *head = head.wrapping_add(1);
+            }
+            header.sq_head.store(*head, Ordering::Release);
+            pending.len()
+        };
+        self.pending.condition.notify(token);
+
+        while self.idle.load(Ordering::Relaxed) < queued
+            && self.workers.load(Ordering::Relaxed) < MAX_WORKERS
+        {
+            self.spawn_worker(token)?;
+        }
+
+        // Make room for completions that did not fit when they were posted.
+        if self.flush_completions(&mut self.cq.lock()) {
+            self.notify_completions(token);
+        }
+        Ok(())
+    }
+
+    fn spawn_worker(self: &Arc<Self>, token: &mut CleanLockToken) -> Result<()> {
+        let context_lock = context::spawn(false, None, worker, token)?;
+        self.workers.fetch_add(1, Ordering::Relaxed);
+        self.idle.fetch_add(1, Ordering::Relaxed);
+        STARTING
+            .lock()
+            .insert(Arc::as_ptr(&context_lock) as usize, Arc::clone(self));
+
+        let mut context = context_lock.write(token.token());
+        context.name.clear();
+        context.name.push_str("[ioring]");
+        context.ens = self.ens;
+        context.syscall_filters = self.syscall_filters.clone();
+        context.euid = self.euid;
+        context.egid = self.egid;
+        context.acting_for = Some(self.pid);
+        context.status = Status::Runnable;
+        Ok(())
+    }
+
+    /// Wait for the next operation, or return None once the ring is closed.
+    fn next_sqe(&self, token: &mut CleanLockToken) -> Option<IoSqe> {
+        loop {
+            let mut pending = self.pending.inner.lock();
+            if let Some(sqe) = pending.pop_front() {
+                self.idle.fetch_sub(1, Ordering::Relaxed);
+                return Some(sqe);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            self.pending
+                .condition
+                .wait(pending, "IoRing::next_sqe", token);
+        }
+    }
+
+    /// Run an operation in the address space and file table of the opener.
+    fn run(&self, sqe: &IoSqe, token: &mut CleanLockToken) -> Result<usize> {
+        let addr_space = self.addr_space.upgrade().ok_or(Error::new(ESRCH))?;
+        let files = self.files.upgrade().ok_or(Error::new(ESRCH))?;
+
+        let current = context::current();
+        let old_files = {
+            let mut context = current.write(token.token());
+            context.set_addr_space(Some(addr_space));
+            mem::replace(&mut context.files, files)
+        };
+        let result = dispatch(sqe, token);
+        {
+            let mut context = current.write(token.token());
+            context.set_addr_space(None);
+            context.files = old_files;
+        }
+        result
+    }
+
+    fn complete(&self, cqe: IoCqe, token: &mut CleanLockToken) {
+        {
+            let mut cq = self.cq.lock();
+            cq.overflow.push_back(cqe);
+            self.flush_completions(&mut cq);
+        }
+        self.idle.fetch_add(1, Ordering::Relaxed);
+        self.notify_completions(token);
+    }
+
+    /// Write as many waiting completions as fit to the completion queue. Returns true if any
+    /// were written.
+    fn flush_completions(&self, cq: &mut CompletionQueue) -> bool {
+        let header = self.pages.header();
+        let head = header.cq_head.load(Ordering::Acquire);
+        let mut written = false;
+        while cq.tail.wrapping_sub(head) < cq_entries::<IoCqe>()
+            && let Some(cqe) = cq.overflow.pop_front()
+        {
+            self.pages.write_cqe_field::<IoCqe, _>(
+                cq.tail,
+                offset_of!(IoCqe, user_data),
+                cqe.user_data,
+            );
+            self.pages
+                .write_cqe_field::<IoCqe, _>(cq.tail, offset_of!(IoCqe, result), cqe.result);
+            cq.tail = cq.tail.wrapping_add(1);
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            written = true;
+        }
+        header.cq_tail.store(cq.tail, Ordering::Release);
+        written
+    }
+
+    fn notify_completions(&self, token: &mut CleanLockToken) {
+        self.completion_condition.notify(token);
+
+        // Pairs with userspace setting CQ_NEED_WAKEUP before checking the tail.
+        core::sync::atomic::fence(Ordering::SeqCst);
+        if self.pages.header().cq_flags.load(Ordering::Acquire) & CQ_NEED_WAKEUP != 0 {
+            futex::wake_physaddr(
+                self.pages.header_physaddr(offset_of!(RingHeader, cq_tail)),
+                token,
+            );
+        }
+
+        event::trigger(GlobalSchemes::IoRing.scheme_id(), self.id, EVENT_READ);
+    }
+
+    /// Number of completions userspace has not consumed yet.
+    fn ready(&self, cq: &CompletionQueue) -> u32 {
+        let head = self.pages.header().cq_head.load(Ordering::Acquire);
+        cq.tail.wrapping_sub(head).min(cq_entries::<IoCqe>())
+    }
+}
+
+fn dispatch(sqe: &IoSqe, token: &mut CleanLockToken) -> Result<usize> {
+    let fd = FileHandle::from(sqe.fd as usize);
+    let (addr, len) = (sqe.addr as usize, sqe.len as usize);
+
+    // Open is checked by the scheme it opens, by openat itself.
+    let number = match sqe.opcode {
+        IORING_OP_READ => SYS_READ,
+        IORING_OP_WRITE => SYS_WRITE,
+        IORING_OP_CALL => SYS_CALL,
+        _ => SYS_OPENAT,
+    };
+    filter::check_syscall(number, token)?;
+
+    match sqe.opcode {
+        IORING_OP_READ if sqe.offset == u64::MAX => sys_read(fd, UserSlice::wo(addr, len)?, token),
+        IORING_OP_READ => file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+            scheme.count(SchemeOp::Read);
+            scheme.kreadoff(
+                desc.number,
+                UserSlice::wo(addr, len)?,
+                sqe.offset,
+                desc.flags,
+                desc.flags,
+                token,
+            )
+        }),
+        IORING_OP_WRITE if sqe.offset == u64::MAX => {
+            sys_write(fd, UserSlice::ro(addr, len)?, token)
+        }
+        IORING_OP_WRITE => file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+            scheme.count(SchemeOp::Write);
+            scheme.kwriteoff(
+                desc.number,
+                UserSlice::ro(addr, len)?,
+                sqe.offset,
+                desc.flags,
+                desc.flags,
+                token,
+            )
+        }),
+        IORING_OP_OPENAT => openat(
+            fd,
+            UserSlice::ro(addr, len)?,
+            sqe.offset as usize,
+            sqe.fcntl_flags,
+            token,
+        )
+        .map(FileHandle::into),
+        IORING_OP_CALL => call(
+            fd,
+            UserSlice::rw(addr, len)?,
+            CallFlags::from_bits(sqe.offset as usize).ok_or(Error::new(EINVAL))?,
+            UserSlice::ro(
+                sqe.meta_addr as usize,
+                (sqe.meta_len as usize).saturating_mul(8),
+            )?,
+            token,
+        ),
+        _ => Err(Error::new(ENOSYS)),
+    }
+}
+
+extern "C" fn worker() {
+    let mut token = unsafe { CleanLockToken::new() };
+
+    let ring = STARTING
+        .lock()
+        .remove(&(Arc::as_ptr(&context::current()) as usize))
+        .expect("ioring worker started without a ring");
+    // Workers only borrow the address space of the opener while running operations.
+    context::current().write(token.token()).set_addr_space(None);
+
+    while let Some(sqe) = ring.next_sqe(&mut token) {
+        let result = ring.run(&sqe, &mut token);
+        ring.complete(
+            IoCqe {
+                user_data: sqe.user_data,
+                result: Error::mux(result) as u64,
+            },
+            &mut token,
+        );
+    }
+
+    ring.workers.fetch_sub(1, Ordering::Relaxed);
+    ring.idle.fetch_sub(1, Ordering::Relaxed);
+    drop(ring);
+    exit_this_context(None, &mut token);
+}
+
+pub struct IoRingScheme;
+
+impl KernelScheme for IoRingScheme {
+    fn kopen(
+        &self,
+        path: &str,
+        _flags: usize,
+        ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        if !path.trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let pages = RingPages::new(sq_entries::<IoSqe>(), cq_entries::<IoCqe>())
+            .ok_or(Error::new(ENOMEM))?;
+        let addr_space = AddrSpace::current()?;
+        let (files, ens, syscall_filters) = {
+            let current = context::current();
+            let context = current.read(token.token());
+            (
+                Arc::downgrade(&context.files),
+                context.ens,
+                context.syscall_filters.without_stops(),
+            )
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        RINGS.write(token.token()).insert(
+            id,
+            Arc::new(IoRing {
+                id,
+                pages,
+                sq_head: Mutex::new(0),
+                cq: Mutex::new(CompletionQueue {
+                    tail: 0,
+                    overflow: VecDeque::new(),
+                }),
+                completion_condition: WaitCondition::new(),
+                pending: WaitQueue::new(),
+                in_flight: AtomicUsize::new(0),
+                workers: AtomicUsize::new(0),
+                idle: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                addr_space: Arc::downgrade(&addr_space),
+                files,
+                ens,
+                syscall_filters,
+                euid: ctx.uid,
+                egid: ctx.gid,
+                pid: ctx.pid,
+            }),
+        );
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn fcntl(
+        &self,
+        _id: usize,
+        _cmd: usize,
+        _arg: usize,
+        _token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fevent(
+        &self,
+        id: usize,
+        flags: EventFlags,
+        token: &mut CleanLockToken,
+    ) -> Result<EventFlags> {
+        let ring = ring(id, token)?;
+        let ready = ring.ready(&ring.cq.lock());
+
+        Ok(if flags.contains(EVENT_READ) && ready > 0 {
+            EVENT_READ
+        } else {
+            EventFlags::empty()
+        })
+    }
+
+    fn fsync(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        let ring = ring(id, token)?;
+
+        // Operations run in the address space of the opener, so only it may submit them.
+        let is_opener = ring.addr_space.upgrade().is_some_and(|addr_space| {
+            AddrSpace::current().is_ok_and(|cur| Arc::ptr_eq(&cur, &addr_space))
+        });
+        if !is_opener {
+            return Err(Error::new(EPERM));
+        }
+
+        ring.enter(token)
+    }
+
+    fn kfmap(
+        &self,
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        ring(id, token)?.pages.map(addr_space, map, token)
+    }
+
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        let ring = RINGS
+            .write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))?;
+
+        // Operations that have not started are dropped, and idle workers exit.
+        {
+            let mut pending = ring.pending.inner.lock();
+            pending.clear();
+            ring.closed.store(true, Ordering::Relaxed);
+        }
+        ring.pending.condition.notify(token);
+        Ok(())
+    }
+
+    fn kread(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        flags: u32,
+        _stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let ring = ring(id, token)?;
+        let buf = buf.limit(size_of::<u64>()).ok_or(Error::new(EINVAL))?;
+
+        loop {
+            let mut cq = ring.cq.lock();
+            ring.flush_completions(&mut cq);
+
+            let ready = ring.ready(&cq);
+            if ready > 0 {
+                buf.copy_from_slice(&u64::from(ready).to_ne_bytes())?;
+                return Ok(size_of::<u64>());
+            }
+
+            if flags & O_NONBLOCK as u32 != 0 {
+                return Err(Error::new(EAGAIN));
+            } else if !ring.completion_condition.wait(cq, "IoRing::read", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+        ring(id, token)?;
+
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o600,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+}
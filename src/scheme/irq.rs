@@ -15,7 +15,10 @@ use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
 
 use crate::context::file::InternalFlags;
 
-use super::{CallerCtx, GlobalSchemes, OpenResult};
+use super::{
+    cap::{self, CapKind},
+    CallerCtx, GlobalSchemes, OpenResult,
+};
 #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 use crate::arch::interrupt::{available_irqs_iter, irq::acknowledge, is_reserved, set_reserved};
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
@@ -212,10 +215,10 @@ impl crate::scheme::KernelScheme for IrqScheme {
         &self,
         path: &str,
         flags: usize,
-        ctx: CallerCtx,
+        _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        if ctx.uid != 0 {
+        if !cap::held(CapKind::Irq, cap::IRQ_HANDLE, token) {
             return Err(Error::new(EACCES));
         }
 
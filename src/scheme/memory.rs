@@ -23,7 +23,10 @@ use crate::syscall::{
     usercopy::UserSliceWo,
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{
+    cap::{self, CapKind},
+    CallerCtx, KernelScheme, OpenResult,
+};
 
 pub struct MemoryScheme;
 
@@ -185,8 +188,8 @@ impl KernelScheme for MemoryScheme {
         &self,
         path: &str,
         _flags: usize,
-        ctx: CallerCtx,
-        _token: &mut CleanLockToken,
+        _ctx: CallerCtx,
+        token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         if path.len() > 64 {
             return Err(Error::new(ENOENT));
@@ -224,13 +227,14 @@ impl KernelScheme for MemoryScheme {
             .ok_or(Error::new(ENOENT))?;
 
         // TODO: Support arches with other default memory types?
-        if ctx.uid != 0
-            && (!flags.is_empty()
-                || !matches!(
-                    (handle_ty, mem_ty),
-                    (HandleTy::Allocated, MemoryType::Writeback)
-                ))
-        {
+        let rights = match handle_ty {
+            HandleTy::PhysBorrow | HandleTy::Translation => cap::MEMORY_PHYS,
+            HandleTy::Allocated if !flags.is_empty() || mem_ty != MemoryType::Writeback => {
+                cap::MEMORY_TYPED
+            }
+            HandleTy::Allocated => 0,
+        };
+        if rights != 0 && !cap::held(CapKind::Memory, rights, token) {
             return Err(Error::new(EACCES));
         }
 
@@ -8,7 +8,13 @@
 
 // TODO: Move handling of the global namespace to userspace.
 
-use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 use core::{hash::BuildHasherDefault, sync::atomic::AtomicUsize};
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use indexmap::IndexMap;
@@ -33,9 +39,9 @@ use self::acpi::AcpiScheme;
 use self::dtb::DtbScheme;
 
 use self::{
-    debug::DebugScheme, event::EventScheme, irq::IrqScheme, memory::MemoryScheme, pipe::PipeScheme,
-    proc::ProcScheme, root::RootScheme, serio::SerioScheme, sys::SysScheme, time::TimeScheme,
-    user::UserScheme,
+    cap::CapScheme, counter::CounterScheme, debug::DebugScheme, event::EventScheme,
+    ioring::IoRingScheme, irq::IrqScheme, memory::MemoryScheme, pipe::PipeScheme, proc::ProcScheme,
+    root::RootScheme, serio::SerioScheme, sys::SysScheme, time::TimeScheme, user::UserScheme,
 };
 
 /// When compiled with the "acpi" feature - `acpi:` - allows drivers to read a limited set of ACPI tables.
@@ -44,12 +50,24 @@ pub mod acpi;
 #[cfg(dtb)]
 pub mod dtb;
 
+/// `kernel.cap:` - capabilities for privileged kernel operations, held as file descriptors
+pub mod cap;
+
+/// `counter:` - eventfd-like counters that can be waited on using `event:`
+pub mod counter;
+
 /// `debug:` - provides access to serial console
 pub mod debug;
 
 /// `event:` - allows reading of `Event`s which are registered using `fevent`
 pub mod event;
 
+/// Fair queueing of requests to userspace schemes, tightly dependent on `user`
+pub mod fair_queue;
+
+/// `ioring:` - rings of asynchronous file operations, run by kernel worker contexts
+pub mod ioring;
+
 /// `irq:` - allows userspace handling of IRQs
 pub mod irq;
 
@@ -65,9 +83,15 @@ pub mod proc;
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
+/// Shared-memory request rings of userspace schemes, tightly dependent on `user`
+pub mod ring;
+
 /// `serio:` - provides access to ps/2 devices
 pub mod serio;
 
+/// Per-scheme call counters and request latencies, shown in `sys:schemestat`
+pub mod stats;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
@@ -136,6 +160,10 @@ impl<'a> Iterator for SchemeIter<'a> {
 pub struct SchemeList {
     map: HashMap<SchemeId, KernelSchemes>,
     pub(crate) names: HashMap<SchemeNamespace, IndexMap<Box<str>, SchemeId, DefaultHashBuilder>>,
+    /// How long calls to user schemes made from a namespace may take, in nanoseconds.
+    call_timeouts: HashMap<SchemeNamespace, u128>,
+    /// The names of a namespace that are bound to a subpath of their scheme, and these subpaths.
+    bound_paths: HashMap<SchemeNamespace, HashMap<Box<str>, Box<str>>>,
     next_ns: usize,
     next_id: usize,
 }
@@ -145,6 +173,8 @@ impl SchemeList {
         let mut list = SchemeList {
             map: HashMap::new(),
             names: HashMap::new(),
+            call_timeouts: HashMap::new(),
+            bound_paths: HashMap::new(),
             // Scheme namespaces always start at 1. 0 is a reserved namespace, the null namespace
             next_ns: 1,
             next_id: MAX_GLOBAL_SCHEMES,
@@ -160,7 +190,9 @@ impl SchemeList {
         // TODO: impl TryFrom<SchemeId> and bypass map for global schemes?
         {
             use GlobalSchemes::*;
-            insert_globals(&[Debug, Event, Memory, Pipe, Serio, Irq, Time, Sys, Proc]);
+            insert_globals(&[
+                Debug, Event, Memory, Pipe, Serio, Irq, Time, Sys, Proc, Counter, IoRing, Cap,
+            ]);
 
             #[cfg(feature = "acpi")]
             insert_globals(&[Acpi]);
@@ -186,22 +218,26 @@ impl SchemeList {
         self.insert_global(ns, "pipe", GlobalSchemes::Pipe);
     }
 
-    /// Initialize a new namespace
-    fn new_ns(&mut self) -> SchemeNamespace {
+    /// Initialize a new namespace without any scheme
+    fn new_empty_ns(&mut self) -> SchemeNamespace {
         let ns = SchemeNamespace(self.next_ns);
         self.next_ns += 1;
         self.names
             .insert(ns, IndexMap::with_hasher(BuildHasherDefault::default()));
+        ns
+    }
+
+    /// Initialize a new namespace
+    fn new_ns(&mut self) -> SchemeNamespace {
+        let ns = self.new_empty_ns();
 
         self.insert(ns, "", |scheme_id| {
             KernelSchemes::Root(Arc::new(RootScheme::new(ns, scheme_id)))
         })
         .unwrap();
-        self.insert_global(ns, "event", GlobalSchemes::Event);
-        self.insert_global(ns, "memory", GlobalSchemes::Memory);
-        self.insert_global(ns, "pipe", GlobalSchemes::Pipe);
-        self.insert_global(ns, "sys", GlobalSchemes::Sys);
-        self.insert_global(ns, "time", GlobalSchemes::Time);
+        for &(name, global) in NS_GLOBALS {
+            self.insert_global(ns, name, global);
+        }
 
         ns
     }
@@ -218,36 +254,67 @@ impl SchemeList {
         self.insert_global(ns, "kernel.acpi", GlobalSchemes::Acpi);
         self.insert_global(ns, "debug", GlobalSchemes::Debug);
         self.insert_global(ns, "irq", GlobalSchemes::Irq);
+        self.insert_global(ns, "kernel.cap", GlobalSchemes::Cap);
         self.insert_global(ns, "kernel.proc", GlobalSchemes::Proc);
         self.insert_global(ns, "serio", GlobalSchemes::Serio);
     }
 
+    /// Create a namespace from schemes of `from`. Each entry is either the name of a scheme to
+    /// copy, `new=old` to copy the scheme `old` under the name `new`, or `new=old/path` to only
+    /// bind the subpath `path` of `old` as `new`. Unless `empty` is set, the new namespace also
+    /// gets its own root scheme and the schemes every namespace has.
     pub fn make_ns(
         &mut self,
         from: SchemeNamespace,
-        names: impl IntoIterator<Item = Box<str>>,
+        empty: bool,
+        entries: impl IntoIterator<Item = Box<str>>,
     ) -> Result<SchemeNamespace> {
-        // Create an empty namespace
-        let to = self.new_ns();
+        // Parse the entries first, so that no namespace is created for invalid ones
+        let mut binds: Vec<(Box<str>, SchemeId, String)> = Vec::new();
+        for entry in entries {
+            let (name, source) = entry.split_once('=').unwrap_or((&*entry, &*entry));
+            let (old, path) = source.split_once('/').unwrap_or((source, ""));
+            if name.is_empty() || name.contains(['/', ':']) {
+                return Err(Error::new(EINVAL));
+            }
+            if binds.iter().any(|(bound, _, _)| **bound == *name)
+                || !empty && NS_GLOBALS.iter().any(|&(global, _)| global == name)
+            {
+                return Err(Error::new(EEXIST));
+            }
+            let (id, _scheme) = self.get_name(from, old).ok_or(Error::new(ENODEV))?;
+
+            // Names bound to a subpath stay bound to it, and their own subpaths are within it.
+            let path = bound_path(
+                self.bound_paths
+                    .get(&from)
+                    .and_then(|paths| paths.get(old))
+                    .map_or("", |path| &**path),
+                path.trim_end_matches('/'),
+            );
+            binds.push((Box::<str>::from(name), id, path));
+        }
+
+        let to = if empty {
+            self.new_empty_ns()
+        } else {
+            self.new_ns()
+        };
+
+        // Calls made from the new namespace time out like those of its parent
+        if let Some(timeout) = self.call_timeout(from) {
+            self.call_timeouts.insert(to, timeout);
+        }
 
         // Copy requested scheme IDs
-        for name in names {
-            let Some((id, _scheme)) = self.get_name(from, &name) else {
-                return Err(Error::new(ENODEV));
-            };
-
-            match self.names.get_mut(&to) {
-                Some(ref mut names) => {
-                    if names
-                        .insert(name.to_string().into_boxed_str(), id)
-                        .is_some()
-                    {
-                        return Err(Error::new(EEXIST));
-                    }
-                }
-                _ => {
-                    panic!("scheme namespace not found");
-                }
+        for (name, id, path) in binds {
+            let names = self.names.get_mut(&to).expect("scheme namespace not found");
+            assert!(names.insert(name.clone(), id).is_none());
+            if !path.is_empty() {
+                self.bound_paths
+                    .entry(to)
+                    .or_default()
+                    .insert(name, path.into_boxed_str());
             }
         }
 
@@ -260,6 +327,23 @@ impl SchemeList {
         }
     }
 
+    /// How long calls to user schemes made from `ns` may take before they are canceled.
+    pub fn call_timeout(&self, ns: SchemeNamespace) -> Option<u128> {
+        self.call_timeouts.get(&ns).copied()
+    }
+
+    /// Set or remove the timeout of calls to user schemes made from `ns`.
+    pub fn set_call_timeout(&mut self, ns: SchemeNamespace, timeout: Option<u128>) -> Result<()> {
+        if !self.names.contains_key(&ns) {
+            return Err(Error::new(ENODEV));
+        }
+        match timeout {
+            Some(timeout) => self.call_timeouts.insert(ns, timeout),
+            None => self.call_timeouts.remove(&ns),
+        };
+        Ok(())
+    }
+
     /// Get the nth scheme.
     pub fn get(&self, id: SchemeId) -> Option<&KernelSchemes> {
         self.map.get(&id)
@@ -274,6 +358,46 @@ impl SchemeList {
         None
     }
 
+    /// Get a scheme by name, along with the reference to pass to it for `reference`, which is
+    /// within the bound subpath if the name was only bound to a subpath of the scheme.
+    pub fn get_name_ref<'a>(
+        &self,
+        ns: SchemeNamespace,
+        name: &str,
+        reference: &'a str,
+    ) -> Option<(SchemeId, &KernelSchemes, Cow<'a, str>)> {
+        let (id, scheme) = self.get_name(ns, name)?;
+        let reference = match self.bound_paths.get(&ns).and_then(|paths| paths.get(name)) {
+            Some(path) => Cow::Owned(bound_path(path, reference)),
+            None => Cow::Borrowed(reference),
+        };
+        Some((id, scheme, reference))
+    }
+
+    /// Whether `name` is bound to a subpath of its scheme in `ns`.
+    pub fn is_bound(&self, ns: SchemeNamespace, name: &str) -> bool {
+        self.bound_paths
+            .get(&ns)
+            .is_some_and(|paths| paths.contains_key(name))
+    }
+
+    /// Give `reference`, a path of the scheme `id`, as a path of the name of `ns` bound to the
+    /// subpath containing it, if there is one.
+    pub fn unbind_ref(&self, ns: SchemeNamespace, id: SchemeId, reference: &str) -> Option<String> {
+        let names = self.names.get(&ns)?;
+        let reference = reference.trim_start_matches('/');
+        self.bound_paths
+            .get(&ns)?
+            .iter()
+            .filter(|(name, _)| names.get(&**name) == Some(&id))
+            .filter_map(|(name, path)| {
+                let rest = reference.strip_prefix(&**path)?;
+                (rest.is_empty() || rest.starts_with('/')).then_some((name, path.len(), rest))
+            })
+            .max_by_key(|&(_, len, _)| len)
+            .map(|(name, _, rest)| format!("{}:/{}", name, rest.trim_start_matches('/')))
+    }
+
     fn insert_global(&mut self, ns: SchemeNamespace, name: &str, global: GlobalSchemes) {
         let prev = self
             .names
@@ -310,22 +434,18 @@ impl SchemeList {
             }
         }
 
-        if self.next_id >= SCHEME_MAX_SCHEMES {
-            self.next_id = 1;
+        if self.map.len() >= SCHEME_MAX_SCHEMES {
+            return Err(Error::new(EAGAIN));
         }
 
+        // Ids only repeat once the counter wraps around, so that file descriptions of removed
+        // schemes fail with ENODEV rather than reach a scheme registered after them.
         while self.map.contains_key(&SchemeId(self.next_id)) {
-            self.next_id += 1;
-        }
-
-        /* Allow scheme list to grow if required
-        if self.next_id >= SCHEME_MAX_SCHEMES {
-            return Err(Error::new(EAGAIN));
+            self.advance_id();
         }
-        */
 
         let id = SchemeId(self.next_id);
-        self.next_id += 1;
+        self.advance_id();
 
         let (new_scheme, t) = scheme_fn(id);
 
@@ -344,10 +464,13 @@ impl SchemeList {
         Ok((id, t))
     }
 
-    /// Remove a scheme
+    fn advance_id(&mut self) {
+        self.next_id = self.next_id.checked_add(1).unwrap_or(MAX_GLOBAL_SCHEMES);
+    }
+
     pub fn remove(&mut self, id: SchemeId) {
         assert!(self.map.remove(&id).is_some());
-        for (_ns, names) in self.names.iter_mut() {
+        for (ns, names) in self.names.iter_mut() {
             let mut remove = Vec::with_capacity(1);
             for (name, name_id) in names.iter() {
                 if name_id == &id {
@@ -356,9 +479,39 @@ impl SchemeList {
             }
             for name in remove {
                 assert!(names.swap_remove(&name).is_some());
+                if let Some(paths) = self.bound_paths.get_mut(ns) {
+                    paths.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+/// Resolve `reference` within `path`, a subpath of a scheme, so that `..` cannot leave it. Empty
+/// and `.` components are dropped, but a trailing slash is kept.
+fn bound_path(path: &str, reference: &str) -> String {
+    let mut components = Vec::new();
+    for component in reference.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
             }
+            component => components.push(component),
+        }
+    }
+
+    let mut bound = String::from(path);
+    for component in components {
+        if !bound.is_empty() {
+            bound.push('/');
         }
+        bound.push_str(component);
+    }
+    if reference.ends_with('/') && !bound.is_empty() {
+        bound.push('/');
     }
+    bound
 }
 
 /// Schemes list
@@ -484,6 +637,15 @@ pub trait KernelScheme: Send + Sync + 'static {
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
         Err(Error::new(EBADF))
     }
+    /// Like `kfpath`, but into a kernel buffer.
+    fn kfpath_kernel(
+        &self,
+        id: usize,
+        buf: &mut [u8],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        self.kfpath(id, unsafe { UserSliceWo::kernel(buf) }, token)
+    }
     fn kfutimens(&self, id: usize, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usize> {
         Err(Error::new(EBADF))
     }
@@ -641,6 +803,9 @@ pub enum GlobalSchemes {
     Time,
     Sys,
     Proc,
+    Counter,
+    IoRing,
+    Cap,
 
     #[cfg(feature = "acpi")]
     Acpi,
@@ -650,6 +815,17 @@ pub enum GlobalSchemes {
 }
 pub const MAX_GLOBAL_SCHEMES: usize = 16;
 
+/// The schemes every namespace has, besides its root scheme.
+const NS_GLOBALS: &[(&str, GlobalSchemes)] = &[
+    ("counter", GlobalSchemes::Counter),
+    ("event", GlobalSchemes::Event),
+    ("ioring", GlobalSchemes::IoRing),
+    ("memory", GlobalSchemes::Memory),
+    ("pipe", GlobalSchemes::Pipe),
+    ("sys", GlobalSchemes::Sys),
+    ("time", GlobalSchemes::Time),
+];
+
 const _: () = {
     assert!(1 + core::mem::variant_count::<GlobalSchemes>() < MAX_GLOBAL_SCHEMES);
 };
@@ -680,6 +856,9 @@ impl core::ops::Deref for GlobalSchemes {
             Self::Time => &TimeScheme,
             Self::Sys => &SysScheme,
             Self::Proc => &ProcScheme,
+            Self::Counter => &CounterScheme,
+            Self::IoRing => &IoRingScheme,
+            Self::Cap => &CapScheme,
             #[cfg(feature = "acpi")]
             Self::Acpi => &AcpiScheme,
             #[cfg(dtb)]
@@ -1,21 +1,35 @@
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
 use crate::{
-    context::file::InternalFlags,
+    context::{
+        self,
+        file::{FileDescription, FileDescriptor, InternalFlags},
+    },
     event,
+    memory::{Frame, RaiiFrame},
+    paging::{RmmA, RmmArch, PAGE_SIZE},
     sync::{CleanLockToken, RwLock, WaitCondition, L1},
     syscall::{
         data::Stat,
-        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, EPIPE},
-        flag::{EventFlags, EVENT_READ, EVENT_WRITE, MODE_FIFO, O_NONBLOCK},
-        usercopy::{UserSliceRo, UserSliceWo},
+        error::{
+            Error, Result, EAGAIN, EBADF, EBUSY, EEXIST, EFAULT, EINTR, EINVAL, EMSGSIZE, ENODEV,
+            ENOENT, ENXIO, EOPNOTSUPP, EPERM, EPIPE,
+        },
+        flag::{
+            EventFlags, EVENT_READ, EVENT_WRITE, MODE_FIFO, MODE_SOCK, O_ACCMODE, O_CLOEXEC,
+            O_CREAT, O_EXCL, O_NONBLOCK, O_RDONLY, O_WRONLY,
+        },
+        usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
     },
 };
 
-use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult, StrOrBytes};
+use super::{
+    schemes, user::UserInner, CallerCtx, FileHandle, GlobalSchemes, KernelScheme, KernelSchemes,
+    OpenResult, StrOrBytes,
+};
 
 // TODO: Preallocate a number of scheme IDs, since there can only be *one* root namespace, and
 // therefore only *one* pipe scheme.
@@ -24,33 +38,250 @@ static PIPE_NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // TODO: SLOB?
 static PIPES: RwLock<L1, HashMap<usize, Arc<Pipe>>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+static CHANNELS: RwLock<L1, HashMap<usize, Arc<Channel>>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+static FIFOS: RwLock<L1, HashMap<usize, Arc<Fifo>>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
 
-const MAX_QUEUE_SIZE: usize = 65536;
+/// Capacity of newly created pipes, in bytes.
+const DEFAULT_PIPE_SIZE: usize = 65536;
+
+/// Largest capacity that can be set using `F_SETPIPE_SZ`.
+const MAX_PIPE_SIZE: usize = 1 << 20;
+
+/// Maximum number of messages queued in a packet-mode pipe or channel, regardless of their size.
+const MAX_PIPE_PACKETS: usize = 128;
+
+/// Set the capacity of a pipe, rounded up to a multiple of the page size. Returns the new capacity.
+pub const F_SETPIPE_SZ: usize = 1031;
+/// Get the capacity of a pipe.
+pub const F_GETPIPE_SZ: usize = 1032;
+
+/// Verbs accepted by `SYS_CALL` on pipe handles. `metadata[0]` holds the verb in its low byte and
+/// [`SpliceFlags`] in its upper 32 bits, `metadata[1]` the other file descriptor, and
+/// `metadata[2]` the maximum number of bytes to transfer.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum PipeVerb {
+    /// Move data between this pipe end and another fd. Pages are passed by reference when the
+    /// other fd is a pipe or a user scheme file, rather than being copied through the kernel.
+    Splice = 0,
+    /// Duplicate data from this read end into another pipe's write end, without consuming it.
+    Tee = 1,
+}
+impl PipeVerb {
+    pub fn try_from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => Self::Splice,
+            1 => Self::Tee,
+            _ => return None,
+        })
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct SpliceFlags: u32 {
+        /// Return EAGAIN rather than blocking on the pipe.
+        const NONBLOCK = 1;
+    }
+}
 
 // In almost all places where Rust (and LLVM) uses pointers, they are limited to nonnegative isize,
 // so this is fine.
 const WRITE_NOT_READ_BIT: usize = 1 << (usize::BITS - 1);
 
+/// Set in the ids of channel endpoints, which are keyed in `CHANNELS` rather than `PIPES`. The
+/// write bit then selects the second endpoint.
+const CHANNEL_BIT: usize = 1 << (usize::BITS - 2);
+
+/// Set in the ids of FIFO node handles, which are keyed in `FIFOS`.
+const FIFO_BIT: usize = 1 << (usize::BITS - 3);
+
+enum Handle {
+    Reader(Arc<Pipe>),
+    Writer(Arc<Pipe>),
+    Endpoint(Arc<Channel>, usize),
+    Fifo(Arc<Fifo>),
+}
+
 fn from_raw_id(id: usize) -> (bool, usize) {
     (id & WRITE_NOT_READ_BIT != 0, id & !WRITE_NOT_READ_BIT)
 }
 
-pub fn pipe(token: &mut CleanLockToken) -> Result<(usize, usize)> {
+fn handle(id: usize, token: &mut CleanLockToken) -> Result<(usize, Handle)> {
+    let (is_writer_not_reader, key) = from_raw_id(id);
+
+    if key & FIFO_BIT != 0 {
+        let fifo = Arc::clone(
+            FIFOS
+                .read(token.token())
+                .get(&key)
+                .ok_or(Error::new(EBADF))?,
+        );
+        return Ok((key, Handle::Fifo(fifo)));
+    }
+    if key & CHANNEL_BIT != 0 {
+        let channel = Arc::clone(
+            CHANNELS
+                .read(token.token())
+                .get(&key)
+                .ok_or(Error::new(EBADF))?,
+        );
+        return Ok((
+            key,
+            Handle::Endpoint(channel, usize::from(is_writer_not_reader)),
+        ));
+    }
+
+    let pipe = Arc::clone(
+        PIPES
+            .read(token.token())
+            .get(&key)
+            .ok_or(Error::new(EBADF))?,
+    );
+
+    Ok((
+        key,
+        if is_writer_not_reader {
+            Handle::Writer(pipe)
+        } else {
+            Handle::Reader(pipe)
+        },
+    ))
+}
+
+pub fn pipe(packet_mode: bool, token: &mut CleanLockToken) -> Result<(usize, usize)> {
     let id = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed);
 
     PIPES.write(token.token()).insert(
         id,
-        Arc::new(Pipe {
-            queue: Mutex::new(VecDeque::new()),
-            read_condition: WaitCondition::new(),
-            write_condition: WaitCondition::new(),
-            writer_is_alive: AtomicBool::new(true),
-            reader_is_alive: AtomicBool::new(true),
+        Arc::new(Pipe::new(id, id | WRITE_NOT_READ_BIT, packet_mode)),
+    );
+
+    Ok((id, id | WRITE_NOT_READ_BIT))
+}
+
+pub fn channel(token: &mut CleanLockToken) -> Result<(usize, usize)> {
+    let key = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed) | CHANNEL_BIT;
+    let ids = [key, key | WRITE_NOT_READ_BIT];
+
+    CHANNELS.write(token.token()).insert(
+        key,
+        Arc::new(Channel {
+            endpoints: [Endpoint::new(ids[0], ids[1]), Endpoint::new(ids[1], ids[0])],
             has_run_dup: AtomicBool::new(false),
         }),
     );
 
-    Ok((id, id | WRITE_NOT_READ_BIT))
+    Ok((ids[0], ids[1]))
+}
+
+/// Open the FIFO node handle named `name`, creating the FIFO if `O_CREAT` is set.
+fn open_fifo_node(name: &str, flags: usize, token: &mut CleanLockToken) -> Result<usize> {
+    if name.is_empty() {
+        return Err(Error::new(ENOENT));
+    }
+
+    let mut fifos = FIFOS.write(token.token());
+
+    let existing = fifos.values().find(|fifo| *fifo.name == *name).cloned();
+
+    let fifo = match existing {
+        Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => {
+            return Err(Error::new(EEXIST));
+        }
+        Some(fifo) => fifo,
+        None if flags & O_CREAT == 0 => return Err(Error::new(ENOENT)),
+        None => {
+            let key = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed) | FIFO_BIT;
+            let fifo = Arc::new(Fifo {
+                key,
+                name: name.into(),
+                state: Mutex::new(FifoState {
+                    pipe: None,
+                    nodes: 0,
+                    readers: 0,
+                    writers: 0,
+                }),
+                open_condition: WaitCondition::new(),
+            });
+            fifos.insert(key, Arc::clone(&fifo));
+            fifo
+        }
+    };
+    // Counted while FIFOS is locked, so that the FIFO cannot be removed in between.
+    fifo.state.lock().nodes += 1;
+
+    Ok(fifo.key)
+}
+
+/// Remove the FIFO keyed `key` if nothing refers to it anymore.
+fn remove_fifo_if_unused(key: usize, token: &mut CleanLockToken) {
+    let mut fifos = FIFOS.write(token.token());
+
+    if fifos
+        .get(&key)
+        .is_some_and(|fifo| fifo.state.lock().is_unused())
+    {
+        fifos.remove(&key);
+    }
+}
+
+/// Resolve an open that a filesystem scheme redirected to a FIFO, by responding with a FIFO node
+/// handle (see [`Fifo`]). The node handle is replaced with a read or write end of the FIFO's pipe,
+/// depending on the access mode in `flags`. Other descriptions are returned as is.
+pub fn resolve_fifo(
+    description: Arc<spin::RwLock<FileDescription>>,
+    flags: usize,
+    token: &mut CleanLockToken,
+) -> Result<Arc<spin::RwLock<FileDescription>>> {
+    let desc = *description.read();
+
+    if desc.scheme != GlobalSchemes::Pipe.scheme_id()
+        || desc.number & (FIFO_BIT | WRITE_NOT_READ_BIT) != FIFO_BIT
+    {
+        return Ok(description);
+    }
+
+    let result = match handle(desc.number, token) {
+        Ok((_, Handle::Fifo(fifo))) => match flags & O_ACCMODE {
+            O_RDONLY => fifo.open(false, flags & O_NONBLOCK != 0, token),
+            O_WRONLY => fifo.open(true, flags & O_NONBLOCK != 0, token),
+            // TODO: Linux allows this without blocking, but a handle can only be one end.
+            _ => Err(Error::new(EINVAL)),
+        },
+        Ok(_) => Err(Error::new(EBADF)),
+        Err(error) => Err(error),
+    };
+
+    let _ = FileDescriptor {
+        description,
+        cloexec: false,
+    }
+    .close(token);
+
+    Ok(Arc::new(spin::RwLock::new(FileDescription {
+        scheme: desc.scheme,
+        number: result?,
+        offset: 0,
+        flags: (flags & !O_CLOEXEC) as u32,
+        internal_flags: InternalFlags::empty(),
+    })))
+}
+
+/// Close the given descriptions, which were in transit through a channel.
+fn close_descriptions(
+    descriptions: Vec<Arc<spin::RwLock<FileDescription>>>,
+    token: &mut CleanLockToken,
+) {
+    for description in descriptions {
+        let _ = FileDescriptor {
+            description,
+            cloexec: false,
+        }
+        .close(token);
+    }
 }
 
 pub struct PipeScheme;
@@ -62,62 +293,104 @@ impl KernelScheme for PipeScheme {
         flags: EventFlags,
         token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        let (is_writer_not_reader, key) = from_raw_id(id);
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
+        let (_, handle) = handle(id, token)?;
+        let (inbound, outbound) = match handle {
+            Handle::Reader(ref pipe) => (Some(&**pipe), None),
+            Handle::Writer(ref pipe) => (None, Some(&**pipe)),
+            Handle::Endpoint(ref channel, side) => (
+                Some(&channel.endpoints[side].inbound),
+                Some(&channel.endpoints[1 - side].inbound),
+            ),
+            Handle::Fifo(_) => (None, None),
+        };
 
         let mut ready = EventFlags::empty();
 
-        if is_writer_not_reader
-            && flags.contains(EVENT_WRITE)
-            && (pipe.queue.lock().len() <= MAX_QUEUE_SIZE
-                || !pipe.reader_is_alive.load(Ordering::Acquire))
-        {
+        if flags.contains(EVENT_WRITE) && outbound.is_some_and(Pipe::is_writable) {
             ready |= EventFlags::EVENT_WRITE;
         }
-        if !is_writer_not_reader
-            && flags.contains(EVENT_READ)
-            && (!pipe.queue.lock().is_empty() || !pipe.writer_is_alive.load(Ordering::Acquire))
-        {
+        if flags.contains(EVENT_READ) && inbound.is_some_and(Pipe::is_readable) {
             ready |= EventFlags::EVENT_READ;
         }
 
         Ok(ready)
     }
 
-    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        let (is_write_not_read, key) = from_raw_id(id);
+    fn fcntl(
+        &self,
+        id: usize,
+        cmd: usize,
+        arg: usize,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let (_, handle) = handle(id, token)?;
+        // For channels, the capacity of the endpoint's own receive queue is used.
+        let pipe = match handle {
+            Handle::Reader(ref pipe) | Handle::Writer(ref pipe) => &**pipe,
+            Handle::Endpoint(ref channel, side) => &channel.endpoints[side].inbound,
+            Handle::Fifo(_) if matches!(cmd, F_GETPIPE_SZ | F_SETPIPE_SZ) => {
+                return Err(Error::new(EBADF));
+            }
+            Handle::Fifo(_) => return Ok(0),
+        };
 
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
-        let scheme_id = GlobalSchemes::Pipe.scheme_id();
+        match cmd {
+            F_GETPIPE_SZ => Ok(pipe.capacity.load(Ordering::Relaxed)),
+            F_SETPIPE_SZ => {
+                let new_capacity = arg
+                    .checked_next_multiple_of(PAGE_SIZE)
+                    .ok_or(Error::new(EINVAL))?
+                    .max(PAGE_SIZE);
+                if new_capacity > MAX_PIPE_SIZE {
+                    return Err(Error::new(EPERM));
+                }
 
-        let can_remove = if is_write_not_read {
-            event::trigger(scheme_id, key, EVENT_READ);
+                {
+                    let queue = pipe.queue.lock();
 
-            pipe.read_condition.notify(token);
-            pipe.writer_is_alive.store(false, Ordering::SeqCst);
+                    // Like Linux, refuse to shrink the pipe below what it currently holds.
+                    if queue.len() > new_capacity {
+                        return Err(Error::new(EBUSY));
+                    }
+                    pipe.capacity.store(new_capacity, Ordering::Relaxed);
+                }
 
-            !pipe.reader_is_alive.load(Ordering::SeqCst)
-        } else {
-            event::trigger(scheme_id, key | WRITE_NOT_READ_BIT, EVENT_WRITE);
+                // Growing the pipe may have made room for blocked writers.
+                pipe.notify_writable(token);
 
-            pipe.write_condition.notify(token);
-            pipe.reader_is_alive.store(false, Ordering::SeqCst);
+                Ok(new_capacity)
+            }
+            _ => Ok(0),
+        }
+    }
 
-            !pipe.writer_is_alive.load(Ordering::SeqCst)
-        };
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        let (key, handle) = handle(id, token)?;
 
-        if can_remove {
-            let _ = PIPES.write(token.token()).remove(&key);
+        match handle {
+            Handle::Reader(pipe) | Handle::Writer(pipe) if pipe.fifo.is_some() => {
+                let fifo = pipe.fifo.as_ref().expect("checked above");
+                fifo.release(id & WRITE_NOT_READ_BIT != 0, token);
+            }
+            Handle::Reader(pipe) => {
+                if pipe.close_reader(token) {
+                    let _ = PIPES.write(token.token()).remove(&key);
+                }
+            }
+            Handle::Writer(pipe) => {
+                if pipe.close_writer(token) {
+                    let _ = PIPES.write(token.token()).remove(&key);
+                }
+            }
+            Handle::Fifo(fifo) => {
+                fifo.state.lock().nodes -= 1;
+                remove_fifo_if_unused(key, token);
+            }
+            Handle::Endpoint(channel, side) => {
+                if channel.close(side, token) {
+                    let _ = CHANNELS.write(token.token()).remove(&key);
+                }
+            }
         }
 
         Ok(())
@@ -130,26 +403,24 @@ impl KernelScheme for PipeScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let (is_writer_not_reader, key) = from_raw_id(old_id);
+        let (key, handle) = handle(old_id, token)?;
 
-        if is_writer_not_reader {
-            return Err(Error::new(EBADF));
-        }
+        let (expected, has_run_dup) = match handle {
+            Handle::Reader(ref pipe) => (&b"write"[..], &pipe.has_run_dup),
+            Handle::Endpoint(ref channel, 0) => (&b"peer"[..], &channel.has_run_dup),
+            Handle::Writer(_) | Handle::Endpoint(..) | Handle::Fifo(_) => {
+                return Err(Error::new(EBADF));
+            }
+        };
 
         let mut buf = [0_u8; 5];
+        let len = user_buf.copy_common_bytes_to_slice(&mut buf)?;
 
-        if user_buf.copy_common_bytes_to_slice(&mut buf)? < 5 || buf != *b"write" {
+        if len < expected.len() || buf[..expected.len()] != *expected {
             return Err(Error::new(EINVAL));
         }
 
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
-
-        if pipe.has_run_dup.swap(true, Ordering::SeqCst) {
+        if has_run_dup.swap(true, Ordering::SeqCst) {
             return Err(Error::new(EBADF));
         }
 
@@ -161,17 +432,25 @@ impl KernelScheme for PipeScheme {
     fn kopen(
         &self,
         path: &str,
-        _flags: usize,
+        flags: usize,
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        if !path.trim_start_matches('/').is_empty() {
-            return Err(Error::new(ENOENT));
+        let path = path.trim_start_matches('/');
+
+        if let Some(name) = path.strip_prefix("fifo/") {
+            let id = open_fifo_node(name, flags, token)?;
+            return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
         }
 
-        let (read_id, _) = pipe(token)?;
+        let (first_id, _) = match path {
+            "" => pipe(false, token)?,
+            "packet" => pipe(true, token)?,
+            "channel" => channel(token)?,
+            _ => return Err(Error::new(ENOENT)),
+        };
 
-        Ok(OpenResult::SchemeLocal(read_id, InternalFlags::empty()))
+        Ok(OpenResult::SchemeLocal(first_id, InternalFlags::empty()))
     }
 
     fn kopenat(
@@ -183,21 +462,19 @@ impl KernelScheme for PipeScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let (_, key) = from_raw_id(id);
-
         let buf = user_buf.as_str().or(Err(Error::new(EINVAL)))?;
         if buf == "write" {
             return Err(Error::new(EINVAL));
         }
 
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
+        let (key, handle) = handle(id, token)?;
+        let has_run_dup = match handle {
+            Handle::Reader(ref pipe) | Handle::Writer(ref pipe) => &pipe.has_run_dup,
+            Handle::Endpoint(ref channel, _) => &channel.has_run_dup,
+            Handle::Fifo(_) => return Err(Error::new(EBADF)),
+        };
 
-        if pipe.has_run_dup.swap(true, Ordering::SeqCst) {
+        if has_run_dup.swap(true, Ordering::SeqCst) {
             return Err(Error::new(EBADF));
         }
 
@@ -215,57 +492,20 @@ impl KernelScheme for PipeScheme {
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
+        let nonblock = fcntl_flags & O_NONBLOCK as u32 != 0;
 
-        if is_write_not_read {
-            return Err(Error::new(EBADF));
-        }
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
+        match handle(id, token)?.1 {
+            Handle::Reader(pipe) => Ok(pipe.read(user_buf, nonblock, token)?.0),
+            Handle::Writer(_) | Handle::Fifo(_) => Err(Error::new(EBADF)),
+            Handle::Endpoint(channel, side) => {
+                let endpoint = &channel.endpoints[side];
+                let (bytes_read, fds) = endpoint.inbound.read(user_buf, nonblock, token)?;
 
-        loop {
-            let mut vec = pipe.queue.lock();
-
-            let (s1, s2) = vec.as_slices();
-            let s1_count = core::cmp::min(user_buf.len(), s1.len());
-
-            let (s1_dst, s2_buf) = user_buf
-                .split_at(s1_count)
-                .expect("s1_count <= user_buf.len()");
-            s1_dst.copy_from_slice(&s1[..s1_count])?;
-
-            let s2_count = core::cmp::min(s2_buf.len(), s2.len());
-            s2_buf
-                .limit(s2_count)
-                .expect("s2_count <= s2_buf.len()")
-                .copy_from_slice(&s2[..s2_count])?;
-
-            let bytes_read = s1_count + s2_count;
-            let _ = vec.drain(..bytes_read);
-
-            if bytes_read > 0 {
-                event::trigger(
-                    GlobalSchemes::Pipe.scheme_id(),
-                    key | WRITE_NOT_READ_BIT,
-                    EVENT_WRITE,
-                );
-                pipe.write_condition.notify(token);
-
-                return Ok(bytes_read);
-            } else if user_buf.is_empty() {
-                return Ok(0);
-            }
+                if !fds.is_empty() {
+                    endpoint.received_fds.lock().extend(fds);
+                }
 
-            if !pipe.writer_is_alive.load(Ordering::SeqCst) {
-                return Ok(0);
-            } else if fcntl_flags & O_NONBLOCK as u32 != 0 {
-                return Err(Error::new(EAGAIN));
-            } else if !pipe.read_condition.wait(vec, "PipeRead::read", token) {
-                return Err(Error::new(EINTR));
+                Ok(bytes_read)
             }
         }
     }
@@ -277,78 +517,1050 @@ impl KernelScheme for PipeScheme {
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
+        let nonblock = fcntl_flags & O_NONBLOCK as u32 != 0;
 
-        if !is_write_not_read {
-            return Err(Error::new(EBADF));
+        match handle(id, token)?.1 {
+            Handle::Reader(_) | Handle::Fifo(_) => Err(Error::new(EBADF)),
+            Handle::Writer(pipe) => pipe.write(user_buf, nonblock, &mut Vec::new(), token),
+            Handle::Endpoint(channel, side) => {
+                let pending_fds = &channel.endpoints[side].pending_fds;
+                let mut fds = core::mem::take(&mut *pending_fds.lock());
+
+                let result = channel.endpoints[1 - side]
+                    .inbound
+                    .write(user_buf, nonblock, &mut fds, token);
+
+                if !fds.is_empty() {
+                    // Nothing was sent, so keep the descriptors for the next message, in order.
+                    let mut pending = pending_fds.lock();
+                    fds.append(&mut pending);
+                    *pending = fds;
+                }
+
+                result
+            }
         }
-        let pipe = Arc::clone(
-            PIPES
-                .read(token.token())
-                .get(&key)
-                .ok_or(Error::new(EBADF))?,
-        );
+    }
+    fn kcall(
+        &self,
+        id: usize,
+        _payload: UserSliceRw,
+        _flags: syscall::CallFlags,
+        metadata: &[u64],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let &[verb_and_flags, other_fd, len] = metadata else {
+            return Err(Error::new(EINVAL));
+        };
+        let verb = PipeVerb::try_from_raw(verb_and_flags as u8).ok_or(Error::new(EINVAL))?;
+        let flags =
+            SpliceFlags::from_bits((verb_and_flags >> 32) as u32).ok_or(Error::new(EINVAL))?;
+        let len = usize::try_from(len).unwrap_or(usize::MAX);
 
-        loop {
-            let mut vec = pipe.queue.lock();
+        let (is_writer_not_reader, pipe) = match handle(id, token)?.1 {
+            Handle::Reader(pipe) => (false, pipe),
+            Handle::Writer(pipe) => (true, pipe),
+            Handle::Endpoint(..) | Handle::Fifo(_) => return Err(Error::new(EINVAL)),
+        };
+        // Splicing would not preserve message boundaries.
+        if pipe.packet_mode {
+            return Err(Error::new(EINVAL));
+        }
 
-            if !pipe.reader_is_alive.load(Ordering::Relaxed) {
-                return Err(Error::new(EPIPE));
+        let other = context::current()
+            .read(token.token())
+            .get_file(FileHandle::from(other_fd as usize))
+            .ok_or(Error::new(EBADF))?;
+        let other_desc = *other.description.read();
+
+        if other_desc.scheme == GlobalSchemes::Pipe.scheme_id() {
+            let other_pipe = match handle(other_desc.number, token)?.1 {
+                // Data can only flow from a read end to a write end.
+                Handle::Reader(other_pipe) if is_writer_not_reader => other_pipe,
+                Handle::Writer(other_pipe) if !is_writer_not_reader => other_pipe,
+                _ => return Err(Error::new(EINVAL)),
+            };
+            if other_pipe.packet_mode {
+                return Err(Error::new(EINVAL));
             }
 
-            let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
-            let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
-            let src_buf = user_buf
-                .limit(bytes_to_write)
-                .expect("bytes_to_write <= user_buf.len()");
+            let (src, dst) = if is_writer_not_reader {
+                (other_pipe, pipe)
+            } else {
+                (pipe, other_pipe)
+            };
+            let share = match verb {
+                PipeVerb::Splice => false,
+                PipeVerb::Tee if !is_writer_not_reader => true,
+                PipeVerb::Tee => return Err(Error::new(EINVAL)),
+            };
 
-            const TMPBUF_SIZE: usize = 512;
-            let mut tmp_buf = [0_u8; TMPBUF_SIZE];
+            return splice_pipes(&src, &dst, len, flags, share, token);
+        }
 
-            let mut bytes_written = 0;
+        let PipeVerb::Splice = verb else {
+            return Err(Error::new(EINVAL));
+        };
 
-            // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
-            for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
-                let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
-                    Ok(c) => c,
-                    Err(_) if idx > 0 => break,
-                    Err(error) => return Err(error),
-                };
-                vec.extend(&tmp_buf[..chunk_byte_count]);
-                bytes_written += chunk_byte_count;
+        // Only user schemes can borrow pages directly.
+        let scheme = schemes(token.token())
+            .get(other_desc.scheme)
+            .ok_or(Error::new(ENODEV))?
+            .clone();
+        let KernelSchemes::User(user_scheme) = scheme else {
+            return Err(Error::new(EINVAL));
+        };
+        let inner = user_scheme.inner.upgrade().ok_or(Error::new(ENODEV))?;
+
+        if is_writer_not_reader {
+            splice_from_scheme(&pipe, &inner, &other.description, len, flags, token)
+        } else {
+            splice_to_scheme(&pipe, &inner, &other.description, len, flags, token)
+        }
+    }
+    fn kfdwrite(
+        &self,
+        id: usize,
+        descs: Vec<Arc<spin::RwLock<FileDescription>>>,
+        _flags: syscall::CallFlags,
+        _args: u64,
+        _metadata: &[u64],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let Handle::Endpoint(channel, side) = handle(id, token)?.1 else {
+            close_descriptions(descs, token);
+            return Err(Error::new(EOPNOTSUPP));
+        };
+        let count = descs.len();
+
+        // TODO: Like Unix sockets, a channel that is sent through itself will never be freed.
+        channel.endpoints[side].pending_fds.lock().extend(descs);
+
+        Ok(count)
+    }
+    fn kfdread(
+        &self,
+        id: usize,
+        payload: UserSliceRw,
+        flags: syscall::CallFlags,
+        _metadata: &[u64],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let Handle::Endpoint(channel, side) = handle(id, token)?.1 else {
+            return Err(Error::new(EOPNOTSUPP));
+        };
+        if payload.len() % size_of::<usize>() != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let descriptions: Vec<_> = {
+            let mut received = channel.endpoints[side].received_fds.lock();
+            let count = core::cmp::min(received.len(), payload.len() / size_of::<usize>());
+            received.drain(..count).collect()
+        };
+        let payload = payload
+            .limit(descriptions.len() * size_of::<usize>())
+            .expect("at most payload.len() bytes");
+
+        if flags.contains(syscall::CallFlags::FD_UPPER) {
+            UserInner::bulk_insert_fds(descriptions, payload, token)
+        } else {
+            UserInner::bulk_add_fds(descriptions, payload, token)
+        }
+    }
+    fn kfstat(&self, id: usize, buf: UserSliceWo, _token: &mut CleanLockToken) -> Result<()> {
+        let (_, key) = from_raw_id(id);
+        let mode = if key & CHANNEL_BIT != 0 {
+            MODE_SOCK
+        } else {
+            MODE_FIFO
+        };
+
+        buf.copy_exactly(&Stat {
+            st_mode: mode | 0o666,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Move (or, if `share` is set, duplicate) up to `len` bytes from the read end of `src` to the
+/// write end of `dst`, by passing page references.
+fn splice_pipes(
+    src: &Pipe,
+    dst: &Pipe,
+    len: usize,
+    flags: SpliceFlags,
+    share: bool,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    if core::ptr::eq(src, dst) {
+        return Err(Error::new(EINVAL));
+    }
+
+    loop {
+        let (mut src_queue, mut dst_queue) = lock_both(src, dst);
+
+        if !dst.reader_is_alive.load(Ordering::Relaxed) {
+            return Err(Error::new(EPIPE));
+        }
+
+        let room = dst
+            .capacity
+            .load(Ordering::Relaxed)
+            .saturating_sub(dst_queue.len());
+        let max = core::cmp::min(len, room);
+        let transferred = if share {
+            src_queue.share_to(&mut dst_queue, max)
+        } else {
+            src_queue.move_to(&mut dst_queue, max)
+        };
+
+        if transferred > 0 {
+            drop((src_queue, dst_queue));
+
+            if !share {
+                src.notify_writable(token);
             }
+            dst.notify_readable(token);
 
-            if bytes_written > 0 {
-                event::trigger(GlobalSchemes::Pipe.scheme_id(), key, EVENT_READ);
-                pipe.read_condition.notify(token);
+            return Ok(transferred);
+        } else if len == 0 {
+            return Ok(0);
+        }
 
-                return Ok(bytes_written);
-            } else if user_buf.is_empty() {
+        if src_queue.is_empty() {
+            if !src.writer_is_alive.load(Ordering::SeqCst) {
                 return Ok(0);
+            } else if flags.contains(SpliceFlags::NONBLOCK) {
+                return Err(Error::new(EAGAIN));
+            } else if !src
+                .read_condition
+                .wait((src_queue, dst_queue), "Pipe::splice", token)
+            {
+                return Err(Error::new(EINTR));
+            }
+        } else if flags.contains(SpliceFlags::NONBLOCK) {
+            return Err(Error::new(EAGAIN));
+        } else if !dst
+            .write_condition
+            .wait((src_queue, dst_queue), "Pipe::splice", token)
+        {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Write up to `len` bytes from the read end of `pipe` to a user scheme file, lending whole pages
+/// to the scheme instead of copying them.
+fn splice_to_scheme(
+    pipe: &Pipe,
+    inner: &UserInner,
+    description: &Arc<spin::RwLock<FileDescription>>,
+    len: usize,
+    flags: SpliceFlags,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let desc = *description.read();
+    let positioned = desc.internal_flags.contains(InternalFlags::POSITIONED);
+    let mut offset = if positioned { desc.offset } else { u64::MAX };
+
+    let mut bytes_written = 0;
+
+    while bytes_written < len {
+        let chunk = loop {
+            let mut queue = pipe.queue.lock();
+
+            if let Some(chunk) = queue.take_front(len - bytes_written) {
+                break Some(chunk);
+            }
+
+            // Only block if nothing has been transferred yet.
+            if bytes_written > 0 || !pipe.writer_is_alive.load(Ordering::SeqCst) {
+                break None;
+            } else if flags.contains(SpliceFlags::NONBLOCK) {
+                return Err(Error::new(EAGAIN));
+            } else if !pipe.read_condition.wait(queue, "Pipe::splice", token) {
+                return Err(Error::new(EINTR));
             }
+        };
+        let Some(mut chunk) = chunk else {
+            break;
+        };
+
+        // The chunk is taken off the queue while the scheme writes it, so that other readers are not
+        // held up by a slow scheme, and whatever it did not write is put back at the front.
+        let chunk_len = chunk.len();
+        let result = inner
+            .write_frame(
+                desc.number,
+                chunk.page.frame(),
+                chunk.start,
+                chunk.len(),
+                offset,
+                desc.flags,
+                token,
+            )
+            .map(|count| count.min(chunk_len));
+        let written = result.as_ref().map_or(0, |&count| count);
+        if written < chunk_len {
+            chunk.start += written;
+            pipe.queue.lock().push_front(chunk);
+            pipe.notify_readable(token);
+        }
+        if written > 0 {
+            pipe.notify_writable(token);
+        }
+
+        let count = match result {
+            Ok(count) => count,
+            Err(_) if bytes_written > 0 => break,
+            Err(error) => return Err(error),
+        };
 
-            if fcntl_flags & O_NONBLOCK as u32 != 0 {
+        bytes_written += count;
+        if positioned {
+            offset = offset.saturating_add(count as u64);
+        }
+
+        if count < chunk_len {
+            break;
+        }
+    }
+
+    if positioned {
+        description.write().offset = offset;
+    }
+
+    Ok(bytes_written)
+}
+
+/// Read up to `len` bytes from a user scheme file into the write end of `pipe`, letting the scheme
+/// fill newly allocated pipe pages directly.
+fn splice_from_scheme(
+    pipe: &Pipe,
+    inner: &UserInner,
+    description: &Arc<spin::RwLock<FileDescription>>,
+    len: usize,
+    flags: SpliceFlags,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let desc = *description.read();
+    let positioned = desc.internal_flags.contains(InternalFlags::POSITIONED);
+    let mut offset = if positioned { desc.offset } else { u64::MAX };
+
+    let mut bytes_read = 0;
+
+    while bytes_read < len {
+        // Wait for room first, so that whatever the scheme returns always fits.
+        let room = loop {
+            let queue = pipe.queue.lock();
+
+            if !pipe.reader_is_alive.load(Ordering::Relaxed) {
+                if bytes_read > 0 {
+                    break 0;
+                }
+                return Err(Error::new(EPIPE));
+            }
+
+            let room = pipe
+                .capacity
+                .load(Ordering::Relaxed)
+                .saturating_sub(queue.len());
+
+            if room > 0 || bytes_read > 0 {
+                break room;
+            } else if flags.contains(SpliceFlags::NONBLOCK) {
                 return Err(Error::new(EAGAIN));
-            } else if !pipe.write_condition.wait(vec, "PipeWrite::write", token) {
+            } else if !pipe.write_condition.wait(queue, "Pipe::splice", token) {
                 return Err(Error::new(EINTR));
             }
+        };
+        let count = room.min(len - bytes_read).min(PAGE_SIZE);
+        if count == 0 {
+            break;
+        }
+
+        let page = PipePage::new()?;
+        let count =
+            match inner.read_frame(desc.number, page.frame(), count, offset, desc.flags, token) {
+                Ok(read) => read.min(count),
+                Err(_) if bytes_read > 0 => break,
+                Err(error) => return Err(error),
+            };
+        if count == 0 {
+            break;
+        }
+
+        pipe.queue.lock().push_back(PipeChunk {
+            page,
+            start: 0,
+            end: count,
+        });
+        pipe.notify_readable(token);
+
+        bytes_read += count;
+        if positioned {
+            offset = offset.saturating_add(count as u64);
         }
     }
-    fn kfstat(&self, _id: usize, buf: UserSliceWo, _token: &mut CleanLockToken) -> Result<()> {
-        buf.copy_exactly(&Stat {
-            st_mode: MODE_FIFO | 0o666,
-            ..Default::default()
-        })?;
 
-        Ok(())
+    if positioned {
+        description.write().offset = offset;
+    }
+
+    Ok(bytes_read)
+}
+
+/// Lock both queues in a consistent order, so that concurrent splices in opposite directions
+/// cannot deadlock. The guards are returned in argument order.
+fn lock_both<'a>(
+    a: &'a Pipe,
+    b: &'a Pipe,
+) -> (MutexGuard<'a, PipeQueue>, MutexGuard<'a, PipeQueue>) {
+    if (a as *const Pipe) < (b as *const Pipe) {
+        let a_guard = a.queue.lock();
+        (a_guard, b.queue.lock())
+    } else {
+        let b_guard = b.queue.lock();
+        (a.queue.lock(), b_guard)
+    }
+}
+
+/// A page of pipe data. Pages are reference counted, so that `tee` can share them between pipes,
+/// and are mapped directly into user schemes when splicing.
+struct PipePage {
+    frame: RaiiFrame,
+}
+impl PipePage {
+    fn new() -> Result<Arc<Self>> {
+        let mut page = Self {
+            frame: RaiiFrame::allocate()?,
+        };
+        // The whole page may later be lent to a scheme, so don't leak old frame contents.
+        page.buf_mut().fill(0_u8);
+        Ok(Arc::new(page))
+    }
+    fn frame(&self) -> Frame {
+        self.frame.get()
+    }
+    fn buf(&self) -> &[u8; PAGE_SIZE] {
+        unsafe { &*(RmmA::phys_to_virt(self.frame.get().base()).data() as *const [u8; PAGE_SIZE]) }
+    }
+    fn buf_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
+        unsafe {
+            &mut *(RmmA::phys_to_virt(self.frame.get().base()).data() as *mut [u8; PAGE_SIZE])
+        }
+    }
+}
+
+/// The bytes `start..end` of a pipe page.
+struct PipeChunk {
+    page: Arc<PipePage>,
+    start: usize,
+    end: usize,
+}
+impl PipeChunk {
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+    fn bytes(&self) -> &[u8] {
+        &self.page.buf()[self.start..self.end]
+    }
+    /// Split off the first `count` bytes, sharing the page.
+    fn split_front(&mut self, count: usize) -> PipeChunk {
+        let front = PipeChunk {
+            page: Arc::clone(&self.page),
+            start: self.start,
+            end: self.start + count,
+        };
+        self.start += count;
+        front
+    }
+}
+
+/// A message queued in a packet-mode pipe, whose bytes are stored in the chunks.
+struct PipePacket {
+    len: usize,
+    /// Descriptors sent along with the message, if it was written to a channel.
+    fds: Vec<Arc<spin::RwLock<FileDescription>>>,
+}
+
+struct PipeQueue {
+    chunks: VecDeque<PipeChunk>,
+    len: usize,
+    /// Message boundaries, only used in packet mode.
+    packets: VecDeque<PipePacket>,
+}
+impl PipeQueue {
+    fn new() -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            len: 0,
+            packets: VecDeque::new(),
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0 && self.packets.is_empty()
+    }
+
+    /// Copy bytes from the front to `dst`, without consuming them.
+    fn copy_to_user(&self, mut dst: UserSliceWo) -> Result<usize> {
+        let mut bytes_copied = 0;
+
+        for chunk in &self.chunks {
+            if dst.is_empty() {
+                break;
+            }
+            let count = core::cmp::min(chunk.len(), dst.len());
+            let (part, rest) = dst.split_at(count).expect("count <= dst.len()");
+
+            match part.copy_from_slice(&chunk.bytes()[..count]) {
+                Ok(()) => (),
+                Err(_) if bytes_copied > 0 => break,
+                Err(error) => return Err(error),
+            }
+
+            bytes_copied += count;
+            dst = rest;
+        }
+
+        Ok(bytes_copied)
+    }
+    fn discard_front(&mut self, mut count: usize) {
+        while count > 0 {
+            let chunk = self.chunks.front_mut().expect("count <= self.len");
+            let n = core::cmp::min(chunk.len(), count);
+
+            chunk.start += n;
+            if chunk.start == chunk.end {
+                self.chunks.pop_front();
+            }
+            self.len -= n;
+            count -= n;
+        }
+    }
+    fn truncate_back(&mut self, mut count: usize) {
+        while count > 0 {
+            let chunk = self.chunks.back_mut().expect("count <= self.len");
+            let n = core::cmp::min(chunk.len(), count);
+
+            chunk.end -= n;
+            if chunk.start == chunk.end {
+                self.chunks.pop_back();
+            }
+            self.len -= n;
+            count -= n;
+        }
+    }
+
+    fn read_to_user(&mut self, dst: UserSliceWo) -> Result<usize> {
+        let bytes_read = self.copy_to_user(dst)?;
+        self.discard_front(bytes_read);
+        Ok(bytes_read)
+    }
+
+    /// Read the next message, if any. Like `SOCK_SEQPACKET`, the part of the message that does not
+    /// fit in `dst` is discarded.
+    fn read_packet_to_user(
+        &mut self,
+        dst: UserSliceWo,
+    ) -> Result<Option<(usize, Vec<Arc<spin::RwLock<FileDescription>>>)>> {
+        let Some(packet) = self.packets.front() else {
+            return Ok(None);
+        };
+        let packet_len = packet.len;
+        let count = core::cmp::min(packet_len, dst.len());
+
+        if self.copy_to_user(dst.limit(count).expect("count <= dst.len()"))? < count {
+            return Err(Error::new(EFAULT));
+        }
+        self.discard_front(packet_len);
+
+        let packet = self.packets.pop_front().expect("front exists");
+        Ok(Some((count, packet.fds)))
+    }
+
+    /// Append bytes from `src`. If `atomic` is set, either all bytes are written or none are,
+    /// otherwise a partial count is returned on failure if some bytes were written.
+    fn write_from_user(&mut self, mut src: UserSliceRo, atomic: bool) -> Result<usize> {
+        let mut bytes_written = 0;
+
+        while !src.is_empty() {
+            // Append to the last page unless it is full, or shared with another pipe or a scheme.
+            let can_append = matches!(
+                self.chunks.back(),
+                Some(chunk) if chunk.end < PAGE_SIZE && Arc::strong_count(&chunk.page) == 1
+            );
+            if !can_append {
+                let page = match PipePage::new() {
+                    Ok(page) => page,
+                    Err(_) if bytes_written > 0 && !atomic => break,
+                    Err(error) => {
+                        self.truncate_back(bytes_written);
+                        return Err(error);
+                    }
+                };
+                self.chunks.push_back(PipeChunk {
+                    page,
+                    start: 0,
+                    end: 0,
+                });
+            }
+            let chunk = self.chunks.back_mut().expect("a tail chunk was ensured");
+
+            let count = core::cmp::min(PAGE_SIZE - chunk.end, src.len());
+            let (part, rest) = src.split_at(count).expect("count <= src.len()");
+            let page = Arc::get_mut(&mut chunk.page).expect("tail page is not shared");
+
+            if let Err(error) = part.copy_to_slice(&mut page.buf_mut()[chunk.end..][..count]) {
+                if chunk.start == chunk.end {
+                    self.chunks.pop_back();
+                }
+                if bytes_written > 0 && !atomic {
+                    break;
+                }
+                self.truncate_back(bytes_written);
+                return Err(error);
+            }
+
+            chunk.end += count;
+            self.len += count;
+            bytes_written += count;
+            src = rest;
+        }
+
+        Ok(bytes_written)
+    }
+
+    /// Append `src` as a single message, taking the descriptors in `fds` only if successful.
+    fn write_packet_from_user(
+        &mut self,
+        src: UserSliceRo,
+        fds: &mut Vec<Arc<spin::RwLock<FileDescription>>>,
+    ) -> Result<usize> {
+        let bytes_written = self.write_from_user(src, true)?;
+
+        self.packets.push_back(PipePacket {
+            len: bytes_written,
+            fds: core::mem::take(fds),
+        });
+
+        Ok(bytes_written)
+    }
+    /// Remove the descriptors attached to all queued messages.
+    fn take_fds(&mut self) -> Vec<Arc<spin::RwLock<FileDescription>>> {
+        self.packets
+            .iter_mut()
+            .flat_map(|packet| core::mem::take(&mut packet.fds))
+            .collect()
+    }
+
+    /// Remove at most `max` bytes from the front, as a single chunk.
+    fn take_front(&mut self, max: usize) -> Option<PipeChunk> {
+        let front = self.chunks.front_mut()?;
+        let chunk = if front.len() > max {
+            front.split_front(max)
+        } else {
+            self.chunks.pop_front().expect("front exists")
+        };
+        self.len -= chunk.len();
+        (chunk.len() > 0).then_some(chunk)
+    }
+    /// Put a chunk taken by `take_front` back at the front.
+    fn push_front(&mut self, chunk: PipeChunk) {
+        self.len += chunk.len();
+        self.chunks.push_front(chunk);
+    }
+    fn push_back(&mut self, chunk: PipeChunk) {
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+
+    /// Move up to `max` bytes to the back of `dst`.
+    fn move_to(&mut self, dst: &mut PipeQueue, max: usize) -> usize {
+        let mut moved = 0;
+        while moved < max {
+            let Some(chunk) = self.take_front(max - moved) else {
+                break;
+            };
+            moved += chunk.len();
+            dst.push_back(chunk);
+        }
+        moved
+    }
+    /// Append references to up to `max` bytes to `dst`, without consuming them.
+    fn share_to(&self, dst: &mut PipeQueue, max: usize) -> usize {
+        let mut shared = 0;
+        for chunk in &self.chunks {
+            if shared >= max {
+                break;
+            }
+            let count = core::cmp::min(chunk.len(), max - shared);
+            dst.push_back(PipeChunk {
+                page: Arc::clone(&chunk.page),
+                start: chunk.start,
+                end: chunk.start + count,
+            });
+            shared += count;
+        }
+        shared
     }
 }
 
 pub struct Pipe {
     read_condition: WaitCondition, // signals whether there are available bytes to read
     write_condition: WaitCondition, // signals whether there is room for additional bytes
-    queue: Mutex<VecDeque<u8>>,
+    queue: Mutex<PipeQueue>,
+    capacity: AtomicUsize, // maximum number of queued bytes, set using F_SETPIPE_SZ
+    packet_mode: bool,     // whether each write is queued as a separate message
+    read_handle: usize,    // id that is notified with EVENT_READ
+    write_handle: usize,   // id that is notified with EVENT_WRITE
     reader_is_alive: AtomicBool, // starts set, unset when reader closes
     writer_is_alive: AtomicBool, // starts set, unset when writer closes
     has_run_dup: AtomicBool,
+    fifo: Option<Arc<Fifo>>, // set if the ends were opened through a FIFO
+}
+impl Pipe {
+    fn new(read_handle: usize, write_handle: usize, packet_mode: bool) -> Self {
+        Self {
+            queue: Mutex::new(PipeQueue::new()),
+            capacity: AtomicUsize::new(DEFAULT_PIPE_SIZE),
+            packet_mode,
+            read_handle,
+            write_handle,
+            read_condition: WaitCondition::new(),
+            write_condition: WaitCondition::new(),
+            writer_is_alive: AtomicBool::new(true),
+            reader_is_alive: AtomicBool::new(true),
+            has_run_dup: AtomicBool::new(false),
+            fifo: None,
+        }
+    }
+
+    fn notify_readable(&self, token: &mut CleanLockToken) {
+        event::trigger(
+            GlobalSchemes::Pipe.scheme_id(),
+            self.read_handle,
+            EVENT_READ,
+        );
+        self.read_condition.notify(token);
+    }
+    fn notify_writable(&self, token: &mut CleanLockToken) {
+        event::trigger(
+            GlobalSchemes::Pipe.scheme_id(),
+            self.write_handle,
+            EVENT_WRITE,
+        );
+        self.write_condition.notify(token);
+    }
+
+    fn is_readable(&self) -> bool {
+        let queue = self.queue.lock();
+        !queue.is_empty() || !self.writer_is_alive.load(Ordering::Acquire)
+    }
+    fn is_writable(&self) -> bool {
+        let queue = self.queue.lock();
+        let has_room = queue.len() < self.capacity.load(Ordering::Relaxed)
+            && queue.packets.len() < MAX_PIPE_PACKETS;
+
+        has_room || !self.reader_is_alive.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the pipe can be removed, i.e. whether the writer has closed as well.
+    fn close_reader(&self, token: &mut CleanLockToken) -> bool {
+        self.reader_is_alive.store(false, Ordering::SeqCst);
+        self.notify_writable(token);
+
+        !self.writer_is_alive.load(Ordering::SeqCst)
+    }
+    /// Returns whether the pipe can be removed, i.e. whether the reader has closed as well.
+    fn close_writer(&self, token: &mut CleanLockToken) -> bool {
+        self.writer_is_alive.store(false, Ordering::SeqCst);
+        self.notify_readable(token);
+
+        !self.reader_is_alive.load(Ordering::SeqCst)
+    }
+
+    /// Read from the pipe, also returning any descriptors sent along with the message.
+    fn read(
+        &self,
+        user_buf: UserSliceWo,
+        nonblock: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<(usize, Vec<Arc<spin::RwLock<FileDescription>>>)> {
+        loop {
+            let mut queue = self.queue.lock();
+
+            // In packet mode, reading consumes a whole message even if the buffer is empty.
+            let received = if self.packet_mode {
+                queue.read_packet_to_user(user_buf)?
+            } else {
+                let bytes_read = queue.read_to_user(user_buf)?;
+                (bytes_read > 0 || user_buf.is_empty()).then(|| (bytes_read, Vec::new()))
+            };
+
+            if let Some((bytes_read, fds)) = received {
+                drop(queue);
+                if self.packet_mode || bytes_read > 0 {
+                    self.notify_writable(token);
+                }
+
+                return Ok((bytes_read, fds));
+            }
+
+            if !self.writer_is_alive.load(Ordering::SeqCst) {
+                return Ok((0, Vec::new()));
+            } else if nonblock {
+                return Err(Error::new(EAGAIN));
+            } else if !self.read_condition.wait(queue, "PipeRead::read", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Write to the pipe. In packet mode, the write is queued as one message, and the descriptors
+    /// in `fds` are taken and sent along with it.
+    fn write(
+        &self,
+        user_buf: UserSliceRo,
+        nonblock: bool,
+        fds: &mut Vec<Arc<spin::RwLock<FileDescription>>>,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        if self.packet_mode && user_buf.len() > self.capacity.load(Ordering::Relaxed) {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        loop {
+            let mut queue = self.queue.lock();
+
+            if !self.reader_is_alive.load(Ordering::Relaxed) {
+                return Err(Error::new(EPIPE));
+            }
+
+            let bytes_left = self
+                .capacity
+                .load(Ordering::Relaxed)
+                .saturating_sub(queue.len());
+
+            let written = if self.packet_mode {
+                // Empty messages are only sent when they carry descriptors.
+                if user_buf.is_empty() && fds.is_empty() {
+                    return Ok(0);
+                }
+                if user_buf.len() <= bytes_left && queue.packets.len() < MAX_PIPE_PACKETS {
+                    Some(queue.write_packet_from_user(user_buf, fds)?)
+                } else {
+                    None
+                }
+            } else {
+                let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
+                let src_buf = user_buf
+                    .limit(bytes_to_write)
+                    .expect("bytes_to_write <= user_buf.len()");
+
+                let bytes_written = queue.write_from_user(src_buf, false)?;
+                (bytes_written > 0 || user_buf.is_empty()).then_some(bytes_written)
+            };
+
+            if let Some(bytes_written) = written {
+                drop(queue);
+                if self.packet_mode || bytes_written > 0 {
+                    self.notify_readable(token);
+                }
+
+                return Ok(bytes_written);
+            }
+
+            if nonblock {
+                return Err(Error::new(EAGAIN));
+            } else if !self.write_condition.wait(queue, "PipeWrite::write", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+/// A bidirectional, message-based connection between two endpoints, similar to a pair of
+/// `SOCK_SEQPACKET` Unix sockets. File descriptors can be sent along with messages.
+pub struct Channel {
+    endpoints: [Endpoint; 2],
+    has_run_dup: AtomicBool,
+}
+impl Channel {
+    /// Returns whether the channel can be removed, i.e. whether the other endpoint is closed or
+    /// was never opened.
+    fn close(&self, side: usize, token: &mut CleanLockToken) -> bool {
+        let endpoint = &self.endpoints[side];
+
+        endpoint.inbound.close_reader(token);
+        self.endpoints[1 - side].inbound.close_writer(token);
+
+        // Nobody can receive the descriptors in transit to this endpoint anymore.
+        let mut in_transit = core::mem::take(&mut *endpoint.pending_fds.lock());
+        in_transit.append(&mut endpoint.received_fds.lock());
+        in_transit.append(&mut endpoint.inbound.queue.lock().take_fds());
+        close_descriptions(in_transit, token);
+
+        let peer = &self.endpoints[1 - side];
+        !self.has_run_dup.load(Ordering::SeqCst)
+            || !peer.inbound.reader_is_alive.load(Ordering::SeqCst)
+    }
+}
+
+struct Endpoint {
+    /// Messages sent by the other endpoint.
+    inbound: Pipe,
+    /// Descriptors passed using `SYS_SENDFD`, which are attached to the next message written.
+    pending_fds: Mutex<Vec<Arc<spin::RwLock<FileDescription>>>>,
+    /// Descriptors received with messages that have been read, until fetched with `SYS_CALL`.
+    received_fds: Mutex<Vec<Arc<spin::RwLock<FileDescription>>>>,
+}
+impl Endpoint {
+    fn new(id: usize, peer_id: usize) -> Self {
+        Self {
+            inbound: Pipe::new(id, peer_id, true),
+            pending_fds: Mutex::new(Vec::new()),
+            received_fds: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// A named pipe. Filesystem schemes implement FIFO nodes by opening `pipe:fifo/<name>` (with
+/// `O_CREAT` to create it), and responding to opens of the node with that FIFO node handle. The
+/// kernel then opens a read or write end on behalf of the caller, see [`resolve_fifo`].
+///
+/// All ends opened while the FIFO is in use share one pipe. Once every end is closed, the data is
+/// discarded and the next open starts a new pipe.
+pub struct Fifo {
+    key: usize,
+    name: Box<str>,
+    state: Mutex<FifoState>,
+    open_condition: WaitCondition, // signals whether a reader or writer has opened
+}
+struct FifoState {
+    /// The current pipe, along with its key.
+    pipe: Option<(usize, Arc<Pipe>)>,
+    /// Number of node handles.
+    nodes: usize,
+    readers: usize,
+    writers: usize,
+}
+impl FifoState {
+    fn is_unused(&self) -> bool {
+        self.nodes == 0 && self.readers == 0 && self.writers == 0
+    }
+}
+impl Fifo {
+    /// Open a read or write end. Like POSIX, this blocks until the other end is opened as well,
+    /// unless `nonblock` is set, in which case opening a write end without readers fails.
+    fn open(
+        self: &Arc<Self>,
+        write: bool,
+        nonblock: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let key = {
+            let mut state = self.state.lock();
+
+            if write && nonblock && state.readers == 0 {
+                return Err(Error::new(ENXIO));
+            }
+
+            let (key, pipe) = match state.pipe.clone() {
+                Some(pipe) => pipe,
+                None => {
+                    let key = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                    let pipe = Arc::new(Pipe {
+                        fifo: Some(Arc::clone(self)),
+                        ..Pipe::new(key, key | WRITE_NOT_READ_BIT, false)
+                    });
+                    // Either side is only alive while opened, and additional ends can only be
+                    // opened through the FIFO.
+                    pipe.reader_is_alive.store(false, Ordering::SeqCst);
+                    pipe.writer_is_alive.store(false, Ordering::SeqCst);
+                    pipe.has_run_dup.store(true, Ordering::SeqCst);
+
+                    PIPES.write(token.token()).insert(key, Arc::clone(&pipe));
+                    state.pipe = Some((key, Arc::clone(&pipe)));
+                    (key, pipe)
+                }
+            };
+
+            if write {
+                state.writers += 1;
+                pipe.writer_is_alive.store(true, Ordering::SeqCst);
+            } else {
+                state.readers += 1;
+                pipe.reader_is_alive.store(true, Ordering::SeqCst);
+            }
+
+            key
+        };
+        self.open_condition.notify(token);
+
+        loop {
+            let state = self.state.lock();
+            let peers = if write { state.readers } else { state.writers };
+
+            if peers > 0 || nonblock {
+                break;
+            } else if !self.open_condition.wait(state, "Fifo::open", token) {
+                self.release(write, token);
+                return Err(Error::new(EINTR));
+            }
+        }
+
+        Ok(if write { key | WRITE_NOT_READ_BIT } else { key })
+    }
+
+    /// Close a read or write end. When the last reader or writer closes, the pipe behaves as if
+    /// the end of an anonymous pipe was closed.
+    fn release(&self, write: bool, token: &mut CleanLockToken) {
+        let (pipe, removed_key, unused) = {
+            let mut state = self.state.lock();
+            let (key, pipe) = state
+                .pipe
+                .clone()
+                .expect("FIFO has a pipe while ends are open");
+
+            let last = if write {
+                state.writers -= 1;
+                state.writers == 0
+            } else {
+                state.readers -= 1;
+                state.readers == 0
+            };
+            // Updated while locked, since a new end may be opened right afterwards.
+            if last && write {
+                pipe.writer_is_alive.store(false, Ordering::SeqCst);
+            } else if last {
+                pipe.reader_is_alive.store(false, Ordering::SeqCst);
+            }
+
+            let removed_key = (state.readers == 0 && state.writers == 0).then(|| {
+                state.pipe = None;
+                key
+            });
+
+            (last.then_some(pipe), removed_key, state.is_unused())
+        };
+
+        match pipe {
+            Some(pipe) if write => pipe.notify_readable(token),
+            Some(pipe) => pipe.notify_writable(token),
+            None => (),
+        }
+        if let Some(key) = removed_key {
+            let _ = PIPES.write(token.token()).remove(&key);
+        }
+        if unused {
+            remove_fifo_if_unused(self.key, token);
+        }
+    }
 }
@@ -2,18 +2,19 @@ use crate::{
     arch::paging::{Page, VirtualAddress},
     context::{
         self,
-        context::{HardBlockedReason, SignalState},
+        context::{HardBlockedReason, SignalState, DEFAULT_IO_PRIORITY, IO_PRIORITY_LEVELS},
         file::InternalFlags,
         memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
         Context, ContextLock, Status,
     },
     memory::PAGE_SIZE,
     ptrace,
-    scheme::{self, FileHandle, KernelScheme},
+    scheme::{self, stats::SchemeOp, FileHandle, KernelScheme},
     sync::{CleanLockToken, RwLock, L1},
     syscall::{
         data::{GrantDesc, Map, SetSighandlerData, Stat},
         error::*,
+        filter::SyscallFilter,
         flag::*,
         usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
         EnvRegisters, FloatRegisters, IntRegisters,
@@ -141,6 +142,8 @@ enum ContextHandle {
     // directory.
     OpenViaDup,
     SchedAffinity,
+    IoPriority,
+    SyscallFilter,
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
 }
@@ -231,6 +234,8 @@ impl ProcScheme {
                 false,
             ),
             "sched-affinity" => (ContextHandle::SchedAffinity, true),
+            "io-priority" => (ContextHandle::IoPriority, false),
+            "syscall-filter" => (ContextHandle::SyscallFilter, false),
             "status" => (ContextHandle::Status { privileged: false }, false),
             _ if path.starts_with("auth-") => {
                 let nonprefix = &path["auth-".len()..];
@@ -288,6 +293,7 @@ impl ProcScheme {
                         let id = NonZeroUsize::new(NEXT_ID.fetch_add(1, Ordering::Relaxed))
                             .ok_or(Error::new(EMFILE))?;
                         let context = context::spawn(true, Some(id), ret, token)?;
+                        crate::syscall::filter::inherit(&context, token);
                         HANDLES.write(token.token()).insert(
                             id.get(),
                             Handle {
@@ -852,6 +858,7 @@ impl ContextHandle {
 
                         let (scheme, number) = extract_scheme_number(fd, token)?;
 
+                        scheme.count(SchemeOp::Fmap);
                         scheme.kfmap(
                             number,
                             &addrspace,
@@ -1078,6 +1085,34 @@ impl ContextHandle {
 
                 Ok(mem::size_of_val(&mask))
             }
+            Self::IoPriority => {
+                let val = buf.read_usize()?;
+                if val >= usize::from(IO_PRIORITY_LEVELS) {
+                    return Err(Error::new(EINVAL));
+                }
+                if val < usize::from(DEFAULT_IO_PRIORITY)
+                    && context::current().read(token.token()).euid != 0
+                {
+                    return Err(Error::new(EPERM));
+                }
+                context.write(token.token()).io_priority = val as u8;
+                Ok(mem::size_of::<usize>())
+            }
+            Self::SyscallFilter => {
+                if buf.len() > PAGE_SIZE {
+                    return Err(Error::new(E2BIG));
+                }
+                let mut text = vec![0_u8; buf.len()];
+                buf.copy_to_slice(&mut text)?;
+                let text = str::from_utf8(&text).map_err(|_| Error::new(EINVAL))?;
+                let filter = SyscallFilter::parse(text)?;
+
+                context
+                    .write(token.token())
+                    .syscall_filters
+                    .extend([Arc::new(filter)]);
+                Ok(text.len())
+            }
             ContextHandle::Status { privileged } => {
                 let mut args = buf.usizes();
 
@@ -1290,6 +1325,22 @@ impl ContextHandle {
 
                 buf.copy_exactly(crate::cpu_set::mask_as_bytes(&mask))?;
                 Ok(mem::size_of_val(&mask))
+            }
+            ContextHandle::IoPriority => {
+                buf.write_usize(context.read(token.token()).io_priority.into())?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::SyscallFilter => {
+                use core::fmt::Write;
+
+                let mut text = String::new();
+                for filter in context.read(token.token()).syscall_filters.iter() {
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    let _ = write!(text, "{}", filter);
+                }
+                read_from(buf, text.as_bytes(), offset)
             } // TODO: Replace write() with SYS_SENDFD?
             ContextHandle::Status { .. } => {
                 let status = {
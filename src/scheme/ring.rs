@@ -0,0 +1,302 @@
+//! Shared-memory submission and completion rings.
+//!
+//! A ring pair is mapped with `fmap` on a kernel handle, with offset 0 and size `RING_SIZE`:
+//!
+//! - The first page holds a `RingHeader`.
+//! - The submission queue starts at `SQ_OFFSET`, and the completion queue at `CQ_OFFSET`.
+//! - Producers write entries at the tail of a queue and then advance it, and consumers read
+//!   entries at the head and then advance it. Heads and tails are free-running counters, indexing
+//!   the queues modulo their sizes.
+//!
+//! The kernel keeps its own copies of the counters it advances, and never trusts the ones advanced
+//! by userspace.
+//!
+//! A v2 userspace scheme maps its rings from its root scheme handle. From then on, the kernel
+//! produces `Sqe`s instead of queueing them to be read from the handle, and the scheme can produce
+//! `Cqe`s instead of writing them to the handle, calling `fsync` on the handle, or reading from it,
+//! for the kernel to consume them. After producing submissions, the kernel triggers `EVENT_READ`
+//! on the handle. A scheme can also wait with a futex on `sq_tail` after setting `SQ_NEED_WAKEUP`
+//! in `sq_flags` and checking the tail again. Requests that do not fit in the submission queue,
+//! or are sent while earlier ones are still waiting to be read, are read from the handle as
+//! before, so the scheme must empty the submission queue before reading from the handle.
+//! Each worker handle of a scheme has its own rings, mapped from that handle. When a worker handle
+//! is closed, the requests left between `sq_head` and `sq_tail` are dispatched to other workers.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    mem::{offset_of, size_of},
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use spin::Mutex;
+use syscall::schemev2::{Cqe, Sqe};
+
+use crate::{
+    context::memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+    memory::{deallocate_frame, get_page_info, init_frame, Frame, PhysicalAddress, RefCount},
+    paging::{RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    sync::CleanLockToken,
+    syscall::{data::Map, error::*},
+};
+
+/// Offset of the submission queue in the mapping.
+pub const SQ_OFFSET: usize = PAGE_SIZE;
+/// Number of pages of the submission queue.
+const SQ_PAGES: usize = 2;
+/// Offset of the completion queue in the mapping.
+pub const CQ_OFFSET: usize = SQ_OFFSET + SQ_PAGES * PAGE_SIZE;
+/// Number of pages of the completion queue.
+const CQ_PAGES: usize = 1;
+
+pub const RING_PAGES: usize = 1 + SQ_PAGES + CQ_PAGES;
+pub const RING_SIZE: usize = RING_PAGES * PAGE_SIZE;
+
+/// Number of entries of the submission queue, if it holds `S`s.
+pub const fn sq_entries<S>() -> u32 {
+    const {
+        assert!(PAGE_SIZE % size_of::<S>() == 0);
+        assert!((SQ_PAGES * PAGE_SIZE / size_of::<S>()).is_power_of_two());
+    }
+    (SQ_PAGES * PAGE_SIZE / size_of::<S>()) as u32
+}
+
+/// Number of entries of the completion queue, if it holds `C`s.
+pub const fn cq_entries<C>() -> u32 {
+    const {
+        assert!(PAGE_SIZE % size_of::<C>() == 0);
+        assert!((CQ_PAGES * PAGE_SIZE / size_of::<C>()).is_power_of_two());
+    }
+    (CQ_PAGES * PAGE_SIZE / size_of::<C>()) as u32
+}
+
+/// Set in `sq_flags` by a consumer of the submission queue before waiting on `sq_tail` with a
+/// futex.
+pub const SQ_NEED_WAKEUP: u32 = 1 << 0;
+/// Set in `cq_flags` by a consumer of the completion queue before waiting on `cq_tail` with a
+/// futex.
+pub const CQ_NEED_WAKEUP: u32 = 1 << 0;
+
+#[repr(C)]
+pub struct RingHeader {
+    pub sq_head: AtomicU32,
+    pub sq_tail: AtomicU32,
+    pub sq_entries: AtomicU32,
+    pub sq_flags: AtomicU32,
+    pub cq_head: AtomicU32,
+    pub cq_tail: AtomicU32,
+    pub cq_entries: AtomicU32,
+    pub cq_flags: AtomicU32,
+}
+
+/// The pages of a ring pair, owned by the kernel and shared with the address spaces that map
+/// them.
+pub struct RingPages {
+    frames: [Frame; RING_PAGES],
+}
+
+impl RingPages {
+    pub fn new(sq_entries: u32, cq_entries: u32) -> Option<RingPages> {
+        let mut frames = [None; RING_PAGES];
+        for i in 0..RING_PAGES {
+            // Keep a reference owned by the kernel, so that the frames outlive the mappings.
+            let Ok(frame) = init_frame(RefCount::One) else {
+                for frame in frames.into_iter().flatten() {
+                    unsafe { deallocate_frame(frame) };
+                }
+                return None;
+            };
+            unsafe {
+                (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+            }
+            frames[i] = Some(frame);
+        }
+
+        let pages = RingPages {
+            frames: frames.map(|frame| frame.expect("all ring pages were allocated")),
+        };
+        let header = pages.header();
+        header.sq_entries.store(sq_entries, Ordering::Relaxed);
+        header.cq_entries.store(cq_entries, Ordering::Relaxed);
+        Some(pages)
+    }
+
+    pub fn header(&self) -> &RingHeader {
+        unsafe { &*(self.ptr(0) as *const RingHeader) }
+    }
+
+    /// Kernel pointer to a byte offset in the mapping.
+    fn ptr(&self, offset: usize) -> *mut u8 {
+        let frame = self.frames[offset / PAGE_SIZE];
+        (RmmA::phys_to_virt(frame.base()).data() + offset % PAGE_SIZE) as *mut u8
+    }
+
+    /// Write entry `index` of the submission queue.
+    pub fn write_sqe<S: Copy>(&self, index: u32, sqe: S) {
+        let offset = SQ_OFFSET + (index % sq_entries::<S>()) as usize * size_of::<S>();
+        unsafe { (self.ptr(offset) as *mut S).write_volatile(sqe) };
+    }
+
+    /// Read entry `index` of the submission queue.
+    pub fn read_sqe<S: Copy>(&self, index: u32) -> S {
+        let offset = SQ_OFFSET + (index % sq_entries::<S>()) as usize * size_of::<S>();
+        unsafe { (self.ptr(offset) as *const S).read_volatile() }
+    }
+
+    /// Write the field at `field_offset` of entry `index` of the completion queue.
+    pub fn write_cqe_field<C, T: Copy>(&self, index: u32, field_offset: usize, value: T) {
+        debug_assert!(field_offset + size_of::<T>() <= size_of::<C>());
+        let offset = CQ_OFFSET + (index % cq_entries::<C>()) as usize * size_of::<C>();
+        unsafe { (self.ptr(offset + field_offset) as *mut T).write_volatile(value) };
+    }
+
+    /// Read entry `index` of the completion queue.
+    pub fn read_cqe<C: Copy>(&self, index: u32) -> C {
+        let offset = CQ_OFFSET + (index % cq_entries::<C>()) as usize * size_of::<C>();
+        unsafe { (self.ptr(offset) as *const C).read_volatile() }
+    }
+
+    /// Physical address of a field of the header, for waking futex waiters.
+    pub fn header_physaddr(&self, offset: usize) -> PhysicalAddress {
+        self.frames[0].base().add(offset)
+    }
+
+    /// Map the ring pair into an address space.
+    pub fn map(
+        &self,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        if map.offset != 0 || map.size != RING_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+
+        let mut notify_files = Vec::new();
+        let page = addr_space.acquire_write().mmap(
+            addr_space,
+            (map.address != 0).then_some(span.base),
+            page_count,
+            map.flags,
+            &mut notify_files,
+            |dst_page, flags, mapper, flusher| {
+                Grant::allocated_shared(&self.frames, dst_page, flags, mapper, flusher)
+            },
+        )?;
+        handle_notify_files(notify_files, token);
+
+        Ok(page.start_address().data())
+    }
+}
+
+impl Drop for RingPages {
+    fn drop(&mut self) {
+        for &frame in self.frames.iter() {
+            let info = get_page_info(frame).expect("ring page had no PageInfo");
+            if info.remove_ref().is_none() {
+                unsafe { deallocate_frame(frame) };
+            }
+        }
+    }
+}
+
+/// The rings of a userspace scheme, where the kernel produces `Sqe`s and consumes `Cqe`s.
+pub struct Ring {
+    pages: RingPages,
+    sq_tail: Mutex<u32>,
+    cq_head: Mutex<u32>,
+}
+
+impl Ring {
+    pub fn new() -> Option<Ring> {
+        Some(Ring {
+            pages: RingPages::new(sq_entries::<Sqe>(), cq_entries::<Cqe>())?,
+            sq_tail: Mutex::new(0),
+            cq_head: Mutex::new(0),
+        })
+    }
+
+    pub fn pages(&self) -> &RingPages {
+        &self.pages
+    }
+
+    /// Write a request to the submission queue. Returns false if it is full.
+    pub fn push_sqe(&self, sqe: &Sqe) -> bool {
+        let header = self.pages.header();
+        let mut tail = self.sq_tail.lock();
+
+        // If the scheme wrote a head beyond the tail, the queue is treated as full.
+        let head = header.sq_head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= sq_entries::<Sqe>() {
+            return false;
+        }
+        self.pages.write_sqe(*tail, *sqe);
+
+        *tail = tail.wrapping_add(1);
+        header.sq_tail.store(*tail, Ordering::Release);
+        true
+    }
+
+    /// Number of requests in the submission queue the scheme has not consumed yet.
+    pub fn sq_len(&self) -> usize {
+        let head = self.pages.header().sq_head.load(Ordering::Acquire);
+        // An invalid head makes the queue full, as for `push_sqe`.
+        let pending = self.sq_tail.lock().wrapping_sub(head);
+        pending.min(sq_entries::<Sqe>()) as usize
+    }
+
+    /// Whether the scheme has not yet consumed every request in the submission queue.
+    pub fn has_sqes(&self) -> bool {
+        let head = self.pages.header().sq_head.load(Ordering::Acquire);
+        *self.sq_tail.lock() != head
+    }
+
+    /// Take back the requests the scheme has not consumed, when it stops reading the ring.
+    pub fn take_sqes(&self) -> Vec<Sqe> {
+        let header = self.pages.header();
+        let tail = self.sq_tail.lock();
+
+        // If the scheme wrote an invalid head, it is not known which requests it consumed.
+        let head = header.sq_head.load(Ordering::Acquire);
+        let pending = tail.wrapping_sub(head);
+        if pending > sq_entries::<Sqe>() {
+            return Vec::new();
+        }
+        let sqes = (0..pending)
+            .map(|i| self.pages.read_sqe(head.wrapping_add(i)))
+            .collect();
+
+        header.sq_head.store(*tail, Ordering::Release);
+        sqes
+    }
+
+    /// Whether the scheme is waiting on `sq_tail` with a futex.
+    pub fn needs_wakeup(&self) -> bool {
+        self.pages.header().sq_flags.load(Ordering::Acquire) & SQ_NEED_WAKEUP != 0
+    }
+
+    /// Physical address of `sq_tail`, for waking futex waiters.
+    pub fn sq_tail_physaddr(&self) -> PhysicalAddress {
+        self.pages.header_physaddr(offset_of!(RingHeader, sq_tail))
+    }
+
+    /// Take the next response from the completion queue, if any.
+    pub fn pop_cqe(&self) -> Option<Cqe> {
+        let header = self.pages.header();
+        let mut head = self.cq_head.lock();
+
+        // A tail more than a full queue ahead is invalid, and its entries are not read.
+        let tail = header.cq_tail.load(Ordering::Acquire);
+        let pending = tail.wrapping_sub(*head);
+        if pending == 0 || pending > cq_entries::<Cqe>() {
+            return None;
+        }
+        let cqe = self.pages.read_cqe(*head);
+
+        *head = head.wrapping_add(1);
+        header.cq_head.store(*head, Ordering::Release);
+        Some(cqe)
+    }
+}
@@ -1,5 +1,6 @@
 use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
 use core::{
+    mem::size_of,
     str,
     sync::atomic::{AtomicUsize, Ordering},
 };
@@ -10,15 +11,22 @@ use syscall::{
 };
 
 use crate::{
-    context::{self, file::InternalFlags},
+    context::{
+        self,
+        file::InternalFlags,
+        memory::{AddrSpace, AddrSpaceWrapper},
+    },
+    cpu_set::LogicalCpuId,
+    event,
     scheme::{
         self,
-        user::{UserInner, UserScheme},
+        stats::SchemeStats,
+        user::{UserInner, UserScheme, Worker},
         FileDescription, SchemeId, SchemeNamespace,
     },
     sync::{CleanLockToken, RwLock, L1},
     syscall::{
-        data::Stat,
+        data::{Map, Stat},
         error::*,
         flag::{CallFlags, EventFlags, MODE_DIR, MODE_FILE, O_CREAT},
         usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
@@ -30,8 +38,21 @@ use super::{CallerCtx, KernelScheme, KernelSchemes, OpenResult};
 #[derive(Clone)]
 enum Handle {
     Scheme(Arc<UserInner>),
-    File(Arc<Box<[u8]>>),
-    List { ens: SchemeNamespace },
+    /// Another handle of a scheme handler, obtained by duplicating the scheme handle with
+    /// `worker`, or `worker/<cpu>` to only receive requests of callers running on that CPU.
+    Worker(Arc<UserInner>, Arc<Worker>),
+    File(Arc<Watch>),
+    List {
+        ens: SchemeNamespace,
+    },
+}
+
+/// A scheme name opened without `O_CREAT`, which can be watched for the handler of the scheme of
+/// that name going away. Reading it returns, as a `usize`, how many times that happened since the
+/// last read, and `EVENT_READ` is triggered each time.
+struct Watch {
+    name: Box<[u8]>,
+    gone: AtomicUsize,
 }
 
 pub struct RootScheme {
@@ -39,6 +60,7 @@ pub struct RootScheme {
     scheme_id: SchemeId,
     next_id: AtomicUsize,
     handles: RwLock<L1, HashMap<usize, Handle>>,
+    stats: SchemeStats,
 }
 
 impl RootScheme {
@@ -48,6 +70,27 @@ impl RootScheme {
             scheme_id,
             next_id: AtomicUsize::new(0),
             handles: RwLock::new(HashMap::new()),
+            stats: SchemeStats::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &SchemeStats {
+        &self.stats
+    }
+
+    /// Notify the watches of a scheme name that the scheme handler went away.
+    fn notify_watches(&self, name: &[u8], token: &mut CleanLockToken) {
+        let mut ids = Vec::new();
+        for (&id, handle) in self.handles.read(token.token()).iter() {
+            if let Handle::File(watch) = handle
+                && *watch.name == *name
+            {
+                watch.gone.fetch_add(1, Ordering::SeqCst);
+                ids.push(id);
+            }
+        }
+        for id in ids {
+            event::trigger(self.scheme_id, id, EventFlags::EVENT_READ);
         }
     }
 }
@@ -73,6 +116,7 @@ impl KernelScheme for RootScheme {
             }
 
             let context = Arc::downgrade(&context::current());
+            let addr_space = Arc::downgrade(&AddrSpace::current()?);
 
             let id = self.next_id.fetch_add(1, Ordering::Relaxed);
 
@@ -106,6 +150,7 @@ impl KernelScheme for RootScheme {
                             path_box,
                             flags,
                             context,
+                            addr_space,
                         ));
                         (
                             KernelSchemes::User(UserScheme::new(Arc::downgrade(&inner))),
@@ -130,7 +175,10 @@ impl KernelScheme for RootScheme {
                 .insert(id, Handle::List { ens });
             Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
         } else {
-            let inner = Arc::new(path.as_bytes().to_vec().into_boxed_slice());
+            let inner = Arc::new(Watch {
+                name: path.as_bytes().to_vec().into_boxed_slice(),
+                gone: AtomicUsize::new(0),
+            });
 
             let id = self.next_id.fetch_add(1, Ordering::Relaxed);
             self.handles
@@ -164,6 +212,51 @@ impl KernelScheme for RootScheme {
         inner.unmount(token)
     }
 
+    fn kdup(
+        &self,
+        old_id: usize,
+        user_buf: UserSliceRo,
+        _ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        let inner = match *self
+            .handles
+            .read(token.token())
+            .get(&old_id)
+            .ok_or(Error::new(EBADF))?
+        {
+            Handle::Scheme(ref inner) | Handle::Worker(ref inner, _) => inner.clone(),
+            Handle::File(_) | Handle::List { .. } => return Err(Error::new(EBADF)),
+        };
+
+        let mut buf = [0_u8; 32];
+        let len = user_buf.copy_common_bytes_to_slice(&mut buf)?;
+        let buf = str::from_utf8(&buf[..len]).map_err(|_| Error::new(EINVAL))?;
+
+        let cpu = match buf.strip_prefix("worker") {
+            Some("") => None,
+            Some(cpu) => {
+                let cpu = cpu
+                    .strip_prefix('/')
+                    .and_then(|cpu| cpu.parse::<u32>().ok())
+                    .ok_or(Error::new(EINVAL))?;
+                if cpu >= crate::cpu_count() {
+                    return Err(Error::new(EINVAL));
+                }
+                Some(LogicalCpuId::new(cpu))
+            }
+            None => return Err(Error::new(EINVAL)),
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let worker = inner.add_worker(id, cpu);
+        self.handles
+            .write(token.token())
+            .insert(id, Handle::Worker(inner, worker));
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
     fn fsize(&self, file: usize, token: &mut CleanLockToken) -> Result<u64> {
         let handle = {
             let handles = self.handles.read(token.token());
@@ -172,7 +265,7 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(_) => Err(Error::new(EBADF)),
+            Handle::Scheme(_) | Handle::Worker(..) => Err(Error::new(EBADF)),
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Ok(0),
         }
@@ -191,8 +284,13 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.fevent(flags),
-            Handle::File(_) => Err(Error::new(EBADF)),
+            Handle::Scheme(inner) => inner.fevent(inner.primary(), flags),
+            Handle::Worker(inner, worker) => inner.fevent(&worker, flags),
+            Handle::File(watch) => Ok(if watch.gone.load(Ordering::SeqCst) == 0 {
+                EventFlags::empty()
+            } else {
+                EventFlags::EVENT_READ.intersection(flags)
+            }),
             Handle::List { .. } => Err(Error::new(EBADF)),
         }
     }
@@ -213,11 +311,11 @@ impl KernelScheme for RootScheme {
         buf = buf.advance(bytes_copied).ok_or(Error::new(EINVAL))?;
 
         match handle {
-            Handle::Scheme(inner) => {
+            Handle::Scheme(inner) | Handle::Worker(inner, _) => {
                 bytes_copied += buf.copy_common_bytes_from_slice(inner.name.as_bytes())?;
             }
-            Handle::File(inner) => {
-                bytes_copied += buf.copy_common_bytes_from_slice(&inner)?;
+            Handle::File(watch) => {
+                bytes_copied += buf.copy_common_bytes_from_slice(&watch.name)?;
             }
             Handle::List { .. } => (),
         }
@@ -233,7 +331,30 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.fsync(),
+            Handle::Scheme(inner) => inner.fsync(inner.primary(), token),
+            Handle::Worker(inner, worker) => inner.fsync(&worker, token),
+            Handle::File(_) => Err(Error::new(EBADF)),
+            Handle::List { .. } => Err(Error::new(EBADF)),
+        }
+    }
+
+    fn kfmap(
+        &self,
+        file: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read(token.token());
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match handle {
+            Handle::Scheme(inner) => inner.map_ring(inner.primary(), addr_space, map, token),
+            Handle::Worker(inner, worker) => inner.map_ring(&worker, addr_space, map, token),
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Err(Error::new(EBADF)),
         }
@@ -245,8 +366,20 @@ impl KernelScheme for RootScheme {
             .write(token.token())
             .remove(&file)
             .ok_or(Error::new(EBADF))?;
-        if let Handle::Scheme(inner) = handle {
-            scheme::schemes_mut(token.token()).remove(inner.scheme_id);
+        match handle {
+            Handle::Scheme(inner) => {
+                scheme::schemes_mut(token.token()).remove(inner.scheme_id);
+                inner.disconnect(token);
+
+                // Wake event loops waiting on files of the scheme, which now fail with ENODEV.
+                event::trigger_all(
+                    inner.scheme_id,
+                    EventFlags::EVENT_READ | EventFlags::EVENT_WRITE,
+                );
+                self.notify_watches(inner.name.as_bytes(), token);
+            }
+            Handle::Worker(inner, worker) => inner.remove_worker(&worker, token),
+            Handle::File(_) | Handle::List { .. } => (),
         }
         Ok(())
     }
@@ -266,8 +399,16 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.read(buf, flags, token),
-            Handle::File(_) => Err(Error::new(EBADF)),
+            Handle::Scheme(inner) => inner.read(inner.primary(), buf, flags, token),
+            Handle::Worker(inner, worker) => inner.read(&worker, buf, flags, token),
+            Handle::File(watch) => {
+                let gone = watch.gone.swap(0, Ordering::SeqCst);
+                if gone == 0 {
+                    return Err(Error::new(EAGAIN));
+                }
+                buf.write_usize(gone)?;
+                Ok(size_of::<usize>())
+            }
             Handle::List { .. } => Err(Error::new(EISDIR)),
         }
     }
@@ -324,7 +465,8 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.write(buf, token),
+            Handle::Scheme(inner) => inner.write(inner.primary(), buf, token),
+            Handle::Worker(inner, worker) => inner.write(&worker, buf, token),
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Err(Error::new(EISDIR)),
         }
@@ -338,7 +480,7 @@ impl KernelScheme for RootScheme {
         };
 
         buf.copy_exactly(&match handle {
-            Handle::Scheme(_) => Stat {
+            Handle::Scheme(_) | Handle::Worker(..) => Stat {
                 st_mode: MODE_FILE,
                 ..Default::default()
             },
@@ -371,7 +513,9 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.call_fdwrite(descs, flags, arg, metadata),
+            Handle::Scheme(inner) | Handle::Worker(inner, _) => {
+                inner.call_fdwrite(descs, flags, arg, metadata)
+            }
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Err(Error::new(EISDIR)),
         }
@@ -392,7 +536,9 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.call_fdread(payload, flags, metadata, token),
+            Handle::Scheme(inner) | Handle::Worker(inner, _) => {
+                inner.call_fdread(payload, flags, metadata, token)
+            }
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Err(Error::new(EISDIR)),
         }
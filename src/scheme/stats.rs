@@ -0,0 +1,95 @@
+//! Per-scheme counters of calls, and latencies of the requests to userspace schemes.
+//!
+//! Calls are counted by the syscalls that dispatch them, rather than by each scheme. The counters
+//! of a userspace scheme are kept in its `UserInner`, and go away with it, those of a root scheme
+//! in the `RootScheme`, and those of the global schemes in a static table.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{KernelSchemes, MAX_GLOBAL_SCHEMES};
+
+/// Number of buckets of the latency histograms. Bucket `i` counts the requests that took less
+/// than `2^i` microseconds, but at least `2^(i - 1)`, and the last one also the slower requests.
+pub const LATENCY_BUCKETS: usize = 24;
+
+#[derive(Clone, Copy)]
+pub enum SchemeOp {
+    Open,
+    Read,
+    Write,
+    Fmap,
+    Call,
+}
+
+pub struct SchemeStats {
+    pub open: AtomicU64,
+    pub read: AtomicU64,
+    pub write: AtomicU64,
+    pub fmap: AtomicU64,
+    pub call: AtomicU64,
+    /// Requests to a userspace scheme, by the time from queueing their `Sqe` to receiving their
+    /// `Cqe`.
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl SchemeStats {
+    pub const fn new() -> SchemeStats {
+        SchemeStats {
+            open: AtomicU64::new(0),
+            read: AtomicU64::new(0),
+            write: AtomicU64::new(0),
+            fmap: AtomicU64::new(0),
+            call: AtomicU64::new(0),
+            latency: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+        }
+    }
+
+    pub fn count(&self, op: SchemeOp) {
+        let counter = match op {
+            SchemeOp::Open => &self.open,
+            SchemeOp::Read => &self.read,
+            SchemeOp::Write => &self.write,
+            SchemeOp::Fmap => &self.fmap,
+            SchemeOp::Call => &self.call,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add a request that took `nanos` nanoseconds to the latency histogram.
+    pub fn record_latency(&self, nanos: u128) {
+        let micros = nanos / 1000;
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self) -> [u64; LATENCY_BUCKETS] {
+        self.latency
+            .each_ref()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for SchemeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static GLOBAL_STATS: [SchemeStats; MAX_GLOBAL_SCHEMES] =
+    [const { SchemeStats::new() }; MAX_GLOBAL_SCHEMES];
+
+impl KernelSchemes {
+    /// Run `f` on the counters of this scheme, unless it is a userspace scheme that is gone.
+    pub fn with_stats<T>(&self, f: impl FnOnce(&SchemeStats) -> T) -> Option<T> {
+        match self {
+            Self::Root(scheme) => Some(f(scheme.stats())),
+            Self::User(scheme) => Some(f(scheme.inner.upgrade()?.stats())),
+            Self::Global(global) => Some(f(&GLOBAL_STATS[*global as usize])),
+        }
+    }
+
+    /// Count a call dispatched to this scheme.
+    pub fn count(&self, op: SchemeOp) {
+        self.with_stats(|stats| stats.count(op));
+    }
+}
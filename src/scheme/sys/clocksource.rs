@@ -0,0 +1,28 @@
+use crate::{
+    clocksource::{self, ClockSourceFlags},
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+/// List the registered clock sources. Writing a name to `sys:clocksource` selects one.
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for info in clocksource::info() {
+        let _ = write!(string, "{} {}", info.name, info.rating);
+        for (set, flag) in [
+            (info.current, "current"),
+            (info.selected, "selected"),
+            (info.flags.contains(ClockSourceFlags::WATCHDOG), "watchdog"),
+            (info.flags.contains(ClockSourceFlags::MUST_VERIFY), "verify"),
+            (info.unstable, "unstable"),
+        ] {
+            if set {
+                let _ = write!(string, " {}", flag);
+            }
+        }
+        string.push('\n');
+    }
+    Ok(string.into_bytes())
+}
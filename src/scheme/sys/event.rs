@@ -0,0 +1,73 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    event::{self, EventMode},
+    sync::CleanLockToken,
+    syscall::{error::Result, flag::EventFlags},
+};
+
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let mut rows = Vec::new();
+    {
+        let registry = event::registry();
+        for (reg_key, queue_list) in registry.iter() {
+            for (queue_key, registration) in queue_list.iter() {
+                rows.push((
+                    *queue_key,
+                    *reg_key,
+                    registration.flags,
+                    registration.mode,
+                    registration.is_armed(),
+                ));
+            }
+        }
+    }
+    rows.sort_by_key(|row| (row.0.queue, row.0.id, row.0.data));
+
+    let mut string = format!(
+        "{:<8}{:<8}{:<10}{:<8}{:<20}{:<7}{}\n",
+        "QUEUE", "SCHEME", "NUMBER", "ID", "DATA", "FLAGS", "MODE"
+    );
+
+    for (queue_key, reg_key, flags, mode, armed) in rows {
+        let mut flags_string = String::new();
+        flags_string.push(if flags.contains(EventFlags::EVENT_READ) {
+            'R'
+        } else {
+            '-'
+        });
+        flags_string.push(if flags.contains(EventFlags::EVENT_WRITE) {
+            'W'
+        } else {
+            '-'
+        });
+
+        let mut mode_string = String::from(if mode.contains(EventMode::LEVEL) {
+            "level"
+        } else {
+            "edge"
+        });
+        if mode.contains(EventMode::ONESHOT) {
+            mode_string.push_str(if armed {
+                " oneshot"
+            } else {
+                " oneshot(disarmed)"
+            });
+        }
+
+        let _ = writeln!(
+            string,
+            "{:<8}{:<8}{:<10}{:<8}{:<#20x}{:<7}{}",
+            queue_key.queue.get(),
+            reg_key.scheme.get(),
+            reg_key.number,
+            queue_key.id,
+            queue_key.data,
+            flags_string,
+            mode_string
+        );
+    }
+
+    Ok(string.into_bytes())
+}
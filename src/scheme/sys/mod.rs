@@ -27,11 +27,16 @@ use crate::{
     },
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{
+    cap::{self, CapKind},
+    CallerCtx, KernelScheme, OpenResult,
+};
 
 mod block;
+mod clocksource;
 mod context;
 mod cpu;
+mod event;
 
 #[cfg(feature = "sys_fdstat")]
 mod fdstat;
@@ -40,10 +45,14 @@ mod exe;
 mod iostat;
 mod irq;
 mod log;
+mod rtc;
 mod scheme;
 mod scheme_num;
+mod scheme_timeout;
+mod schemestat;
 mod stat;
 mod syscall;
+mod timex;
 mod uname;
 
 enum Handle {
@@ -51,12 +60,18 @@ enum Handle {
     Resource {
         path: &'static str,
         data: Option<Vec<u8>>,
+        writable: bool,
     },
 }
 
 enum Kind {
     Rd(fn(&mut CleanLockToken) -> Result<Vec<u8>>),
     Wr(fn(&[u8], &mut CleanLockToken) -> Result<usize>),
+    /// Readable by anyone, writable with the `SYS_WRITE` capability right
+    RdWr(
+        fn(&mut CleanLockToken) -> Result<Vec<u8>>,
+        fn(&[u8], &mut CleanLockToken) -> Result<usize>,
+    ),
 }
 use Kind::*;
 
@@ -68,8 +83,17 @@ static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
 
 const FILES: &[(&str, Kind)] = &[
     ("block", Rd(block::resource)),
+    (
+        "cap_root_compat",
+        RdWr(cap::sys_root_compat_resource, cap::sys_root_compat),
+    ),
+    (
+        "clocksource",
+        RdWr(clocksource::resource, crate::clocksource::sys_clocksource),
+    ),
     ("context", Rd(context::resource)),
     ("cpu", Rd(cpu::resource)),
+    ("event", Rd(event::resource)),
     #[cfg(feature = "sys_fdstat")]
     ("fdstat", Rd(fdstat::resource)),
     ("exe", Rd(exe::resource)),
@@ -78,7 +102,13 @@ const FILES: &[(&str, Kind)] = &[
     ("log", Rd(log::resource)),
     ("scheme", Rd(scheme::resource)),
     ("scheme_num", Rd(scheme_num::resource)),
+    (
+        "scheme_timeout",
+        RdWr(scheme_timeout::resource, scheme_timeout::write),
+    ),
+    ("schemestat", Rd(schemestat::resource)),
     ("syscall", Rd(syscall::resource)),
+    ("timex", Rd(timex::resource)),
     ("uname", Rd(uname::resource)),
     ("env", Rd(|_| Ok(Vec::from(crate::init_env())))),
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -95,6 +125,8 @@ const FILES: &[(&str, Kind)] = &[
         "update_time_offset",
         Wr(crate::time::sys_update_time_offset),
     ),
+    ("adjtime", Wr(crate::time::sys_adjtime)),
+    ("rtc", RdWr(rtc::resource, crate::time::sys_rtc)),
     (
         "kstop",
         Wr(|arg, token| unsafe {
@@ -113,7 +145,7 @@ impl KernelScheme for SysScheme {
         &self,
         path: &str,
         _flags: usize,
-        ctx: CallerCtx,
+        _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         let path = path.trim_matches('/');
@@ -131,20 +163,28 @@ impl KernelScheme for SysScheme {
                 .find(|(entry_path, _)| *entry_path == path)
                 .ok_or(Error::new(ENOENT))?;
 
-            if matches!(entry.1, Wr(_)) && ctx.uid != 0 {
+            let rights = if entry.0 == "kstop" {
+                cap::SYS_KSTOP
+            } else {
+                cap::SYS_WRITE
+            };
+            let may_write = !matches!(entry.1, Rd(_)) && cap::held(CapKind::Sys, rights, token);
+            if matches!(entry.1, Wr(_)) && !may_write {
                 return Err(Error::new(EPERM));
             }
 
             let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-            let data = match entry.1 {
-                Rd(r) => Some(r(token)?),
-                Wr(_) => None,
+            let (data, writable) = match entry.1 {
+                Rd(r) => (Some(r(token)?), false),
+                Wr(_) => (None, true),
+                RdWr(r, _) => (Some(r(token)?), may_write),
             };
             HANDLES.write(token.token()).insert(
                 id,
                 Handle::Resource {
                     path: entry.0,
                     data,
+                    writable,
                 },
             );
             Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
@@ -228,20 +268,25 @@ impl KernelScheme for SysScheme {
             .get(&id)
             .ok_or(Error::new(EBADF))?
         {
-            Handle::TopLevel | Handle::Resource { data: Some(_), .. } => {
-                return Err(Error::new(EISDIR))
-            }
-            Handle::Resource { data: None, path } => {
+            Handle::TopLevel
+            | Handle::Resource {
+                writable: false, ..
+            } => return Err(Error::new(EISDIR)),
+            Handle::Resource {
+                path,
+                writable: true,
+                ..
+            } => {
                 let mut intermediate = [0_u8; 256];
                 let len = buffer.copy_common_bytes_to_slice(&mut intermediate)?;
-                let (_, Wr(handler)) = FILES
+                let (_, Wr(handler) | RdWr(_, handler)) = FILES
                     .iter()
                     .find(|(entry_path, _)| entry_path == path)
                     .ok_or(Error::new(EBADFD))?
                 else {
                     return Err(Error::new(EBADFD))?;
                 };
-                (handler, intermediate, len)
+                (*handler, intermediate, len)
             }
         };
         handler(&intermediate[..len], token)
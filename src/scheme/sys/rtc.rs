@@ -0,0 +1,15 @@
+use crate::{sync::CleanLockToken, syscall::error::Result, time};
+use alloc::vec::Vec;
+
+/// Get the time of the RTC, and whether the realtime clock is written to it on shutdown.
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let rtc = crate::arch::time::rtc_time()?;
+
+    Ok(format!(
+        "time: {}\n\
+        sync_on_shutdown: {}\n",
+        rtc,
+        u8::from(time::rtc_sync_on_shutdown()),
+    )
+    .into_bytes())
+}
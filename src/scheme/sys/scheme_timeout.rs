@@ -0,0 +1,74 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str, sync::atomic::Ordering};
+
+use crate::{
+    context,
+    scheme::{self, KernelSchemes, SchemeNamespace},
+    sync::CleanLockToken,
+    syscall::error::{Error, Result, EINVAL},
+    time::NANOS_PER_SEC,
+};
+
+const NANOS_PER_MILLI: u128 = NANOS_PER_SEC / 1000;
+
+/// Get the timeout of calls to user schemes made from the current namespace, and the number of
+/// requests to each scheme that timed out.
+pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let scheme_ns = context::current().read(token.token()).ens;
+
+    let mut string = String::new();
+    let schemes = scheme::schemes(token.token());
+    match schemes.call_timeout(scheme_ns) {
+        Some(timeout) => {
+            let _ = writeln!(string, "timeout_ms: {}", timeout / NANOS_PER_MILLI);
+        }
+        None => string.push_str("timeout_ms: none\n"),
+    }
+    for (name, &scheme_id) in schemes.iter_name(scheme_ns) {
+        let Some(KernelSchemes::User(user)) = schemes.get(scheme_id) else {
+            continue;
+        };
+        let Some(inner) = user.inner.upgrade() else {
+            continue;
+        };
+        let stats = inner.timeout_stats();
+        let _ = writeln!(
+            string,
+            "{}: timed_out={} abandoned={} stuck={}",
+            name,
+            stats.timed_out.load(Ordering::Relaxed),
+            stats.abandoned.load(Ordering::Relaxed),
+            stats.stuck.load(Ordering::Relaxed),
+        );
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Set the timeout of calls to user schemes, with `timeout_ms=<milliseconds>` (0 for none), for
+/// the current namespace or the one given by `ns=<id>`.
+pub fn write(buf: &[u8], token: &mut CleanLockToken) -> Result<usize> {
+    let args = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    let mut ns = None;
+    let mut timeout_ms = None;
+    for arg in args.split_ascii_whitespace() {
+        let (key, value) = arg.split_once('=').ok_or(Error::new(EINVAL))?;
+        let value = value.parse::<u64>().map_err(|_| Error::new(EINVAL))?;
+        match key {
+            "ns" => ns = Some(SchemeNamespace::from(value as usize)),
+            "timeout_ms" => timeout_ms = Some(value),
+            _ => return Err(Error::new(EINVAL)),
+        }
+    }
+    let timeout_ms = timeout_ms.ok_or(Error::new(EINVAL))?;
+    let ns = match ns {
+        Some(ns) => ns,
+        None => context::current().read(token.token()).ens,
+    };
+
+    let timeout = (timeout_ms != 0).then(|| u128::from(timeout_ms) * NANOS_PER_MILLI);
+    scheme::schemes_mut(token.token()).set_call_timeout(ns, timeout)?;
+
+    Ok(buf.len())
+}
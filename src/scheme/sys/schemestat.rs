@@ -0,0 +1,63 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, sync::atomic::Ordering};
+
+use crate::{
+    context,
+    scheme::{self, KernelSchemes},
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+/// Get the number of opens, reads, writes, fmaps and calls made to each scheme of the current
+/// namespace. Userspace schemes also get a latency histogram, where the `i`th count is of the
+/// requests that took less than `2^i` microseconds, up to the last nonzero count, followed by the
+/// depths of their request queues.
+pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let scheme_ns = context::current().read(token.token()).ens;
+
+    let mut string = String::new();
+    let schemes = scheme::schemes(token.token());
+    for (name, &scheme_id) in schemes.iter_name(scheme_ns) {
+        let Some(scheme) = schemes.get(scheme_id) else {
+            continue;
+        };
+        let user = match scheme {
+            KernelSchemes::User(user) => Some(user.inner.upgrade()),
+            _ => None,
+        };
+        scheme.with_stats(|stats| {
+            let _ = write!(
+                string,
+                "{}: open={} read={} write={} fmap={} call={}",
+                name,
+                stats.open.load(Ordering::Relaxed),
+                stats.read.load(Ordering::Relaxed),
+                stats.write.load(Ordering::Relaxed),
+                stats.fmap.load(Ordering::Relaxed),
+                stats.call.load(Ordering::Relaxed),
+            );
+            if let Some(inner) = &user {
+                let latency = stats.latency();
+                let len = latency.iter().rposition(|&n| n != 0).map_or(0, |i| i + 1);
+                string.push_str(" latency_us=");
+                for (i, n) in latency[..len].iter().enumerate() {
+                    if i != 0 {
+                        string.push(',');
+                    }
+                    let _ = write!(string, "{}", n);
+                }
+                if let Some(inner) = inner {
+                    let depth = inner.queue_depth();
+                    let _ = write!(
+                        string,
+                        " queued={} callers={} unread={} pending={}",
+                        depth.queued, depth.callers, depth.unread, depth.pending,
+                    );
+                }
+            }
+            string.push('\n');
+        });
+    }
+
+    Ok(string.into_bytes())
+}
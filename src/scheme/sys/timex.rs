@@ -0,0 +1,31 @@
+use crate::{
+    sync::CleanLockToken,
+    syscall::error::Result,
+    time::{self, NANOS_PER_SEC},
+};
+use alloc::vec::Vec;
+
+/// Get the state of the realtime clock adjustments made through `sys:adjtime`.
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let status = time::adjustment_status();
+    let realtime = time::realtime();
+
+    Ok(format!(
+        "time: {}.{:09}\n\
+        offset: {}\n\
+        freq: {}\n\
+        rate: {}\n\
+        maxerror: {}\n\
+        esterror: {}\n\
+        status: {:#x}\n",
+        realtime / NANOS_PER_SEC,
+        realtime % NANOS_PER_SEC,
+        status.offset,
+        status.freq_ppb,
+        status.rate_ppb,
+        status.max_error,
+        status.est_error,
+        status.status,
+    )
+    .into_bytes())
+}
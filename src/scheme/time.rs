@@ -1,27 +1,251 @@
+use alloc::{sync::Arc, vec::Vec};
 use core::{
     mem, str,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+use spin::Mutex;
 
 use crate::{
-    context::{file::InternalFlags, timeout},
-    sync::{CleanLockToken, RwLock, L1},
+    context::{
+        file::InternalFlags,
+        timeout::{self, TimerId},
+    },
+    event,
+    sync::{CleanLockToken, RwLock, WaitCondition, L1},
     syscall::{
-        data::TimeSpec,
+        data::{ITimerSpec, TimeSpec},
         error::*,
-        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
+        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
-    time,
+    time::{self, Clock},
 };
 
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
-static HANDLES: RwLock<L1, HashMap<usize, usize>> =
+static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+/// Timeouts queued for each handle, so that they can be cancelled when it is closed or rearmed.
+static TIMEOUTS: Mutex<HashMap<usize, Vec<TimerId>>> =
+    Mutex::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+#[derive(Clone)]
+enum Handle {
+    Clock(Clock),
+    Timer(Arc<Timer>),
+}
+
+struct Timer {
+    clock: Clock,
+    state: Mutex<TimerState>,
+    condition: WaitCondition, // signals whether there are expirations to read
+}
+
+struct TimerState {
+    /// Next expiration in nanoseconds on the timer's clock, if armed.
+    deadline: Option<u128>,
+    /// Interval between expirations in nanoseconds, or zero for a one-shot timer.
+    interval: u128,
+    /// Incremented when the timer is rearmed, disarmed or cancelled, so that pending timeouts
+    /// from an earlier arming are ignored.
+    generation: u128,
+    /// Number of expirations since the last read.
+    expirations: u64,
+    /// Whether the timer was disarmed because the realtime clock was set.
+    cancelled: bool,
+}
+
+impl TimerState {
+    fn is_readable(&self) -> bool {
+        self.expirations > 0 || self.cancelled
+    }
+}
+
+fn handle(id: usize, token: &mut CleanLockToken) -> Result<Handle> {
+    HANDLES
+        .read(token.token())
+        .get(&id)
+        .cloned()
+        .ok_or(Error::new(EBADF))
+}
+
+/// Remember a timeout queued for a handle, forgetting those that have expired.
+fn add_timeout(id: usize, timer: TimerId, token: &mut CleanLockToken) {
+    {
+        let mut timeouts = TIMEOUTS.lock();
+        let timers = timeouts.entry(id).or_default();
+        timers.retain(|timer| !timer.has_expired());
+        timers.push(timer);
+    }
+
+    // The handle may have been closed in the meantime.
+    if handle(id, token).is_err() {
+        cancel_timeouts(id, token);
+    }
+}
+
+/// Cancel the timeouts queued for a handle.
+fn cancel_timeouts(id: usize, token: &mut CleanLockToken) {
+    let timers = TIMEOUTS.lock().remove(&id).unwrap_or_default();
+    for timer in timers {
+        timeout::cancel(timer, token);
+    }
+}
+
+fn timespec_to_nanos(time: TimeSpec) -> Result<u128> {
+    if time.tv_sec < 0 || time.tv_nsec < 0 || time.tv_nsec as u128 >= time::NANOS_PER_SEC {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(time.tv_sec as u128 * time::NANOS_PER_SEC + time.tv_nsec as u128)
+}
+
+fn nanos_to_timespec(nanos: u128) -> TimeSpec {
+    TimeSpec {
+        tv_sec: (nanos / time::NANOS_PER_SEC) as i64,
+        tv_nsec: (nanos % time::NANOS_PER_SEC) as i32,
+    }
+}
+
+/// Call `func(id, arg)` once `clock` reaches `deadline`.
+///
+/// Only the realtime and monotonic clocks have timer queues. Other clocks advance at most as fast
+/// as the monotonic clock, or once per CPU for process CPU time, so their callbacks are scheduled
+/// for the earliest time the deadline can be reached, and have to check the clock again.
+fn schedule(
+    clock: &Clock,
+    deadline: u128,
+    func: fn(usize, u128, &mut CleanLockToken),
+    id: usize,
+    arg: u128,
+    token: &mut CleanLockToken,
+) {
+    let (queue_clock, time) = match clock {
+        Clock::Realtime => (CLOCK_REALTIME, deadline),
+        Clock::Monotonic => (CLOCK_MONOTONIC, deadline),
+        _ => {
+            // The thread or process is gone, so the clock will not advance anymore.
+            let Ok(now) = clock.now(token) else {
+                return;
+            };
+            let mut remaining = deadline.saturating_sub(now);
+            if let Clock::ProcessCpuTime(_) = clock {
+                remaining /= u128::from(crate::cpu_count());
+            }
+            (CLOCK_MONOTONIC, time::monotonic() + remaining)
+        }
+    };
+
+    let timer = timeout::register_callback(queue_clock, time, func, (id, arg), token);
+    add_timeout(id, timer, token);
+}
+
+/// Called when a deadline written to a `time:<clock>` handle may have been reached, for clocks
+/// without a timer queue.
+fn deadline_reached(id: usize, deadline: u128, token: &mut CleanLockToken) {
+    let Ok(Handle::Clock(clock)) = handle(id, token) else {
+        return;
+    };
+    let Ok(now) = clock.now(token) else {
+        return;
+    };
+
+    if now < deadline {
+        schedule(&clock, deadline, deadline_reached, id, deadline, token);
+    } else {
+        event::trigger(GlobalSchemes::Time.scheme_id(), id, EVENT_READ);
+    }
+}
+
+/// Called when an interval timer may have expired.
+fn timer_expired(id: usize, generation: u128, token: &mut CleanLockToken) {
+    let Ok(Handle::Timer(timer)) = handle(id, token) else {
+        return;
+    };
+    let Ok(now) = timer.clock.now(token) else {
+        return;
+    };
+
+    let (next, expired) = {
+        let mut state = timer.state.lock();
+        let Some(deadline) = state.deadline.filter(|_| state.generation == generation) else {
+            return;
+        };
 
+        if now < deadline {
+            (Some(deadline), false)
+        } else {
+            // Count the expirations that were missed, if the interrupt came late.
+            let expirations = if state.interval > 0 {
+                1 + (now - deadline) / state.interval
+            } else {
+                1
+            };
+            state.expirations = state
+                .expirations
+                .saturating_add(expirations.min(u64::MAX as u128) as u64);
+
+            state.deadline = if state.interval > 0 {
+                Some(deadline + expirations * state.interval)
+            } else {
+                None
+            };
+            (state.deadline, true)
+        }
+    };
+
+    if let Some(next) = next {
+        schedule(&timer.clock, next, timer_expired, id, generation, token);
+    }
+
+    if expired {
+        event::trigger(GlobalSchemes::Time.scheme_id(), id, EVENT_READ);
+        timer.condition.notify(token);
+    }
+}
+
+/// Cancel all armed `CLOCK_REALTIME` timers, after the realtime clock was set. Reading them
+/// returns `ECANCELED` once.
+pub fn realtime_clock_set(token: &mut CleanLockToken) {
+    let timers = HANDLES
+        .read(token.token())
+        .iter()
+        .filter_map(|(&id, handle)| match handle {
+            Handle::Timer(timer) if matches!(timer.clock, Clock::Realtime) => {
+                Some((id, timer.clone()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for (id, timer) in timers {
+        {
+            let mut state = timer.state.lock();
+            if state.deadline.take().is_none() {
+                continue;
+            }
+            state.generation += 1;
+            state.cancelled = true;
+        }
+        cancel_timeouts(id, token);
+
+        event::trigger(GlobalSchemes::Time.scheme_id(), id, EVENT_READ);
+        timer.condition.notify(token);
+    }
+}
+
+/// Clocks are opened as the current context sees them, so CPU-time clocks measure the thread or
+/// process that opened them.
+///
+/// Opening `time:<clock>` gives a handle that reads the current time as a `TimeSpec`, and
+/// triggers a read event at each absolute `TimeSpec` written to it.
+///
+/// Opening `time:<clock>/timer` gives an interval timer. Writing an `ITimerSpec` arms it to first
+/// expire at the absolute time `it_value`, and then every `it_interval` if nonzero; a zero
+/// `it_value` disarms it. Reading returns the number of expirations since the last read as a
+/// `u64`, blocking until there is at least one. Armed `CLOCK_REALTIME` timers are disarmed when
+/// the realtime clock is set, which the next read reports with `ECANCELED`.
 pub struct TimeScheme;
 
 impl KernelScheme for TimeScheme {
@@ -32,16 +256,28 @@ impl KernelScheme for TimeScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let clock = path.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+        let (clock, kind) = path.split_once('/').unwrap_or((path, ""));
+        let clock = clock.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+        let clock = Clock::current(clock, token).map_err(|_| Error::new(ENOENT))?;
 
-        match clock {
-            CLOCK_REALTIME => (),
-            CLOCK_MONOTONIC => (),
+        let handle = match kind {
+            "" => Handle::Clock(clock),
+            "timer" => Handle::Timer(Arc::new(Timer {
+                clock,
+                state: Mutex::new(TimerState {
+                    deadline: None,
+                    interval: 0,
+                    generation: 0,
+                    expirations: 0,
+                    cancelled: false,
+                }),
+                condition: WaitCondition::new(),
+            })),
             _ => return Err(Error::new(ENOENT)),
-        }
+        };
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write(token.token()).insert(id, clock);
+        HANDLES.write(token.token()).insert(id, handle);
 
         Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
     }
@@ -59,21 +295,17 @@ impl KernelScheme for TimeScheme {
     fn fevent(
         &self,
         id: usize,
-        _flags: EventFlags,
+        flags: EventFlags,
         token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        HANDLES
-            .read(token.token())
-            .get(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(EventFlags::empty()))
+        match handle(id, token)? {
+            Handle::Timer(timer) if timer.state.lock().is_readable() => Ok(flags & EVENT_READ),
+            _ => Ok(EventFlags::empty()),
+        }
     }
 
     fn fsync(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        HANDLES
-            .read(token.token())
-            .get(&id)
-            .ok_or(Error::new(EBADF))?;
+        handle(id, token)?;
         Ok(())
     }
 
@@ -81,35 +313,50 @@ impl KernelScheme for TimeScheme {
         HANDLES
             .write(token.token())
             .remove(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(()))
+            .ok_or(Error::new(EBADF))?;
+        cancel_timeouts(id, token);
+        Ok(())
     }
     fn kread(
         &self,
         id: usize,
         buf: UserSliceWo,
-        _flags: u32,
+        flags: u32,
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let clock = *HANDLES
-            .read(token.token())
-            .get(&id)
-            .ok_or(Error::new(EBADF))?;
+        let clock = match handle(id, token)? {
+            Handle::Clock(clock) => clock,
+            Handle::Timer(timer) => {
+                let buf = buf.limit(mem::size_of::<u64>()).ok_or(Error::new(EINVAL))?;
+
+                loop {
+                    let mut state = timer.state.lock();
+
+                    if state.cancelled {
+                        state.cancelled = false;
+                        return Err(Error::new(ECANCELED));
+                    }
+                    if state.expirations > 0 {
+                        buf.copy_from_slice(&state.expirations.to_ne_bytes())?;
+                        state.expirations = 0;
+                        return Ok(mem::size_of::<u64>());
+                    }
+
+                    if flags & O_NONBLOCK as u32 != 0 {
+                        return Err(Error::new(EAGAIN));
+                    } else if !timer.condition.wait(state, "Timer::read", token) {
+                        return Err(Error::new(EINTR));
+                    }
+                }
+            }
+        };
 
         let mut bytes_read = 0;
 
         for current_chunk in buf.in_exact_chunks(mem::size_of::<TimeSpec>()) {
-            let arch_time = match clock {
-                CLOCK_REALTIME => time::realtime(),
-                CLOCK_MONOTONIC => time::monotonic(),
-                _ => return Err(Error::new(EINVAL)),
-            };
-            let time = TimeSpec {
-                tv_sec: (arch_time / time::NANOS_PER_SEC) as i64,
-                tv_nsec: (arch_time % time::NANOS_PER_SEC) as i32,
-            };
-            current_chunk.copy_exactly(&time)?;
+            let arch_time = clock.now(token)?;
+            current_chunk.copy_exactly(&nanos_to_timespec(arch_time))?;
 
             bytes_read += mem::size_of::<TimeSpec>();
         }
@@ -125,17 +372,56 @@ impl KernelScheme for TimeScheme {
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let clock = *HANDLES
-            .read(token.token())
-            .get(&id)
-            .ok_or(Error::new(EBADF))?;
+        let clock = match handle(id, token)? {
+            Handle::Clock(clock) => clock,
+            Handle::Timer(timer) => {
+                let buf = buf
+                    .limit(mem::size_of::<ITimerSpec>())
+                    .ok_or(Error::new(EINVAL))?;
+                let spec = unsafe { buf.read_exact::<ITimerSpec>()? };
+                let value = timespec_to_nanos(spec.it_value)?;
+                let interval = timespec_to_nanos(spec.it_interval)?;
+
+                let generation = {
+                    let mut state = timer.state.lock();
+                    state.generation += 1;
+                    state.deadline = Some(value).filter(|&value| value > 0);
+                    state.interval = interval;
+                    state.expirations = 0;
+                    state.cancelled = false;
+                    state.generation
+                };
+                cancel_timeouts(id, token);
+
+                if value > 0 {
+                    schedule(&timer.clock, value, timer_expired, id, generation, token);
+                }
+
+                return Ok(mem::size_of::<ITimerSpec>());
+            }
+        };
 
         let mut bytes_written = 0;
 
         for current_chunk in buf.in_exact_chunks(mem::size_of::<TimeSpec>()) {
             let time = unsafe { current_chunk.read_exact::<TimeSpec>()? };
 
-            timeout::register(GlobalSchemes::Time.scheme_id(), id, clock, time, token);
+            match clock {
+                Clock::Realtime | Clock::Monotonic => {
+                    let timer = timeout::register(
+                        GlobalSchemes::Time.scheme_id(),
+                        id,
+                        clock.id(),
+                        time,
+                        token,
+                    );
+                    add_timeout(id, timer, token);
+                }
+                _ => {
+                    let deadline = timespec_to_nanos(time)?;
+                    schedule(&clock, deadline, deadline_reached, id, deadline, token);
+                }
+            }
 
             bytes_written += mem::size_of::<TimeSpec>();
         }
@@ -143,12 +429,11 @@ impl KernelScheme for TimeScheme {
         Ok(bytes_written)
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
-        let clock = *HANDLES
-            .read(token.token())
-            .get(&id)
-            .ok_or(Error::new(EBADF))?;
-
-        let scheme_path = format!("time:{}", clock).into_bytes();
+        let scheme_path = match handle(id, token)? {
+            Handle::Clock(clock) => format!("time:{}", clock.id()),
+            Handle::Timer(timer) => format!("time:{}/timer", timer.clock.id()),
+        }
+        .into_bytes();
         buf.copy_common_bytes_from_slice(&scheme_path)
     }
 }
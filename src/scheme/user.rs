@@ -1,5 +1,6 @@
 use alloc::{
     boxed::Box,
+    collections::VecDeque,
     sync::{Arc, Weak},
     vec::Vec,
 };
@@ -7,10 +8,10 @@ use core::{
     mem,
     mem::size_of,
     num::NonZeroUsize,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use slab::Slab;
-use spin::{Mutex, RwLock};
+use spin::{Mutex, Once, RwLock};
 use syscall::{
     schemev2::{Cqe, CqeOpcode, Opcode, Sqe, SqeFlags},
     CallFlags, FmoveFdFlags, FobtainFdFlags, MunmapFlags, RecvFdFlags, SchemeSocketCall,
@@ -27,46 +28,158 @@ use crate::{
             AddrSpace, AddrSpaceWrapper, BorrowedFmapSource, Grant, GrantFileRef, MmapMode,
             PageSpan, DANGLING,
         },
-        BorrowedHtBuf, ContextLock, Status,
+        timeout, BorrowedHtBuf, ContextLock, Status,
     },
+    cpu_set::LogicalCpuId,
     event,
     memory::Frame,
-    paging::{Page, VirtualAddress, PAGE_SIZE},
-    scheme::SchemeId,
+    paging::{Page, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    scheme::{
+        self,
+        fair_queue::{self, FairQueue},
+        ring::Ring,
+        stats::SchemeStats,
+        SchemeId,
+    },
     sync::{CleanLockToken, WaitQueue},
     syscall::{
         data::{Map, Packet},
         error::*,
         flag::{EventFlags, MapFlags, EVENT_READ, O_NONBLOCK, PROT_READ},
+        futex,
         number::*,
         usercopy::{UserSlice, UserSliceRo, UserSliceRw, UserSliceWo},
     },
+    time,
 };
 
 use super::{CallerCtx, FileHandle, KernelScheme, OpenResult};
 
 pub struct UserInner {
     root_id: SchemeId,
-    handle_id: usize,
     pub name: Box<str>,
     pub scheme_id: SchemeId,
     v2: bool,
     supports_on_close: bool,
     context: Weak<ContextLock>,
-    todo: WaitQueue<Sqe>,
+    /// Address space of the scheme handler, which the memory borrowed by requests is mapped into.
+    addr_space: Weak<AddrSpaceWrapper>,
+    /// The worker of the handle that registered the scheme, which is never removed.
+    primary: Arc<Worker>,
+    /// Every worker requests are dispatched to, including the primary one.
+    workers: RwLock<Vec<Arc<Worker>>>,
+    /// Round-robin counter for requests of callers on CPUs without a bound worker.
+    next_worker: AtomicUsize,
 
     // TODO: custom packed radix tree data structure
     states: Mutex<Slab<State>>,
 
     unmounting: AtomicBool,
+    /// Set once the handle that registered the scheme is closed.
+    disconnected: AtomicBool,
+
+    timeout_stats: TimeoutStats,
+    stats: SchemeStats,
+}
+
+/// A root scheme handle of the scheme handler, which requests are dispatched to. Responses can be
+/// written to any worker, as the pending requests are shared.
+pub struct Worker {
+    handle_id: usize,
+    /// The CPU whose callers this worker serves, if it is bound to one.
+    cpu: Option<LogicalCpuId>,
+    /// Requests waiting for their turn to be delivered to the scheme handler.
+    queued: Mutex<FairQueue>,
+    /// Requests delivered to the scheme handler, to be read from the handle.
+    todo: WaitQueue<Sqe>,
+    /// Submission and completion rings shared with the scheme, once it has mapped them.
+    ring: Once<Ring>,
+    /// Set when the handle of the worker is closed, after which no requests are queued to it.
+    closed: AtomicBool,
+}
+
+/// How many delivered requests each worker can have unread, before the others are left in its
+/// fair queue.
+const DISPATCH_WINDOW: usize = 32;
+
+impl Worker {
+    fn new(handle_id: usize, cpu: Option<LogicalCpuId>) -> Worker {
+        Worker {
+            handle_id,
+            cpu,
+            queued: Mutex::new(FairQueue::default()),
+            todo: WaitQueue::new(),
+            ring: Once::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Make a request readable by the scheme handler. It is written to the submission ring if it
+    /// is mapped, unless it is full or earlier requests still have to be read from the handle, so
+    /// that the scheme handler sees them in order.
+    fn deliver(&self, todo: &mut VecDeque<Sqe>, sqe: Sqe) {
+        let in_ring = todo.is_empty() && self.ring.get().is_some_and(|ring| ring.push_sqe(&sqe));
+        if !in_ring {
+            todo.push_back(sqe);
+        }
+    }
+
+    /// Number of delivered requests the scheme handler has not read yet.
+    fn unread(&self, todo: &VecDeque<Sqe>) -> usize {
+        todo.len() + self.ring.get().map_or(0, |ring| ring.sq_len())
+    }
+}
+
+/// Number of requests to a scheme at each stage, summed over its workers.
+#[derive(Default)]
+pub struct QueueDepth {
+    /// Requests waiting for their turn in the fair queues.
+    pub queued: usize,
+    /// Requests delivered to the scheme handler that it has not read yet.
+    pub unread: usize,
+    /// Caller processes with queued requests.
+    pub callers: usize,
+    /// Requests the scheme handler has not responded to yet.
+    pub pending: usize,
+}
+
+/// How long a scheme has to respond to the cancelation of a request that timed out, before the
+/// caller stops waiting for it.
+const CANCEL_GRACE: u128 = time::NANOS_PER_SEC;
+
+/// Why a request is being canceled.
+#[derive(Clone, Copy, PartialEq)]
+enum Cancel {
+    None,
+    /// The caller was interrupted by a signal, and gets EINTR.
+    Signal,
+    /// The deadline of the namespace passed, and the caller gets ETIMEDOUT.
+    Timeout,
+    /// The scheme did not respond to the cancelation in time, and the caller already returned.
+    Abandoned,
+}
+
+/// Counters of requests that timed out.
+#[derive(Default)]
+pub struct TimeoutStats {
+    /// Requests that were canceled after their deadline.
+    pub timed_out: AtomicUsize,
+    /// Requests whose cancelation was not answered in time.
+    pub abandoned: AtomicUsize,
+    /// Abandoned requests the scheme has not responded to yet.
+    pub stuck: AtomicUsize,
 }
 
 enum State {
     Waiting {
         context: Weak<ContextLock>,
+        /// The worker the request was dispatched to, which cancelations are also sent to.
+        worker: Weak<Worker>,
         fds: Option<Vec<Arc<RwLock<FileDescription>>>>,
         callee_responsible: PageSpan,
-        canceling: bool,
+        cancel: Cancel,
+        /// When the request was queued, in monotonic nanoseconds.
+        submitted: u128,
     },
     Responded(Response),
     Fmap(Weak<ContextLock>),
@@ -210,35 +323,160 @@ impl UserInner {
         name: Box<str>,
         _flags: usize,
         context: Weak<ContextLock>,
+        addr_space: Weak<AddrSpaceWrapper>,
     ) -> UserInner {
+        let primary = Arc::new(Worker::new(handle_id, None));
         UserInner {
             root_id,
-            handle_id,
             name,
             v2,
             supports_on_close: new_close,
             scheme_id,
             context,
-            todo: WaitQueue::new(),
+            addr_space,
+            workers: RwLock::new(vec![primary.clone()]),
+            primary,
+            next_worker: AtomicUsize::new(0),
             unmounting: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
             states: Mutex::new(Slab::with_capacity(32)),
+            timeout_stats: TimeoutStats::default(),
+            stats: SchemeStats::new(),
+        }
+    }
+
+    pub fn timeout_stats(&self) -> &TimeoutStats {
+        &self.timeout_stats
+    }
+
+    pub fn stats(&self) -> &SchemeStats {
+        &self.stats
+    }
+
+    pub fn queue_depth(&self) -> QueueDepth {
+        let mut depth = QueueDepth {
+            pending: self.states.lock().len(),
+            ..QueueDepth::default()
+        };
+        for worker in self.workers.read().iter() {
+            let queued = worker.queued.lock();
+            depth.queued += queued.len();
+            depth.callers += queued.callers();
+            depth.unread += worker.unread(&worker.todo.inner.lock());
         }
+        depth
+    }
+
+    pub fn primary(&self) -> &Arc<Worker> {
+        &self.primary
     }
 
     pub fn unmount(&self, token: &mut CleanLockToken) -> Result<()> {
         // First, block new requests and prepare to return EOF
         self.unmounting.store(true, Ordering::SeqCst);
 
-        // Wake up any blocked scheme handler
-        unsafe { self.todo.condition.notify_signal(token) };
+        let workers = self.workers.read().clone();
+        for worker in workers {
+            // Wake up any blocked scheme handler
+            unsafe { worker.todo.condition.notify_signal(token) };
 
-        // Tell the scheme handler to read
-        event::trigger(self.root_id, self.handle_id, EVENT_READ);
+            // Tell the scheme handler to read
+            self.wake_ring(&worker, token);
+            event::trigger(self.root_id, worker.handle_id, EVENT_READ);
+        }
 
-        //TODO: wait for all todo and done to be processed?
+        // Requests that were already sent are still served, until the scheme handler closes its
+        // handle and the remaining ones fail.
         Ok(())
     }
 
+    /// Fail every request the scheme handler has not responded to, after the handle that
+    /// registered the scheme was closed. Waiting callers get EPIPE, and later calls ENODEV.
+    pub fn disconnect(&self, token: &mut CleanLockToken) {
+        self.disconnected.store(true, Ordering::SeqCst);
+        self.unmounting.store(true, Ordering::SeqCst);
+
+        let mut callers = Vec::new();
+        let mut fmap_callers = Vec::new();
+        let mut callee_responsible = Vec::new();
+        let mut to_close = Vec::new();
+        {
+            let mut states = self.states.lock();
+            let tags: Vec<usize> = states.iter().map(|(tag, _)| tag).collect();
+            for tag in tags {
+                match mem::replace(&mut states[tag], State::Placeholder) {
+                    State::Waiting {
+                        context,
+                        fds,
+                        cancel,
+                        callee_responsible: span,
+                        ..
+                    } => {
+                        callee_responsible.push(span);
+                        to_close.extend(
+                            fds.into_iter()
+                                .flatten()
+                                .filter_map(|f| Arc::try_unwrap(f).ok())
+                                .map(RwLock::into_inner),
+                        );
+                        if cancel == Cancel::Abandoned {
+                            self.timeout_stats.stuck.fetch_sub(1, Ordering::Relaxed);
+                        }
+                        match context.upgrade() {
+                            Some(context) => {
+                                states[tag] = State::Responded(Response::Regular(
+                                    Error::mux(Err(Error::new(EPIPE))),
+                                    0,
+                                ));
+                                callers.push(context);
+                            }
+                            None => {
+                                states.remove(tag);
+                            }
+                        }
+                    }
+                    State::Fmap(context) => {
+                        states.remove(tag);
+                        fmap_callers.extend(context.upgrade());
+                    }
+                    // Requests that are being set up see `unmounting` and fail by themselves.
+                    old_state @ (State::Placeholder | State::Responded(_)) => {
+                        states[tag] = old_state;
+                    }
+                }
+            }
+        }
+
+        for worker in self.workers.read().iter() {
+            worker.queued.lock().take_all();
+            worker.todo.inner.lock().clear();
+        }
+
+        for context in callers {
+            context.write(token.token()).unblock();
+        }
+        // Without a frame, the page fault of the caller fails.
+        for context in fmap_callers {
+            let mut context = context.write(token.token());
+            if let Status::HardBlocked {
+                reason: HardBlockedReason::AwaitingMmap { .. },
+            } = context.status
+            {
+                context.status = Status::Runnable;
+            }
+        }
+        // This is usually the scheme handler exiting, whose address space is already gone along
+        // with the borrowed memory.
+        if let Some(addr_space) = self.addr_space.upgrade() {
+            for span in callee_responsible {
+                let _ = addr_space.munmap(span, true);
+            }
+        }
+        for fd in to_close {
+            let _ = fd.try_close(token);
+        }
+    }
+
     fn next_id(&self) -> Result<u32> {
         let idx = {
             let mut states = self.states.lock();
@@ -296,25 +534,58 @@ impl UserInner {
     fn call_extended_inner(
         &self,
         fds: Option<Vec<Arc<RwLock<FileDescription>>>>,
-        sqe: Sqe,
+        mut sqe: Sqe,
         caller_responsible: &mut PageSpan,
         token: &mut CleanLockToken,
     ) -> Result<Response> {
         if self.unmounting.load(Ordering::SeqCst) {
+            self.states.lock().remove(sqe.tag as usize);
             return Err(Error::new(ENODEV));
         }
 
+        let worker = self.pick_worker();
+        let deadline = {
+            let (ens, io_priority) = {
+                let context = context::current();
+                let context = context.read(token.token());
+                (context.ens, context.io_priority)
+            };
+            fair_queue::set_priority(&mut sqe, io_priority);
+            let timeout = scheme::schemes(token.token()).call_timeout(ens);
+            timeout.map(|timeout| time::monotonic() + timeout)
+        };
+
         {
             let current_context = context::current();
-            current_context
-                .write(token.token())
-                .block("UserScheme::call");
+            {
+                let mut context = current_context.write(token.token());
+                context.block("UserScheme::call");
+                context.wake = deadline;
+            }
+            if let Some(deadline) = deadline {
+                timeout::register_wake(&current_context, deadline, token);
+            }
             {
                 let mut states = self.states.lock();
+                // The scheme handler may have closed its handle since the check above, after
+                // which `disconnect` no longer sees new requests.
+                if self.unmounting.load(Ordering::SeqCst) {
+                    states.remove(sqe.tag as usize);
+                    drop(states);
+                    {
+                        let mut context = current_context.write(token.token());
+                        context.unblock();
+                        context.wake = None;
+                    }
+                    timeout::cancel_wake(&current_context, token);
+                    return Err(Error::new(ENODEV));
+                }
                 states[sqe.tag as usize] = State::Waiting {
                     context: Arc::downgrade(&current_context),
+                    worker: Arc::downgrade(&worker),
                     fds,
-                    canceling: false,
+                    cancel: Cancel::None,
+                    submitted: time::monotonic(),
 
                     // This is the part that the scheme handler will deallocate when responding. It
                     // starts as empty, so the caller can unmap it (optimal for TLB), but is populated
@@ -323,13 +594,203 @@ impl UserInner {
                 };
             }
         }
-        self.todo.send(sqe, token);
+        self.submit(worker, sqe, token);
 
-        event::trigger(self.root_id, self.handle_id, EVENT_READ);
+        let result = self.wait_for_response(sqe.tag, deadline, caller_responsible, token);
+        if deadline.is_some() {
+            let current_context = context::current();
+            current_context.write(token.token()).wake = None;
+            timeout::cancel_wake(&current_context, token);
+        }
+        result
+    }
+
+    fn send_cancel(&self, tag: u32, worker: &Weak<Worker>, token: &mut CleanLockToken) {
+        let worker = worker.upgrade().unwrap_or_else(|| self.pick_worker());
+        self.submit(
+            worker,
+            Sqe {
+                opcode: Opcode::Cancel as u8,
+                sqe_flags: SqeFlags::ONEWAY,
+                tag,
+                ..Default::default()
+            },
+            token,
+        );
+    }
+
+    /// Choose the worker for a new request: the one bound to the CPU of the caller if any, and
+    /// otherwise the next unbound one in turn.
+    fn pick_worker(&self) -> Arc<Worker> {
+        let workers = self.workers.read();
+        let cpu = crate::cpu_id();
+        if let Some(worker) = workers.iter().find(|worker| worker.cpu == Some(cpu)) {
+            return worker.clone();
+        }
+
+        // The primary worker is never bound, so there is always an unbound worker.
+        let unbound = workers.iter().filter(|worker| worker.cpu.is_none()).count();
+        let n = self.next_worker.fetch_add(1, Ordering::Relaxed) % unbound;
+        workers
+            .iter()
+            .filter(|worker| worker.cpu.is_none())
+            .nth(n)
+            .expect("fewer unbound workers than counted")
+            .clone()
+    }
+
+    /// Queue a request to a worker, and deliver it to the scheme handler when it is its turn. If
+    /// the worker was closed in the meantime, another one is chosen.
+    fn submit(&self, mut worker: Arc<Worker>, sqe: Sqe, token: &mut CleanLockToken) {
+        let cancel = sqe.opcode == Opcode::Cancel as u8;
+        loop {
+            let mut queued = worker.queued.lock();
+            if worker.closed.load(Ordering::SeqCst) {
+                drop(queued);
+                worker = self.pick_worker();
+                continue;
+            }
+            if cancel {
+                // Cancelations are delivered right away, and the scheme handler must see a
+                // request before its cancelation, so a request still queued is delivered first.
+                let mut todo = worker.todo.inner.lock();
+                if let Some(request) = queued.remove(sqe.tag) {
+                    worker.deliver(&mut todo, request);
+                }
+                worker.deliver(&mut todo, sqe);
+            } else {
+                queued.push(sqe);
+            }
+            break;
+        }
+        self.dispatch(&worker, cancel, token);
+    }
+
+    /// Deliver the queued requests of a worker while fewer than `DISPATCH_WINDOW` are unread, and
+    /// notify the scheme handler if any were delivered, or if `notify` is set.
+    fn dispatch(&self, worker: &Worker, mut notify: bool, token: &mut CleanLockToken) {
+        {
+            let mut queued = worker.queued.lock();
+            let mut todo = worker.todo.inner.lock();
+            while worker.unread(&todo) < DISPATCH_WINDOW
+                && let Some(sqe) = queued.pop()
+            {
+                worker.deliver(&mut todo, sqe);
+                notify = true;
+            }
+        }
+        if notify {
+            worker.todo.condition.notify(token);
+            self.wake_ring(worker, token);
+            event::trigger(self.root_id, worker.handle_id, EVENT_READ);
+        }
+    }
+
+    /// Register another handle of the scheme handler to dispatch requests to, optionally only
+    /// those of callers running on `cpu`.
+    pub fn add_worker(&self, handle_id: usize, cpu: Option<LogicalCpuId>) -> Arc<Worker> {
+        let worker = Arc::new(Worker::new(handle_id, cpu));
+        self.workers.write().push(worker.clone());
+        worker
+    }
+
+    /// Stop dispatching requests to a worker whose handle was closed, and dispatch the requests
+    /// it had not read yet to the remaining workers.
+    pub fn remove_worker(&self, worker: &Arc<Worker>, token: &mut CleanLockToken) {
+        self.workers
+            .write()
+            .retain(|other| !Arc::ptr_eq(other, worker));
+
+        let mut requests = Vec::new();
+        {
+            let mut queued = worker.queued.lock();
+            let mut todo = worker.todo.inner.lock();
+            worker.closed.store(true, Ordering::SeqCst);
+            // Requests in the submission ring were delivered before the ones read from the
+            // handle, and those before the ones still queued.
+            if let Some(ring) = worker.ring.get() {
+                requests.extend(ring.take_sqes());
+            }
+            requests.extend(todo.drain(..));
+            requests.extend(queued.take_all());
+        }
+        for sqe in requests {
+            self.submit(self.pick_worker(), sqe, token);
+        }
+    }
+
+    /// Wake scheme handlers waiting on the submission ring of a worker with a futex.
+    fn wake_ring(&self, worker: &Worker, token: &mut CleanLockToken) {
+        if let Some(ring) = worker.ring.get() {
+            // Pairs with the scheme handler setting SQ_NEED_WAKEUP before checking the tail.
+            core::sync::atomic::fence(Ordering::SeqCst);
+            if ring.needs_wakeup() {
+                futex::wake_physaddr(ring.sq_tail_physaddr(), token);
+            }
+        }
+    }
 
+    /// Map the submission and completion rings of a worker into the address space of the scheme
+    /// handler.
+    pub fn map_ring(
+        &self,
+        worker: &Worker,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        // Legacy schemes use packets, which do not fit in the rings.
+        if !self.v2 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let ring = worker
+            .ring
+            .try_call_once(|| Ring::new().ok_or(Error::new(ENOMEM)))?;
+        ring.pages().map(addr_space, map, token)
+    }
+
+    /// Handle the responses the scheme handler wrote to the completion ring of a worker, returning
+    /// the first error if any of them were invalid.
+    fn reap_completions(&self, worker: &Worker, token: &mut CleanLockToken) -> Result<()> {
+        let Some(ring) = worker.ring.get() else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        while let Some(cqe) = ring.pop_cqe() {
+            if let Err(error) =
+                ParsedCqe::parse_cqe(&cqe).and_then(|p| self.handle_parsed(&p, token))
+            {
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+
+    /// Block the caller and sleep again, until `deadline` if there is one.
+    fn block_until(deadline: Option<u128>, token: &mut CleanLockToken) {
+        let current_context = context::current();
+        {
+            let mut context = current_context.write(token.token());
+            context.block("UserInner::call");
+            context.wake = deadline;
+        }
+        if let Some(deadline) = deadline {
+            timeout::register_wake(&current_context, deadline, token);
+        }
+    }
+
+    fn wait_for_response(
+        &self,
+        tag: u32,
+        mut deadline: Option<u128>,
+        caller_responsible: &mut PageSpan,
+        token: &mut CleanLockToken,
+    ) -> Result<Response> {
         loop {
             context::switch(token);
 
+            let timed_out = deadline.is_some_and(|deadline| time::monotonic() >= deadline);
+
             {
                 let mut eintr_if_sigkill = |callee_responsible: &mut PageSpan| {
                     // If SIGKILL was found without waiting for scheme, EINTR directly. In that
@@ -348,63 +809,102 @@ impl UserInner {
                 };
 
                 let mut states = self.states.lock();
-                match states.get_mut(sqe.tag as usize) {
+                match states.get_mut(tag as usize) {
                     // invalid state
                     None => return Err(Error::new(EBADFD)),
                     Some(o) => match mem::replace(o, State::Placeholder) {
+                        // the scheme did not respond to the cancelation in time
+                        State::Waiting {
+                            cancel: Cancel::Timeout,
+                            fds,
+                            worker,
+                            submitted,
+                            ..
+                        } if timed_out => {
+                            // The request is left to the scheme, which may still respond to it,
+                            // but the caller no longer waits. As with SIGKILL, the scheme must be
+                            // able to access the borrowed memory until it responds.
+                            *o = State::Waiting {
+                                cancel: Cancel::Abandoned,
+                                callee_responsible: core::mem::replace(
+                                    caller_responsible,
+                                    PageSpan::empty(),
+                                ),
+                                context: Weak::new(),
+                                worker,
+                                fds,
+                                submitted,
+                            };
+                            drop(states);
+
+                            self.timeout_stats.abandoned.fetch_add(1, Ordering::Relaxed);
+                            self.timeout_stats.stuck.fetch_add(1, Ordering::Relaxed);
+                            return Err(Error::new(ETIMEDOUT));
+                        }
                         // signal wakeup while awaiting cancelation
                         State::Waiting {
-                            canceling: true,
+                            cancel: cancel @ (Cancel::Signal | Cancel::Timeout | Cancel::Abandoned),
                             mut callee_responsible,
                             context,
+                            worker,
                             fds,
+                            submitted,
                         } => {
                             let maybe_eintr = eintr_if_sigkill(&mut callee_responsible);
+                            // If the deadline passed while a signal was being canceled, give
+                            // the scheme the same time to respond as for a timeout.
+                            let cancel = if timed_out {
+                                self.timeout_stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                                deadline = Some(time::monotonic() + CANCEL_GRACE);
+                                Cancel::Timeout
+                            } else {
+                                cancel
+                            };
                             *o = State::Waiting {
-                                canceling: true,
+                                cancel,
                                 callee_responsible,
                                 context,
+                                worker,
                                 fds,
+                                submitted,
                             };
                             drop(states);
                             maybe_eintr?;
 
-                            context::current()
-                                .write(token.token())
-                                .block("UserInner::call");
+                            Self::block_until(deadline, token);
                         }
-                        // spurious wakeup
+                        // spurious wakeup, or the deadline passed
                         State::Waiting {
-                            canceling: false,
+                            cancel: Cancel::None,
                             fds,
                             context,
+                            worker,
                             mut callee_responsible,
+                            submitted,
                         } => {
                             let maybe_eintr = eintr_if_sigkill(&mut callee_responsible);
+                            let cancel = if timed_out {
+                                self.timeout_stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                                deadline = Some(time::monotonic() + CANCEL_GRACE);
+                                Cancel::Timeout
+                            } else {
+                                Cancel::Signal
+                            };
                             *o = State::Waiting {
-                                canceling: true,
+                                cancel,
                                 fds,
                                 context,
+                                worker: worker.clone(),
                                 callee_responsible,
+                                submitted,
                             };
 
                             drop(states);
                             maybe_eintr?;
 
                             // TODO: Is this too dangerous when the states lock is held?
-                            self.todo.send(
-                                Sqe {
-                                    opcode: Opcode::Cancel as u8,
-                                    sqe_flags: SqeFlags::ONEWAY,
-                                    tag: sqe.tag,
-                                    ..Default::default()
-                                },
-                                token,
-                            );
-                            event::trigger(self.root_id, self.handle_id, EVENT_READ);
-                            context::current()
-                                .write(token.token())
-                                .block("UserInner::call");
+                            self.send_cancel(tag, &worker, token);
+                            Self::block_until(deadline, token);
                         }
 
                         // invalid state
@@ -414,7 +914,7 @@ impl UserInner {
                         }
 
                         State::Responded(response) => {
-                            states.remove(sqe.tag as usize);
+                            states.remove(tag as usize);
                             return Ok(response);
                         }
                     },
@@ -453,7 +953,10 @@ impl UserInner {
         if buf.len() > tail.buf().len() {
             return Err(Error::new(EINVAL));
         }
-        tail.buf_mut()[..buf.len()].copy_from_slice(buf);
+        let (to_copy, to_zero) = tail.buf_mut().split_at_mut(buf.len());
+        to_copy.copy_from_slice(buf);
+        // The whole page is mapped, so don't leak what the buffer was used for before.
+        to_zero.fill(0_u8);
 
         let is_pinned = true;
         let dst_page = {
@@ -491,6 +994,155 @@ impl UserInner {
             addrsp: Some(dst_addr_space),
         })
     }
+    /// Lend a kernel-owned frame to the scheme, mapping it without copying, as a buffer of `len`
+    /// bytes at the start of the page. The whole page is visible to the scheme, so it must not hold
+    /// anything else, and must not be modified by the kernel until the returned guard has been
+    /// released.
+    fn capture_frame(
+        &self,
+        frame: Frame,
+        len: usize,
+        writable: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<CaptureGuard<false, false>> {
+        if len > PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let dst_addr_space = {
+            Arc::clone(
+                self.context
+                    .upgrade()
+                    .ok_or(Error::new(ENODEV))?
+                    .read(token.token())
+                    .addr_space()?,
+            )
+        };
+
+        let mut map_flags = PROT_READ;
+        map_flags.set(MapFlags::PROT_WRITE, writable);
+
+        let is_pinned = true;
+        let dst_page = {
+            dst_addr_space.acquire_write().mmap_anywhere(
+                &dst_addr_space,
+                ONE,
+                map_flags,
+                |dst_page, flags, mapper, flusher| {
+                    Grant::allocated_shared_one_page(
+                        frame, dst_page, flags, mapper, flusher, is_pinned,
+                    )
+                },
+            )?
+        };
+
+        Ok(CaptureGuard {
+            base: dst_page.start_address().data(),
+            len,
+            destroyed: false,
+            head: CopyInfo {
+                src: None,
+                dst: None,
+            },
+            tail: CopyInfo {
+                src: None,
+                dst: None,
+            },
+            span: PageSpan::new(dst_page, 1),
+            addrsp: Some(dst_addr_space),
+        })
+    }
+    /// Write `len` bytes at `offset` within `frame` to `file`. The frame is lent to the scheme if
+    /// the bytes cover all of it, and otherwise they are copied, like the head and tail of a
+    /// captured user buffer, since the rest of the page may hold other data.
+    pub fn write_frame(
+        &self,
+        file: usize,
+        frame: Frame,
+        offset: usize,
+        len: usize,
+        pos: u64,
+        flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        if offset.saturating_add(len) > PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let mut address = if offset == 0 && len == PAGE_SIZE {
+            self.capture_frame(frame, len, false, token)?
+        } else {
+            let page =
+                unsafe { &*(RmmA::phys_to_virt(frame.base()).data() as *const [u8; PAGE_SIZE]) };
+            self.copy_and_capture_tail(&page[offset..][..len], token)?
+        };
+        let result = self.call(
+            Opcode::Write,
+            [
+                file as u64,
+                address.base() as u64,
+                address.len() as u64,
+                pos,
+                u64::from(flags),
+            ],
+            address.span(),
+            token,
+        );
+        address.release()?;
+        result
+    }
+    /// Read at most `len` bytes from `file` into the start of `frame`, letting the scheme write to
+    /// the frame directly. The frame must not hold anything the scheme may not see.
+    pub fn read_frame(
+        &self,
+        file: usize,
+        frame: Frame,
+        len: usize,
+        pos: u64,
+        flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let mut address = self.capture_frame(frame, len, true, token)?;
+        let result = self.call(
+            Opcode::Read,
+            [
+                file as u64,
+                address.base() as u64,
+                address.len() as u64,
+                pos,
+                u64::from(flags),
+            ],
+            address.span(),
+            token,
+        );
+        address.release()?;
+        result
+    }
+
+    /// Get the path of `file` into `buf`, lending the scheme a kernel page to write it to.
+    pub fn fpath_kernel(
+        &self,
+        file: usize,
+        buf: &mut [u8],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let mut page = BorrowedHtBuf::tail(token)?;
+        page.buf_mut().fill(0_u8);
+        let len = buf.len().min(PAGE_SIZE);
+
+        let mut address = self.capture_frame(page.frame(), len, true, token)?;
+        let result = self.call(
+            Opcode::Fpath,
+            [file, address.base(), address.len()],
+            address.span(),
+            token,
+        );
+        address.release()?;
+
+        let path_len = result?;
+        let count = path_len.min(len);
+        buf[..count].copy_from_slice(&page.buf()[..count]);
+        Ok(path_len)
+    }
 
     // TODO: Use an address space Arc over a context Arc. While contexts which share address spaces
     // still can access borrowed scheme pages, it would both be cleaner and would handle the case
@@ -736,7 +1388,27 @@ impl UserInner {
         })
     }
 
-    pub fn read(&self, buf: UserSliceWo, flags: u32, token: &mut CleanLockToken) -> Result<usize> {
+    pub fn read(
+        &self,
+        worker: &Worker,
+        buf: UserSliceWo,
+        flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        self.dispatch(worker, false, token);
+        let result = self.read_delivered(worker, buf, flags, token);
+        // Deliver the requests that were waiting for the ones that were read.
+        self.dispatch(worker, false, token);
+        result
+    }
+
+    fn read_delivered(
+        &self,
+        worker: &Worker,
+        buf: UserSliceWo,
+        flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
         // If O_NONBLOCK is used, do not block
         let nonblock = flags & O_NONBLOCK as u32 != 0;
 
@@ -744,7 +1416,8 @@ impl UserInner {
         let block = !(nonblock || self.unmounting.load(Ordering::SeqCst));
 
         if self.v2 {
-            match self
+            self.reap_completions(worker, token)?;
+            match worker
                 .todo
                 .receive_into_user(buf, block, "UserInner::read (v2)", token)
             {
@@ -760,10 +1433,11 @@ impl UserInner {
             let mut bytes_read = 0;
 
             for dst in buf.in_exact_chunks(size_of::<Packet>()) {
-                match self
-                    .todo
-                    .receive(block && bytes_read == 0, "UserInner::read (legacy)", token)
-                {
+                match worker.todo.receive(
+                    block && bytes_read == 0,
+                    "UserInner::read (legacy)",
+                    token,
+                ) {
                     Ok(sqe) => {
                         dst.copy_exactly(&self.translate_sqe_to_packet(&sqe)?)?;
                         bytes_read += size_of::<Packet>();
@@ -887,7 +1561,15 @@ impl UserInner {
         })
     }
 
-    pub fn write(&self, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usize> {
+    pub fn write(
+        &self,
+        worker: &Worker,
+        buf: UserSliceRo,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        // The scheme handler may have read requests from the submission ring.
+        self.dispatch(worker, false, token);
+
         let mut bytes_read = 0;
         if self.v2 {
             for chunk in buf.in_exact_chunks(size_of::<Cqe>()) {
@@ -925,6 +1607,10 @@ impl UserInner {
         let tag = self.next_id()?;
         {
             let mut states = self.states.lock();
+            if self.unmounting.load(Ordering::SeqCst) {
+                states.remove(tag as usize);
+                return Err(Error::new(ENODEV));
+            }
             states[tag as usize] = State::Fmap(Arc::downgrade(&context::current()));
         }
 
@@ -938,25 +1624,23 @@ impl UserInner {
             uid: offset as u32,
             gid: (offset >> 32) as u32,
         });*/
-        self.todo.send(
-            Sqe {
-                opcode: Opcode::RequestMmap as u8,
-                sqe_flags: SqeFlags::empty(),
-                _rsvd: 0,
-                tag,
-                args: [
-                    id as u64,
-                    flags.bits() as u64,
-                    required_page_count as u64,
-                    0,
-                    0,
-                    uid_gid_hack_merge(current_uid_gid(token)),
-                ],
-                caller: { context::current().read(token.token()).pid as u64 },
-            },
-            token,
-        );
-        event::trigger(self.root_id, self.handle_id, EVENT_READ);
+        let mut sqe = Sqe {
+            opcode: Opcode::RequestMmap as u8,
+            sqe_flags: SqeFlags::empty(),
+            _rsvd: 0,
+            tag,
+            args: [
+                id as u64,
+                flags.bits() as u64,
+                required_page_count as u64,
+                0,
+                0,
+                uid_gid_hack_merge(current_uid_gid(token)),
+            ],
+            caller: 0,
+        };
+        tag_caller(&mut sqe, token);
+        self.submit(self.pick_worker(), sqe, token);
 
         Ok(())
     }
@@ -1108,21 +1792,29 @@ impl UserInner {
                     State::Waiting {
                         context,
                         mut fds,
-                        canceling,
+                        cancel,
                         callee_responsible,
+                        submitted,
+                        ..
                     } => {
-                        // Convert ECANCELED to EINTR if a request was being canceled (currently always
-                        // due to signals).
+                        self.stats
+                            .record_latency(time::monotonic().saturating_sub(submitted));
+
+                        // Convert ECANCELED to the error of the reason the request was canceled.
                         if let Response::Regular(ref mut code, _) = response
-                            && canceling
+                            && cancel != Cancel::None
                             && *code == Error::mux(Err(Error::new(ECANCELED)))
                         {
-                            *code = Error::mux(Err(Error::new(EINTR)));
+                            *code = Error::mux(Err(Error::new(if cancel == Cancel::Signal {
+                                EINTR
+                            } else {
+                                ETIMEDOUT
+                            })));
                         }
 
                         // TODO: Require ECANCELED?
                         if let Response::Regular(ref mut code, _) = response
-                            && !canceling
+                            && cancel == Cancel::None
                             && *code == Error::mux(Err(Error::new(EINTR)))
                         {
                             // EINTR is valid after cancelation has been requested, but not otherwise.
@@ -1148,6 +1840,9 @@ impl UserInner {
                             }
                             _ => {
                                 states.remove(tag as usize);
+                                if cancel == Cancel::Abandoned {
+                                    self.timeout_stats.stuck.fetch_sub(1, Ordering::Relaxed);
+                                }
                             }
                         }
 
@@ -1166,18 +1861,26 @@ impl UserInner {
         Ok(())
     }
 
-    pub fn fevent(&self, flags: EventFlags) -> Result<EventFlags> {
+    pub fn fevent(&self, worker: &Worker, flags: EventFlags) -> Result<EventFlags> {
         // TODO: Should the root scheme also suppress events if `flags` does not contain
         // `EVENT_READ`?
-        Ok(if self.todo.is_currently_empty() {
-            EventFlags::empty()
-        } else {
-            EventFlags::EVENT_READ.intersection(flags)
-        })
+        let ring_empty = worker.ring.get().is_none_or(|ring| !ring.has_sqes());
+        // Queued requests are delivered when the scheme handler reads.
+        let queue_empty = worker.queued.lock().is_empty();
+        Ok(
+            if worker.todo.is_currently_empty() && ring_empty && queue_empty {
+                EventFlags::empty()
+            } else {
+                EventFlags::EVENT_READ.intersection(flags)
+            },
+        )
     }
 
-    pub fn fsync(&self) -> Result<()> {
-        Ok(())
+    /// The scheme handler calls fsync after writing responses to the completion ring of a worker.
+    pub fn fsync(&self, worker: &Worker, token: &mut CleanLockToken) -> Result<()> {
+        let result = self.reap_completions(worker, token);
+        self.dispatch(worker, false, token);
+        result
     }
 
     fn fmap_inner(
@@ -1222,7 +1925,7 @@ impl UserInner {
             let context_lock = context::current();
             let context = context_lock.read(token.token());
             let desc = context.files.read().find_by_scheme(self.scheme_id, file)?;
-            (context.pid, desc.description)
+            (context.caller_pid(), desc.description)
         };
 
         let response = self.call_extended_inner(
@@ -1451,7 +2154,7 @@ impl UserInner {
         Ok(num_fds)
     }
 
-    fn bulk_add_fds(
+    pub(crate) fn bulk_add_fds(
         descriptions: Vec<Arc<RwLock<FileDescription>>>,
         payload: UserSliceRw,
         token: &mut CleanLockToken,
@@ -1483,7 +2186,7 @@ impl UserInner {
         Ok(handles.len())
     }
 
-    fn bulk_insert_fds(
+    pub(crate) fn bulk_insert_fds(
         descriptions: Vec<Arc<RwLock<FileDescription>>>,
         payload: UserSliceRw,
         token: &mut CleanLockToken,
@@ -1836,26 +2539,29 @@ impl KernelScheme for UserScheme {
     }
 
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        // Files of a scheme whose handler is gone are dead, and closing them has nothing to do.
+        let Some(inner) = self.inner.upgrade() else {
+            return Ok(());
+        };
+        if inner.disconnected.load(Ordering::SeqCst) {
+            return Ok(());
+        }
         if !inner.supports_on_close {
             let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
             inner.call(Opcode::Close, [id], &mut PageSpan::empty(), token)?;
             return Ok(());
         }
 
-        inner.todo.send(
-            Sqe {
-                opcode: Opcode::CloseMsg as u8,
-                sqe_flags: SqeFlags::empty(),
-                _rsvd: 0,
-                tag: 0,
-                args: [id as u64, 0, 0, 0, 0, 0],
-                caller: 0, // TODO?
-            },
-            token,
-        );
-
-        event::trigger(inner.root_id, inner.handle_id, EVENT_READ);
+        let mut sqe = Sqe {
+            opcode: Opcode::CloseMsg as u8,
+            sqe_flags: SqeFlags::empty(),
+            _rsvd: 0,
+            tag: 0,
+            args: [id as u64, 0, 0, 0, 0, 0],
+            caller: 0,
+        };
+        tag_caller(&mut sqe, token);
+        inner.submit(inner.pick_worker(), sqe, token);
 
         Ok(())
     }
@@ -1903,6 +2609,15 @@ impl KernelScheme for UserScheme {
         address.release()?;
         result
     }
+    fn kfpath_kernel(
+        &self,
+        file: usize,
+        buf: &mut [u8],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.fpath_kernel(file, buf, token)
+    }
 
     fn kreadoff(
         &self,
@@ -2251,6 +2966,13 @@ impl<const N: usize> Args for [usize; N] {
 fn uid_gid_hack_merge([uid, gid]: [u32; 2]) -> u64 {
     u64::from(uid) | (u64::from(gid) << 32)
 }
+/// Tag a request with the pid and I/O priority of the current context.
+fn tag_caller(sqe: &mut Sqe, token: &mut CleanLockToken) {
+    let context = context::current();
+    let context = context.read(token.token());
+    sqe.caller = context.caller_pid() as u64;
+    fair_queue::set_priority(sqe, context.io_priority);
+}
 fn current_uid_gid(token: &mut CleanLockToken) -> [u32; 2] {
     let ctx = context::current();
     let p = &ctx.read(token.token());
@@ -0,0 +1,291 @@
+//! Per-context syscall filters, restricting a context to a set of syscalls and schemes.
+//!
+//! A filter is written as text to the `syscall-filter` handle of a context in `proc:`, one rule
+//! per line:
+//!
+//! - `default <action>`, for the syscalls no other rule matches, `allow` if not given.
+//! - `<syscall number> <action>`.
+//! - `scheme <name> <action>`, for `SYS_OPEN` and `SYS_OPENAT` of the scheme with that name in
+//!   the namespace of the context, taking precedence over the rules of these syscalls.
+//!
+//! The actions are `allow`, `deny` to fail with EPERM, `errno <n>` to fail with errno `n`, `kill`
+//! to kill the context, and `trace` to stop at a breakpoint of the tracer, which fails with ENOSYS
+//! if the context is not traced. Denials and kills are also reported in the kernel log.
+//!
+//! Filters can be added but never removed. When a context has several, the most restrictive action
+//! of any of them is taken, in the order `kill`, `deny`, `errno`, `trace` and `allow`. Contexts that
+//! a filtered context sets up before they first run, such as its forks and threads, inherit its
+//! filters, and exec keeps them.
+//!
+//! Operations submitted to an `ioring:` ring are checked against the filters its opener had, as
+//! the syscalls they correspond to. As they run in kernel workers, which cannot be killed or
+//! traced in place of the opener, `kill` and `trace` deny them instead.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{fmt, str};
+
+use crate::{
+    context::{self, context::HardBlockedReason, ContextLock, Status},
+    ptrace,
+    scheme::{self, SchemeId},
+    sync::CleanLockToken,
+    syscall::{
+        error::*,
+        exit_this_context,
+        flag::PTRACE_STOP_PRE_SYSCALL,
+        number::{SYS_OPEN, SYS_OPENAT},
+        ptrace_event,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Allow,
+    Trace,
+    Errno(u16),
+    Deny,
+    Kill,
+}
+
+impl Action {
+    fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let action = match words.next()? {
+            "allow" => Action::Allow,
+            "trace" => Action::Trace,
+            "errno" => Action::Errno(words.next()?.parse().ok().filter(|&errno| errno != 0)?),
+            "deny" => Action::Deny,
+            "kill" => Action::Kill,
+            _ => return None,
+        };
+        words.next().is_none().then_some(action)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Allow => f.write_str("allow"),
+            Action::Trace => f.write_str("trace"),
+            Action::Errno(errno) => write!(f, "errno {}", errno),
+            Action::Deny => f.write_str("deny"),
+            Action::Kill => f.write_str("kill"),
+        }
+    }
+}
+
+pub struct SyscallFilter {
+    default: Action,
+    syscalls: BTreeMap<usize, Action>,
+    schemes: BTreeMap<String, Action>,
+}
+
+impl SyscallFilter {
+    pub fn parse(text: &str) -> Result<SyscallFilter> {
+        let mut filter = SyscallFilter {
+            default: Action::Allow,
+            syscalls: BTreeMap::new(),
+            schemes: BTreeMap::new(),
+        };
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                Some("default") => {
+                    filter.default = Action::parse(words).ok_or(Error::new(EINVAL))?;
+                }
+                Some("scheme") => {
+                    let name = words.next().ok_or(Error::new(EINVAL))?;
+                    let action = Action::parse(words).ok_or(Error::new(EINVAL))?;
+                    filter.schemes.insert(name.into(), action);
+                }
+                Some(number) => {
+                    let number = number.parse().map_err(|_| Error::new(EINVAL))?;
+                    let action = Action::parse(words).ok_or(Error::new(EINVAL))?;
+                    filter.syscalls.insert(number, action);
+                }
+                None => unreachable!("empty lines are skipped"),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn without_stops(&self) -> SyscallFilter {
+        let map = |action| match action {
+            Action::Trace | Action::Kill => Action::Deny,
+            action => action,
+        };
+        SyscallFilter {
+            default: map(self.default),
+            syscalls: self.syscalls.iter().map(|(&n, &a)| (n, map(a))).collect(),
+            schemes: self
+                .schemes
+                .iter()
+                .map(|(n, &a)| (n.clone(), map(a)))
+                .collect(),
+        }
+    }
+
+    fn action(&self, number: usize, scheme: Option<&str>) -> Action {
+        scheme
+            .and_then(|scheme| self.schemes.get(scheme))
+            .or_else(|| self.syscalls.get(&number))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl fmt::Display for SyscallFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "default {}", self.default)?;
+        for (number, action) in self.syscalls.iter() {
+            writeln!(f, "{} {}", number, action)?;
+        }
+        for (name, action) in self.schemes.iter() {
+            writeln!(f, "scheme {} {}", name, action)?;
+        }
+        Ok(())
+    }
+}
+
+/// The filters of a context, shared with the contexts that inherit them.
+#[derive(Clone, Default)]
+pub struct Filters(Option<Arc<[Arc<SyscallFilter>]>>);
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<SyscallFilter>> {
+        self.0.iter().flat_map(|filters| filters.iter())
+    }
+
+    /// Add filters, skipping those that were already added.
+    pub fn extend(&mut self, filters: impl IntoIterator<Item = Arc<SyscallFilter>>) {
+        let mut all = self.iter().cloned().collect::<Vec<_>>();
+        for filter in filters {
+            if !all.iter().any(|other| Arc::ptr_eq(other, &filter)) {
+                all.push(filter);
+            }
+        }
+        self.0 = Some(all.into());
+    }
+
+    /// The same filters, denying the syscalls they would kill or trace, for contexts that run
+    /// syscalls on behalf of another.
+    pub fn without_stops(&self) -> Filters {
+        let mut filters = Filters::default();
+        filters.extend(self.iter().map(|filter| Arc::new(filter.without_stops())));
+        filters
+    }
+
+    fn action(&self, number: usize, scheme: Option<&str>) -> Action {
+        self.iter()
+            .map(|filter| filter.action(number, scheme))
+            .max()
+            .unwrap_or(Action::Allow)
+    }
+}
+
+fn filters(token: &mut CleanLockToken) -> Filters {
+    context::current()
+        .read(token.token())
+        .syscall_filters
+        .clone()
+}
+
+/// Check a syscall against the filters of the current context, before it is dispatched. The
+/// opening syscalls are only checked once their scheme is known, by `check_open` and
+/// `check_openat`.
+pub fn check_syscall(number: usize, token: &mut CleanLockToken) -> Result<()> {
+    if number == SYS_OPEN || number == SYS_OPENAT {
+        return Ok(());
+    }
+    let filters = filters(token);
+    if filters.is_empty() {
+        return Ok(());
+    }
+    apply(&filters, number, None, token)
+}
+
+/// Check `SYS_OPEN` of the scheme with a name against the filters of the current context.
+pub fn check_open(name: &str, token: &mut CleanLockToken) -> Result<()> {
+    let filters = filters(token);
+    if filters.is_empty() {
+        return Ok(());
+    }
+    apply(&filters, SYS_OPEN, Some(name), token)
+}
+
+/// Check `SYS_OPENAT`, or `SYS_DUP` with a path, relative to a file of a scheme against the
+/// filters of the current context, by the name of the scheme in the namespace of the context.
+pub fn check_openat(scheme_id: SchemeId, token: &mut CleanLockToken) -> Result<()> {
+    let filters = filters(token);
+    if filters.is_empty() {
+        return Ok(());
+    }
+
+    let ens = context::current().read(token.token()).ens;
+    let name = scheme::schemes(token.token())
+        .iter_name(ens)
+        .find(|&(_, &id)| id == scheme_id)
+        .map(|(name, _)| String::from(&**name));
+    apply(&filters, SYS_OPENAT, name.as_deref(), token)
+}
+
+fn apply(
+    filters: &Filters,
+    number: usize,
+    scheme: Option<&str>,
+    token: &mut CleanLockToken,
+) -> Result<()> {
+    let action = filters.action(number, scheme);
+    match action {
+        Action::Allow => Ok(()),
+        Action::Trace => ptrace::breakpoint_callback(
+            PTRACE_STOP_PRE_SYSCALL,
+            Some(ptrace_event!(PTRACE_STOP_PRE_SYSCALL, number)),
+            token,
+        )
+        .map(|_| ())
+        .ok_or(Error::new(ENOSYS)),
+        Action::Errno(errno) => Err(Error::new(errno.into())),
+        Action::Deny | Action::Kill => {
+            let (name, pid) = {
+                let context = context::current();
+                let context = context.read(token.token());
+                (context.name, context.pid)
+            };
+            warn!(
+                "syscall filter: {} (pid {}) syscall {} scheme {:?}: {}",
+                name,
+                pid,
+                number,
+                scheme.unwrap_or(""),
+                action,
+            );
+            if action == Action::Kill {
+                exit_this_context(None, token);
+            }
+            Err(Error::new(EPERM))
+        }
+    }
+}
+
+/// Let a context that has not run yet inherit the filters of the current context, which is
+/// setting it up.
+pub fn inherit(context: &Arc<ContextLock>, token: &mut CleanLockToken) {
+    let filters = filters(token);
+    if filters.is_empty() {
+        return;
+    }
+
+    let mut context = context.write(token.token());
+    if matches!(
+        context.status,
+        Status::HardBlocked {
+            reason: HardBlockedReason::NotYetStarted
+        }
+    ) {
+        context.syscall_filters.extend(filters.iter().cloned());
+    }
+}
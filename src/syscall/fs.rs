@@ -1,5 +1,5 @@
 //! Filesystem syscalls
-use core::{mem::size_of, num::NonZeroUsize};
+use core::{mem::size_of, num::NonZeroUsize, str};
 
 use alloc::{string::String, sync::Arc, vec::Vec};
 use redox_path::RedoxPath;
@@ -12,25 +12,33 @@ use crate::{
         memory::{AddrSpace, GenericFlusher, Grant, PageSpan, TlbShootdownActions},
     },
     paging::{Page, VirtualAddress, PAGE_SIZE},
-    scheme::{self, CallerCtx, FileHandle, KernelScheme, OpenResult, StrOrBytes},
+    scheme::{
+        self,
+        pipe::{self, F_GETPIPE_SZ, F_SETPIPE_SZ},
+        stats::SchemeOp,
+        CallerCtx, FileHandle, GlobalSchemes, KernelScheme, KernelSchemes, OpenResult, StrOrBytes,
+    },
     sync::CleanLockToken,
     syscall::{data::Stat, error::*, flag::*},
 };
 
-use super::usercopy::{UserSlice, UserSliceRo, UserSliceRw, UserSliceWo};
+use super::{
+    filter,
+    usercopy::{UserSlice, UserSliceRo, UserSliceRw, UserSliceWo},
+};
 
 pub fn file_op_generic<T>(
     fd: FileHandle,
     token: &mut CleanLockToken,
     op: impl FnOnce(&dyn KernelScheme, usize, &mut CleanLockToken) -> Result<T>,
 ) -> Result<T> {
-    file_op_generic_ext(fd, token, |s, _, desc, token| op(s, desc.number, token))
+    file_op_generic_ext(fd, token, |s, _, desc, token| op(&**s, desc.number, token))
 }
 pub fn file_op_generic_ext<T>(
     fd: FileHandle,
     token: &mut CleanLockToken,
     op: impl FnOnce(
-        &dyn KernelScheme,
+        &KernelSchemes,
         Arc<RwLock<FileDescription>>,
         FileDescription,
         &mut CleanLockToken,
@@ -47,10 +55,10 @@ pub fn file_op_generic_ext<T>(
 
     let scheme = scheme::schemes(token.token())
         .get(desc.scheme)
-        .ok_or(Error::new(EBADF))?
+        .ok_or(Error::new(ENODEV))?
         .clone();
 
-    op(&*scheme, file.description, desc, token)
+    op(&scheme, file.description, desc, token)
 }
 pub fn copy_path_to_buf(raw_path: UserSliceRo, max_len: usize) -> Result<String> {
     let mut path_buf = vec![0_u8; max_len];
@@ -63,7 +71,7 @@ pub fn copy_path_to_buf(raw_path: UserSliceRo, max_len: usize) -> Result<String>
     //core::str::from_utf8(&path_buf[..path_len]).map_err(|_| Error::new(EINVAL))
 }
 // TODO: Define elsewhere
-const PATH_MAX: usize = PAGE_SIZE;
+pub(super) const PATH_MAX: usize = PAGE_SIZE;
 
 #[inline]
 fn is_legacy(path_buf: &String) -> bool {
@@ -79,7 +87,7 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
     let (pid, uid, gid, scheme_ns) = {
         let ctx = context::current();
         let cx = &ctx.read(token.token());
-        (cx.pid, cx.euid, cx.egid, cx.ens)
+        (cx.caller_pid(), cx.euid, cx.egid, cx.ens)
     };
 
     // TODO: BorrowedHtBuf!
@@ -103,22 +111,22 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
 
+    filter::check_open(scheme_name.as_ref(), token)?;
+
     let description = {
-        let (scheme_id, scheme) = {
+        let (scheme_id, scheme, reference, bound) = {
             let schemes = scheme::schemes(token.token());
-            let (scheme_id, scheme) = schemes
-                .get_name(scheme_ns, scheme_name.as_ref())
+            let (scheme_id, scheme, reference) = schemes
+                .get_name_ref(scheme_ns, scheme_name.as_ref(), reference.as_ref())
                 .ok_or(Error::new(ENODEV))?;
-            (scheme_id, scheme.clone())
+            let bound = schemes.is_bound(scheme_ns, scheme_name.as_ref());
+            (scheme_id, scheme.clone(), reference, bound)
         };
 
-        match scheme.kopen(
-            reference.as_ref(),
-            flags,
-            CallerCtx { uid, gid, pid },
-            token,
-        )? {
-            OpenResult::SchemeLocal(number, internal_flags) => {
+        scheme.count(SchemeOp::Open);
+        match scheme.kopen(&reference, flags, CallerCtx { uid, gid, pid }, token)? {
+            OpenResult::SchemeLocal(number, mut internal_flags) => {
+                internal_flags.set(InternalFlags::BOUND, bound);
                 Arc::new(RwLock::new(FileDescription {
                     scheme: scheme_id,
                     number,
@@ -127,7 +135,8 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
                     internal_flags,
                 }))
             }
-            OpenResult::External(desc) => desc,
+            // Filesystem schemes redirect opens of FIFO nodes to the pipe scheme.
+            OpenResult::External(desc) => pipe::resolve_fifo(desc, flags, token)?,
         }
     };
     //drop(path_buf);
@@ -159,6 +168,16 @@ pub fn openat(
         .get_file(fh)
         .ok_or(Error::new(EBADF))?;
 
+    let (scheme_id, internal_flags) = {
+        let description = pipe.description.read();
+        (description.scheme, description.internal_flags)
+    };
+    // The scheme could resolve the path outside of the bound subpath.
+    if internal_flags.contains(InternalFlags::BOUND) {
+        return Err(Error::new(EACCES));
+    }
+    filter::check_openat(scheme_id, token)?;
+
     let description = pipe.description.read();
 
     let caller_ctx = context::current().read(token.token()).caller_ctx();
@@ -166,9 +185,10 @@ pub fn openat(
     let new_description = {
         let scheme = scheme::schemes(token.token())
             .get(description.scheme)
-            .ok_or(Error::new(EBADF))?
+            .ok_or(Error::new(ENODEV))?
             .clone();
 
+        scheme.count(SchemeOp::Open);
         let res = scheme.kopenat(
             description.number,
             StrOrBytes::from_str(&path_buf),
@@ -188,7 +208,7 @@ pub fn openat(
                     flags: description.flags,
                 }))
             }
-            OpenResult::External(desc) => desc,
+            OpenResult::External(desc) => pipe::resolve_fifo(desc, flags, token)?,
         }
     };
 
@@ -216,14 +236,14 @@ pub fn rmdir(raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
 
-    let scheme = {
+    let (scheme, reference) = {
         let schemes = scheme::schemes(token.token());
-        let (_scheme_id, scheme) = schemes
-            .get_name(scheme_ns, scheme_name.as_ref())
+        let (_scheme_id, scheme, reference) = schemes
+            .get_name_ref(scheme_ns, scheme_name.as_ref(), reference.as_ref())
             .ok_or(Error::new(ENODEV))?;
-        scheme.clone()
+        (scheme.clone(), reference)
     };
-    scheme.rmdir(reference.as_ref(), caller_ctx, token)
+    scheme.rmdir(&reference, caller_ctx, token)
 }
 
 /// Unlink syscall
@@ -241,14 +261,14 @@ pub fn unlink(raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
 
-    let scheme = {
+    let (scheme, reference) = {
         let schemes = scheme::schemes(token.token());
-        let (_scheme_id, scheme) = schemes
-            .get_name(scheme_ns, scheme_name.as_ref())
+        let (_scheme_id, scheme, reference) = schemes
+            .get_name_ref(scheme_ns, scheme_name.as_ref(), reference.as_ref())
             .ok_or(Error::new(ENODEV))?;
-        scheme.clone()
+        (scheme.clone(), reference)
     };
-    scheme.unlink(reference.as_ref(), caller_ctx, token)
+    scheme.unlink(&reference, caller_ctx, token)
 }
 
 /// Close syscall
@@ -283,11 +303,17 @@ fn duplicate_file(
         })
     } else {
         let description = { *file.description.read() };
+        // The scheme could resolve the path outside of the bound subpath.
+        if description.internal_flags.contains(InternalFlags::BOUND) {
+            return Err(Error::new(EACCES));
+        }
+        // Like openat, a dup with a path opens a new file of the scheme.
+        filter::check_openat(description.scheme, token)?;
 
         let new_description = {
             let scheme = scheme::schemes(token.token())
                 .get(description.scheme)
-                .ok_or(Error::new(EBADF))?
+                .ok_or(Error::new(ENODEV))?
                 .clone();
 
             match scheme.kdup(description.number, user_buf, caller_ctx, token)? {
@@ -311,6 +337,34 @@ fn duplicate_file(
     }
 }
 
+/// fpath syscall. Files opened through a name bound to a subpath of their scheme get paths
+/// under that name, without the subpath.
+pub fn fpath(fd: FileHandle, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
+    file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+        if !desc.internal_flags.contains(InternalFlags::BOUND) {
+            return scheme.kfpath(desc.number, buf, token);
+        }
+
+        // The path is only copied to the caller once the subpath has been removed from it.
+        let mut path = vec![0_u8; PAGE_SIZE];
+        let len = scheme.kfpath_kernel(desc.number, &mut path, token)?;
+        path.truncate(len);
+
+        let reference = str::from_utf8(&path)
+            .ok()
+            .and_then(|path| path.split_once(':'))
+            .map(|(_, reference)| reference);
+        let unbound = reference.and_then(|reference| {
+            let ens = context::current().read(token.token()).ens;
+            scheme::schemes(token.token()).unbind_ref(ens, desc.scheme, reference)
+        });
+        match unbound {
+            Some(path) => buf.copy_common_bytes_from_slice(path.as_bytes()),
+            None => buf.copy_common_bytes_from_slice(&path),
+        }
+    })
+}
+
 /// Duplicate file descriptor
 pub fn dup(fd: FileHandle, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<FileHandle> {
     let new_file = duplicate_file(fd, buf, token)?;
@@ -392,6 +446,7 @@ fn call_normal(
         .ok_or(Error::new(EBADFD))?
         .clone();
 
+    scheme.count(SchemeOp::Call);
     scheme.kcall(number, payload, flags, metadata, token)
 }
 
@@ -470,6 +525,7 @@ fn fdwrite_inner(
         CallFlags::empty()
     };
 
+    scheme.count(SchemeOp::Call);
     scheme.kfdwrite(number, descs_to_send, flags_to_scheme, arg, metadata, token)
 }
 
@@ -496,6 +552,7 @@ fn call_fdread(
         (scheme, number)
     };
 
+    scheme.count(SchemeOp::Call);
     scheme.kfdread(number, payload, flags, metadata, token)
 }
 
@@ -539,14 +596,23 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize, token: &mut CleanLockToken)
             .map(FileHandle::into);
     }
 
+    // Only pipes have a capacity, whatever other schemes return for these
+    if matches!(cmd, F_GETPIPE_SZ | F_SETPIPE_SZ)
+        && description.scheme != GlobalSchemes::Pipe.scheme_id()
+    {
+        return Err(Error::new(EBADF));
+    }
+
     // Communicate fcntl with scheme
-    if cmd != F_GETFD && cmd != F_SETFD {
+    let scheme_ret = if cmd != F_GETFD && cmd != F_SETFD {
         let scheme = scheme::schemes(token.token())
             .get(description.scheme)
-            .ok_or(Error::new(EBADF))?
+            .ok_or(Error::new(ENODEV))?
             .clone();
 
-        scheme.fcntl(description.number, cmd, arg, token)?;
+        scheme.fcntl(description.number, cmd, arg, token)?
+    } else {
+        0
     };
 
     // Perform kernel operation if scheme agrees
@@ -576,6 +642,8 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize, token: &mut CleanLockToken)
                     file.description.write().flags = new_flags;
                     Ok(0)
                 }
+                // Handled entirely by the scheme
+                F_GETPIPE_SZ | F_SETPIPE_SZ => Ok(scheme_ret),
                 _ => Err(Error::new(EINVAL)),
             },
             None => Err(Error::new(EBADF)),
@@ -602,12 +670,12 @@ pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken)
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
 
-    let (scheme_id, scheme) = {
+    let (scheme_id, scheme, reference) = {
         let schemes = scheme::schemes(token.token());
-        let (scheme_id, scheme) = schemes
-            .get_name(scheme_ns, scheme_name.as_ref())
+        let (scheme_id, scheme, reference) = schemes
+            .get_name_ref(scheme_ns, scheme_name.as_ref(), reference.as_ref())
             .ok_or(Error::new(ENODEV))?;
-        (scheme_id, scheme.clone())
+        (scheme_id, scheme.clone(), reference)
     };
 
     let description = file.description.read();
@@ -616,7 +684,7 @@ pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken)
         return Err(Error::new(EXDEV));
     }
 
-    scheme.flink(description.number, reference.as_ref(), caller_ctx, token)
+    scheme.flink(description.number, &reference, caller_ctx, token)
 }
 
 pub fn frename(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
@@ -638,12 +706,12 @@ pub fn frename(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
 
-    let (scheme_id, scheme) = {
+    let (scheme_id, scheme, reference) = {
         let schemes = scheme::schemes(token.token());
-        let (scheme_id, scheme) = schemes
-            .get_name(scheme_ns, scheme_name.as_ref())
+        let (scheme_id, scheme, reference) = schemes
+            .get_name_ref(scheme_ns, scheme_name.as_ref(), reference.as_ref())
             .ok_or(Error::new(ENODEV))?;
-        (scheme_id, scheme.clone())
+        (scheme_id, scheme.clone(), reference)
     };
 
     let description = file.description.read();
@@ -652,7 +720,7 @@ pub fn frename(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken
         return Err(Error::new(EXDEV));
     }
 
-    scheme.frename(description.number, reference.as_ref(), caller_ctx, token)
+    scheme.frename(description.number, &reference, caller_ctx, token)
 }
 
 /// File status
@@ -837,6 +905,7 @@ pub fn sys_read(fd: FileHandle, buf: UserSliceWo, token: &mut CleanLockToken) ->
             } else {
                 u64::MAX
             };
+            scheme.count(SchemeOp::Read);
             Ok((
                 scheme.kreadoff(desc.number, buf, offset, desc.flags, desc.flags, token)?,
                 desc_arc,
@@ -857,6 +926,7 @@ pub fn sys_write(fd: FileHandle, buf: UserSliceRo, token: &mut CleanLockToken) -
             } else {
                 u64::MAX
             };
+            scheme.count(SchemeOp::Write);
             Ok((
                 scheme.kwriteoff(desc.number, buf, offset, desc.flags, desc.flags, token)?,
                 desc_arc,
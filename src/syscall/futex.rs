@@ -15,7 +15,7 @@ use crate::{
     context::{
         self,
         memory::{AddrSpace, AddrSpaceWrapper},
-        ContextLock,
+        timeout, ContextLock,
     },
     memory::PhysicalAddress,
     paging::{Page, VirtualAddress},
@@ -92,6 +92,9 @@ pub fn futex(
                 .none_if_null()
                 .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
                 .transpose()?;
+            let wake_opt = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| {
+                tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
+            });
 
             {
                 let mut futexes = FUTEXES.lock(token.token());
@@ -143,9 +146,7 @@ pub fn futex(
                 {
                     let mut context = context_lock.write(token.token());
 
-                    context.wake = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| {
-                        tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
-                    });
+                    context.wake = wake_opt;
                     if let Some((tctl, pctl, _)) = context.sigcontrol() {
                         if tctl.currently_pending_unblocked(pctl) != 0 {
                             return Err(Error::new(EINTR));
@@ -167,10 +168,16 @@ pub fn futex(
 
             drop(addr_space_guard);
 
+            if let Some(wake) = wake_opt {
+                timeout::register_wake(&context::current(), wake, token);
+            }
+
             context::switch(token);
 
             if timeout_opt.is_some() {
-                context::current().write(token.token()).wake = None;
+                let current_context = context::current();
+                current_context.write(token.token()).wake = None;
+                timeout::cancel_wake(&current_context, token);
                 Err(Error::new(ETIMEDOUT))
             } else {
                 Ok(0)
@@ -214,3 +221,18 @@ pub fn futex(
         _ => Err(Error::new(EINVAL)),
     }
 }
+
+/// Wake every context waiting on a futex in shared memory, from the kernel. Unlike `FUTEX_WAKE`,
+/// the waiters are only matched by physical address, since the kernel has no mapping of its own.
+pub fn wake_physaddr(target_physaddr: PhysicalAddress, token: &mut CleanLockToken) -> usize {
+    let mut futexes_map = FUTEXES.lock(token.token());
+    let (futexes_map, mut token) = futexes_map.token_split();
+
+    let Some(futexes) = futexes_map.remove(&target_physaddr) else {
+        return 0;
+    };
+    for futex in futexes.iter() {
+        futex.context_lock.write(token.token()).unblock();
+    }
+    futexes.len()
+}
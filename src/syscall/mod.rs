@@ -26,7 +26,7 @@ use self::{
 use crate::{
     context::memory::AddrSpace,
     percpu::PercpuBlock,
-    scheme::{memory::MemoryScheme, FileHandle},
+    scheme::{memory::MemoryScheme, stats::SchemeOp, FileHandle},
     sync::CleanLockToken,
 };
 
@@ -39,6 +39,9 @@ pub mod fs;
 /// Fast userspace mutex
 pub mod futex;
 
+/// Per-context syscall filters
+pub mod filter;
+
 /// Privilege syscalls
 pub mod privilege;
 
@@ -87,6 +90,7 @@ pub fn syscall(
                             .ok_or(Error::new(EINVAL))?,
                     )
                 };
+                scheme.count(SchemeOp::Write);
                 scheme.kwriteoff(
                     desc.number,
                     UserSlice::ro(c, d)?,
@@ -103,8 +107,9 @@ pub fn syscall(
                 if b == !0 {
                     MemoryScheme::fmap_anonymous(&addrspace, &map, false, token)
                 } else {
-                    file_op_generic(fd, token, |scheme, number, token| {
-                        scheme.kfmap(number, &addrspace, &map, false, token)
+                    file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+                        scheme.count(SchemeOp::Fmap);
+                        scheme.kfmap(desc.number, &addrspace, &map, false, token)
                     })
                 }
             }
@@ -135,6 +140,7 @@ pub fn syscall(
                             .ok_or(Error::new(EINVAL))?,
                     )
                 };
+                scheme.count(SchemeOp::Read);
                 scheme.kreadoff(
                     desc.number,
                     UserSlice::wo(c, d)?,
@@ -145,9 +151,7 @@ pub fn syscall(
                 )
             }),
             SYS_READ => sys_read(fd, UserSlice::wo(c, d)?, token),
-            SYS_FPATH => file_op_generic(fd, token, |scheme, number, token| {
-                scheme.kfpath(number, UserSlice::wo(c, d)?, token)
-            }),
+            SYS_FPATH => fpath(fd, UserSlice::wo(c, d)?, token),
             SYS_FSTAT => fstat(fd, UserSlice::wo(c, d)?, token).map(|()| 0),
             SYS_FSTATVFS => file_op_generic(fd, token, |scheme, number, token| {
                 scheme
@@ -217,9 +221,12 @@ pub fn syscall(
                 token,
             )
             .map(|()| 0),
-            SYS_CLOCK_GETTIME => {
-                clock_gettime(b, UserSlice::wo(c, core::mem::size_of::<TimeSpec>())?).map(|()| 0)
-            }
+            SYS_CLOCK_GETTIME => clock_gettime(
+                b,
+                UserSlice::wo(c, core::mem::size_of::<TimeSpec>())?,
+                token,
+            )
+            .map(|()| 0),
             SYS_FUTEX => futex(b, c, d, e, f, token),
 
             SYS_MPROTECT => mprotect(b, c, MapFlags::from_bits_truncate(d)).map(|()| 0),
@@ -241,7 +248,7 @@ pub fn syscall(
 
     debug_start([a, b, c, d, e, f], token);
 
-    let result = inner(a, b, c, d, e, f, token);
+    let result = filter::check_syscall(a, token).and_then(|()| inner(a, b, c, d, e, f, token));
 
     debug_end([a, b, c, d, e, f], result, token);
 
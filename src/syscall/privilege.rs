@@ -4,9 +4,11 @@ use crate::{context, scheme, sync::CleanLockToken, syscall::error::*};
 
 use super::{
     copy_path_to_buf,
+    fs::PATH_MAX,
     usercopy::{UserSlice, UserSliceRo},
 };
 
+/// Create a namespace from the schemes of the current one, as described by `SchemeList::make_ns`.
 pub fn mkns(mut user_buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usize> {
     let (uid, from) = {
         let ctx = context::current();
@@ -20,6 +22,7 @@ pub fn mkns(mut user_buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usi
     }
 
     let mut names = Vec::with_capacity(user_buf.len() / core::mem::size_of::<[usize; 2]>());
+    let mut empty = false;
 
     while let Some((current_name_ptr_buf, next_part)) =
         user_buf.split_at(core::mem::size_of::<[usize; 2]>())
@@ -30,14 +33,17 @@ pub fn mkns(mut user_buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usi
 
         let raw_path = UserSlice::new(ptr, len)?;
 
-        // TODO: Max scheme size limit?
-        let max_len = 256;
-
-        names.push(copy_path_to_buf(raw_path, max_len)?.into_boxed_str());
+        // An empty entry creates the namespace without the schemes every namespace has, so that
+        // it only contains the other entries.
+        if raw_path.is_empty() {
+            empty = true;
+        } else {
+            names.push(copy_path_to_buf(raw_path, PATH_MAX)?.into_boxed_str());
+        }
 
         user_buf = next_part;
     }
 
-    let to = scheme::schemes_mut(token.token()).make_ns(from, names)?;
+    let to = scheme::schemes_mut(token.token()).make_ns(from, empty, names)?;
     Ok(to.into())
 }
@@ -11,7 +11,7 @@ use crate::{
         ContextRef,
     },
     event,
-    scheme::GlobalSchemes,
+    scheme::{cap, GlobalSchemes},
     sync::CleanLockToken,
     syscall::EventFlags,
 };
@@ -126,6 +126,8 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap, token: &mut CleanLockTok
     debug!("Bootstrap entry point: {:X}", bootstrap_entry);
     assert_ne!(bootstrap_entry, 0);
 
+    cap::bootstrap(token);
+
     // Start in a minimal environment without any stack.
 
     let ctx = context::current();
@@ -1,22 +1,14 @@
 use crate::{
-    context,
+    context::{self, timeout},
     sync::CleanLockToken,
-    syscall::{
-        data::TimeSpec,
-        error::*,
-        flag::{CLOCK_MONOTONIC, CLOCK_REALTIME},
-    },
+    syscall::{data::TimeSpec, error::*},
     time,
 };
 
 use super::usercopy::{UserSliceRo, UserSliceWo};
 
-pub fn clock_gettime(clock: usize, buf: UserSliceWo) -> Result<()> {
-    let arch_time = match clock {
-        CLOCK_REALTIME => time::realtime(),
-        CLOCK_MONOTONIC => time::monotonic(),
-        _ => return Err(Error::new(EINVAL)),
-    };
+pub fn clock_gettime(clock: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+    let arch_time = time::Clock::current(clock, token)?.now(token)?;
 
     buf.copy_exactly(&TimeSpec {
         tv_sec: (arch_time / time::NANOS_PER_SEC) as i64,
@@ -48,12 +40,14 @@ pub fn nanosleep(
         context.wake = Some(end);
         context.block("nanosleep");
     }
+    timeout::register_wake(&current_context, end, token);
 
     // TODO: The previous wakeup reason was most likely signals, but is there any other possible
     // reason?
     context::switch(token);
 
     let was_interrupted = current_context.write(token.token()).wake.take().is_some();
+    timeout::cancel_wake(&current_context, token);
 
     if let Some(rem_buf) = rem_buf_opt {
         let current = time::monotonic();
@@ -207,6 +207,18 @@ impl UserSliceWo {
     pub fn wo(base: usize, size: usize) -> Result<Self> {
         Self::new(base, size)
     }
+    /// A slice of a kernel buffer, for output that userspace must not see as it is written.
+    ///
+    /// # Safety
+    ///
+    /// The slice must not outlive `buf`, and must not be passed to user schemes, which would try
+    /// to map it.
+    pub unsafe fn kernel(buf: &mut [u8]) -> Self {
+        Self {
+            base: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+        }
+    }
 }
 impl UserSliceRw {
     pub fn rw(base: usize, size: usize) -> Result<Self> {
@@ -1,12 +1,34 @@
-use spin::Mutex;
+use alloc::sync::{Arc, Weak};
+use core::{
+    num::NonZeroUsize,
+    str,
+    sync::atomic::{fence, AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering},
+};
+use spin::{Mutex, Once};
 
 use crate::{
+    context::{self, timeout, Context, ContextLock},
+    memory::{init_frame, Frame, RefCount},
+    paging::{RmmA, RmmArch, PAGE_SIZE},
     sync::CleanLockToken,
-    syscall::error::{Error, Result, EINVAL},
+    syscall::{
+        error::{Error, Result, EINVAL, ESRCH},
+        flag::{CLOCK_MONOTONIC, CLOCK_REALTIME},
+    },
 };
 
 pub const NANOS_PER_SEC: u128 = 1_000_000_000;
 
+// Clocks not defined by the syscall crate, numbered like Linux where that does not collide.
+/// CPU time consumed by all threads of the calling process
+pub const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+/// CPU time consumed by the calling thread
+pub const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+/// Time since boot, including time spent suspended
+pub const CLOCK_BOOTTIME: usize = 7;
+/// Monotonic time read directly from the hardware clock, without any adjustments
+pub const CLOCK_MONOTONIC_RAW: usize = 8;
+
 // TODO: seqlock?
 /// Kernel start time, measured in nanoseconds since Unix epoch
 pub static START: Mutex<u128> = Mutex::new(0);
@@ -14,15 +36,492 @@ pub static START: Mutex<u128> = Mutex::new(0);
 pub static OFFSET: Mutex<u128> = Mutex::new(0);
 
 pub fn monotonic() -> u128 {
-    crate::arch::time::monotonic_absolute()
+    crate::clocksource::read().unwrap_or_else(crate::arch::time::monotonic_absolute)
 }
 
 pub fn realtime() -> u128 {
-    *START.lock() + monotonic()
+    realtime_at(monotonic())
+}
+
+/// The realtime clock at the given monotonic time, including the adjustments made through
+/// `sys:adjtime`.
+pub fn realtime_at(mono: u128) -> u128 {
+    let correction = ADJUSTMENT.lock().correction_at(mono);
+    (*START.lock() as i128 + mono as i128 + correction).max(0) as u128
+}
+
+/// The current difference between the realtime and monotonic clocks.
+pub fn realtime_offset() -> u128 {
+    let mono = monotonic();
+    realtime_at(mono).saturating_sub(mono)
+}
+
+/// Largest frequency correction, in parts per billion.
+pub const MAX_FREQ_PPB: i64 = 500_000;
+/// Rate at which offsets are slewed, in parts per billion.
+const SLEW_RATE_PPB: i64 = 500_000;
+/// Largest offset that can be slewed rather than stepped, in nanoseconds.
+pub const MAX_SLEW_NS: i64 = 500_000_000;
+/// Rate at which the maximum error grows, in parts per billion.
+const MAX_ERROR_RATE_PPB: u128 = 500_000;
+
+/// Adjustments of the realtime clock made by a time synchronization daemon, on top of
+/// `START + monotonic`.
+struct Adjustment {
+    /// Monotonic time at which `offset` and `slew_remaining` were last updated.
+    base: u128,
+    /// Correction of the realtime clock at `base`, in nanoseconds.
+    offset: i128,
+    /// Frequency correction, in parts per billion.
+    freq_ppb: i64,
+    /// Offset that remains to be slewed at `base`, in nanoseconds.
+    slew_remaining: i64,
+    /// Incremented whenever a slew starts, so that timers for earlier slews are ignored.
+    slew_generation: u128,
+    /// Maximum and estimated error in nanoseconds, and the monotonic time they were set.
+    max_error: u64,
+    est_error: u64,
+    error_time: u128,
+    /// Status flags, as set by the daemon.
+    status: u64,
+}
+
+impl Adjustment {
+    /// The part of the remaining slew applied after `elapsed` nanoseconds.
+    fn slewed(&self, elapsed: u128) -> i64 {
+        let max = (elapsed * SLEW_RATE_PPB as u128 / NANOS_PER_SEC).min(i64::MAX as u128) as i64;
+        self.slew_remaining.clamp(-max, max)
+    }
+
+    fn correction_at(&self, mono: u128) -> i128 {
+        let elapsed = mono.saturating_sub(self.base);
+        let freq = elapsed as i128 * self.freq_ppb as i128 / NANOS_PER_SEC as i128;
+        self.offset + freq + self.slewed(elapsed) as i128
+    }
+
+    /// Apply the corrections up to `mono`, so that the parameters can be changed from then on.
+    fn fold(&mut self, mono: u128) {
+        let elapsed = mono.saturating_sub(self.base);
+        let slewed = self.slewed(elapsed);
+        self.offset = self.correction_at(mono);
+        self.slew_remaining -= slewed;
+        self.base = mono;
+    }
+
+    /// Current rate of the realtime clock relative to the monotonic clock, minus one, in parts
+    /// per billion.
+    fn rate_ppb(&self) -> i64 {
+        self.freq_ppb + self.slew_remaining.signum() * SLEW_RATE_PPB
+    }
+}
+
+static ADJUSTMENT: Mutex<Adjustment> = Mutex::new(Adjustment {
+    base: 0,
+    offset: 0,
+    freq_ppb: 0,
+    slew_remaining: 0,
+    slew_generation: 0,
+    max_error: 0,
+    est_error: 0,
+    error_time: 0,
+    status: 0,
+});
+
+/// Called when a slew should have finished, to update the time page with the new rate.
+fn slew_finished(_: usize, generation: u128, _token: &mut CleanLockToken) {
+    {
+        let mut adjustment = ADJUSTMENT.lock();
+        if adjustment.slew_generation != generation {
+            return;
+        }
+        adjustment.fold(monotonic());
+        adjustment.slew_remaining = 0;
+    }
+    update_time_page();
+}
+
+/// Adjust the realtime clock, for time synchronization daemons. Takes whitespace-separated
+/// `key=value` pairs, where values are integers:
+///
+/// - `offset`: slew the clock by this many nanoseconds, at 500 ppm, replacing any slew in
+///   progress; at most 0.5 s in either direction
+/// - `step`: step the clock by this many nanoseconds, cancelling `CLOCK_REALTIME` timers
+/// - `freq`: set the frequency correction, in parts per billion, at most 500 ppm
+/// - `maxerror`, `esterror`: set the maximum and estimated error, in nanoseconds
+/// - `status`: set the status flags
+///
+/// The state can be read back from `sys:timex`.
+pub fn sys_adjtime(buf: &[u8], token: &mut CleanLockToken) -> Result<usize> {
+    let args = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    // Parse everything before applying anything.
+    let mut params = [None; 6];
+    const KEYS: [&str; 6] = ["offset", "step", "freq", "maxerror", "esterror", "status"];
+    for arg in args.split_ascii_whitespace() {
+        let (key, value) = arg.split_once('=').ok_or(Error::new(EINVAL))?;
+        let index = KEYS
+            .iter()
+            .position(|&k| k == key)
+            .ok_or(Error::new(EINVAL))?;
+        params[index] = Some(value.parse::<i64>().map_err(|_| Error::new(EINVAL))?);
+    }
+    let [offset, step, freq, max_error, est_error, status] = params;
+
+    if offset.is_some_and(|offset| !(-MAX_SLEW_NS..=MAX_SLEW_NS).contains(&offset))
+        || freq.is_some_and(|freq| !(-MAX_FREQ_PPB..=MAX_FREQ_PPB).contains(&freq))
+        || [max_error, est_error, status]
+            .iter()
+            .any(|value| value.is_some_and(|value| value < 0))
+    {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mono = monotonic();
+    let slew_end = {
+        let mut adjustment = ADJUSTMENT.lock();
+        adjustment.fold(mono);
+
+        if let Some(step) = step {
+            adjustment.offset += step as i128;
+        }
+        if let Some(freq) = freq {
+            adjustment.freq_ppb = freq;
+        }
+        if let Some(max_error) = max_error {
+            adjustment.max_error = max_error as u64;
+            adjustment.error_time = mono;
+        }
+        if let Some(est_error) = est_error {
+            adjustment.est_error = est_error as u64;
+        }
+        if let Some(status) = status {
+            adjustment.status = status as u64;
+        }
+
+        offset.map(|offset| {
+            adjustment.slew_remaining = offset;
+            adjustment.slew_generation += 1;
+            let duration = offset.unsigned_abs() as u128 * NANOS_PER_SEC / SLEW_RATE_PPB as u128;
+            (mono + duration, adjustment.slew_generation)
+        })
+    };
+
+    update_time_page();
+
+    if let Some((end, generation)) = slew_end {
+        timeout::register_callback(CLOCK_MONOTONIC, end, slew_finished, (0, generation), token);
+    }
+    if step.is_some() {
+        crate::scheme::time::realtime_clock_set(token);
+    }
+
+    Ok(buf.len())
+}
+
+/// State of the realtime clock adjustments, as reported in `sys:timex`.
+pub struct AdjustmentStatus {
+    /// Offset that remains to be slewed, in nanoseconds.
+    pub offset: i64,
+    pub freq_ppb: i64,
+    /// Current rate of the realtime clock relative to the monotonic clock, in parts per billion.
+    pub rate_ppb: i64,
+    /// Maximum error, grown at 500 ppm since it was set.
+    pub max_error: u64,
+    pub est_error: u64,
+    pub status: u64,
+}
+
+pub fn adjustment_status() -> AdjustmentStatus {
+    let mono = monotonic();
+    let adjustment = ADJUSTMENT.lock();
+    let elapsed = mono.saturating_sub(adjustment.base);
+    let error_growth =
+        mono.saturating_sub(adjustment.error_time) * MAX_ERROR_RATE_PPB / NANOS_PER_SEC;
+
+    AdjustmentStatus {
+        offset: adjustment.slew_remaining - adjustment.slewed(elapsed),
+        freq_ppb: adjustment.freq_ppb,
+        rate_ppb: adjustment.rate_ppb(),
+        max_error: adjustment
+            .max_error
+            .saturating_add(error_growth.min(u64::MAX as u128) as u64),
+        est_error: adjustment.est_error,
+        status: adjustment.status,
+    }
+}
+
+pub fn monotonic_raw() -> u128 {
+    // Adjustments only apply to the realtime clock, the monotonic clock is read as is.
+    monotonic()
+}
+
+pub fn boottime() -> u128 {
+    // The kernel does not suspend, so this is the same as the monotonic clock.
+    monotonic()
 }
 
-pub fn sys_update_time_offset(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
+/// CPU time consumed by a context, including the current time slice if it is running.
+pub fn context_cpu_time(context: &Context) -> u128 {
+    if context.running {
+        context.cpu_time + monotonic().saturating_sub(context.switch_time)
+    } else {
+        context.cpu_time
+    }
+}
+
+/// A clock as seen by a particular context, which determines whose CPU time is measured.
+#[derive(Clone)]
+pub enum Clock {
+    Realtime,
+    Monotonic,
+    MonotonicRaw,
+    Boottime,
+    ThreadCpuTime(Weak<ContextLock>),
+    ProcessCpuTime(NonZeroUsize),
+}
+
+impl Clock {
+    /// Look up a clock by id, for the current context.
+    pub fn current(id: usize, token: &mut CleanLockToken) -> Result<Self> {
+        Ok(match id {
+            CLOCK_REALTIME => Clock::Realtime,
+            CLOCK_MONOTONIC => Clock::Monotonic,
+            CLOCK_MONOTONIC_RAW => Clock::MonotonicRaw,
+            CLOCK_BOOTTIME => Clock::Boottime,
+            CLOCK_THREAD_CPUTIME_ID | CLOCK_PROCESS_CPUTIME_ID => {
+                let current = context::current();
+                let owner_proc_id = current.read(token.token()).owner_proc_id;
+                match owner_proc_id {
+                    Some(owner_proc_id) if id == CLOCK_PROCESS_CPUTIME_ID => {
+                        Clock::ProcessCpuTime(owner_proc_id)
+                    }
+                    // Contexts without a process only have their own CPU time.
+                    _ => Clock::ThreadCpuTime(Arc::downgrade(&current)),
+                }
+            }
+            _ => return Err(Error::new(EINVAL)),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        match self {
+            Clock::Realtime => CLOCK_REALTIME,
+            Clock::Monotonic => CLOCK_MONOTONIC,
+            Clock::MonotonicRaw => CLOCK_MONOTONIC_RAW,
+            Clock::Boottime => CLOCK_BOOTTIME,
+            Clock::ThreadCpuTime(_) => CLOCK_THREAD_CPUTIME_ID,
+            Clock::ProcessCpuTime(_) => CLOCK_PROCESS_CPUTIME_ID,
+        }
+    }
+
+    /// Whether the clock only advances while a thread is running.
+    pub fn is_cpu_time(&self) -> bool {
+        matches!(self, Clock::ThreadCpuTime(_) | Clock::ProcessCpuTime(_))
+    }
+
+    /// Read the clock, in nanoseconds. Fails with `ESRCH` if the thread or process it measures
+    /// no longer exists.
+    pub fn now(&self, token: &mut CleanLockToken) -> Result<u128> {
+        Ok(match self {
+            Clock::Realtime => realtime(),
+            Clock::Monotonic => monotonic(),
+            Clock::MonotonicRaw => monotonic_raw(),
+            Clock::Boottime => boottime(),
+            Clock::ThreadCpuTime(context) => {
+                let context = context.upgrade().ok_or(Error::new(ESRCH))?;
+                let context = context.read(token.token());
+                context_cpu_time(&context)
+            }
+            Clock::ProcessCpuTime(owner_proc_id) => {
+                let mut contexts = context::contexts(token.token());
+                let (contexts, mut token) = contexts.token_split();
+
+                let mut found = false;
+                let mut total = 0;
+                for context_ref in contexts.iter().filter_map(|r| r.upgrade()) {
+                    let context = context_ref.read(token.token());
+                    if context.owner_proc_id == Some(*owner_proc_id) {
+                        found = true;
+                        total += context_cpu_time(&context);
+                    }
+                }
+                if !found {
+                    return Err(Error::new(ESRCH));
+                }
+                total
+            }
+        })
+    }
+}
+
+/// Address at which the time page is mapped, read-only, into every address space.
+pub const TIME_PAGE_ADDRESS: usize = crate::USER_END_OFFSET - PAGE_SIZE;
+
+/// The time page lets userspace read `CLOCK_MONOTONIC` and `CLOCK_REALTIME` without a syscall.
+///
+/// If `counter_usable` is zero, the hardware counter cannot be read from userspace and
+/// `clock_gettime` must be used instead. Otherwise, the monotonic time in nanoseconds is
+/// `monotonic_base + ((counter - counter_base) * counter_mult) >> counter_shift`, computed in
+/// 128 bits, where `counter` is `rdtsc` on x86, `rdtime` on RISC-V and `cntpct_el0` on AArch64.
+/// The realtime clock is
+/// `monotonic + realtime_offset + (monotonic - realtime_base) * realtime_rate / 10^9`, with
+/// `realtime_rate` being signed.
+///
+/// The fields must be read between two reads of `seq`, retrying if they differ or are odd.
+#[repr(C)]
+pub struct TimePage {
+    pub seq: AtomicU32,
+    pub counter_usable: AtomicU32,
+    pub counter_shift: AtomicU32,
+    _reserved: AtomicU32,
+    pub counter_mult: AtomicU64,
+    pub counter_base: AtomicU64,
+    pub monotonic_base: AtomicU64,
+    pub realtime_offset: AtomicU64,
+    pub realtime_base: AtomicU64,
+    pub realtime_rate: AtomicI64,
+}
+
+const TIME_PAGE_COUNTER_SHIFT: u32 = 32;
+
+static TIME_PAGE: Once<Frame> = Once::new();
+/// Serializes updates of the time page.
+static TIME_PAGE_LOCK: Mutex<()> = Mutex::new(());
+
+/// The frame holding the time page, allocated on first use.
+pub fn time_page_frame() -> Frame {
+    *TIME_PAGE.call_once(|| {
+        // Keep a reference owned by the kernel, so that the frame is never freed when address
+        // spaces unmap it.
+        let frame = init_frame(RefCount::One).expect("failed to allocate time page");
+        unsafe {
+            (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+        }
+        frame
+    })
+}
+
+fn time_page() -> &'static TimePage {
+    unsafe { &*(RmmA::phys_to_virt(time_page_frame().base()).data() as *const TimePage) }
+}
+
+/// Publish the current clock parameters to the time page.
+pub fn update_time_page() {
+    let page = time_page();
+    let _guard = TIME_PAGE_LOCK.lock();
+
+    page.seq.fetch_add(1, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    // Userspace reads the counter of the current clock source directly, and adds the offset of
+    // the clock source through the bases. A negative offset is folded into the counter base, as
+    // the monotonic base is unsigned.
+    match crate::arch::time::user_counter_frequency() {
+        Some(frequency) => {
+            let mult = (NANOS_PER_SEC << TIME_PAGE_COUNTER_SHIFT) / u128::from(frequency);
+            let offset = i128::from(crate::clocksource::offset());
+            let counter_base = if offset < 0 {
+                (-offset) as u128 * u128::from(frequency) / NANOS_PER_SEC
+            } else {
+                0
+            };
+            let monotonic_base =
+                offset + ((counter_base * mult) >> TIME_PAGE_COUNTER_SHIFT) as i128;
+            page.counter_mult.store(mult as u64, Ordering::Relaxed);
+            page.counter_shift
+                .store(TIME_PAGE_COUNTER_SHIFT, Ordering::Relaxed);
+            page.counter_base
+                .store(counter_base as u64, Ordering::Relaxed);
+            page.monotonic_base
+                .store(monotonic_base.max(0) as u64, Ordering::Relaxed);
+            page.counter_usable.store(1, Ordering::Relaxed);
+        }
+        None => page.counter_usable.store(0, Ordering::Relaxed),
+    }
+    {
+        let adjustment = ADJUSTMENT.lock();
+        let offset = *START.lock() as i128 + adjustment.offset;
+        page.realtime_offset
+            .store(offset.max(0) as u64, Ordering::Relaxed);
+        page.realtime_base
+            .store(adjustment.base as u64, Ordering::Relaxed);
+        page.realtime_rate
+            .store(adjustment.rate_ppb(), Ordering::Relaxed);
+    }
+
+    page.seq.fetch_add(1, Ordering::Release);
+}
+
+pub fn sys_update_time_offset(buf: &[u8], token: &mut CleanLockToken) -> Result<usize> {
     let start = <[u8; 16]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
     *START.lock() = u128::from_ne_bytes(start);
+    {
+        // Setting the clock discards the adjustments made so far, but not the frequency.
+        let mut adjustment = ADJUSTMENT.lock();
+        adjustment.base = monotonic();
+        adjustment.offset = 0;
+        adjustment.slew_remaining = 0;
+    }
+    update_time_page();
+    crate::scheme::time::realtime_clock_set(token);
     Ok(16)
 }
+
+/// Whether the realtime clock is written to the RTC when the system is shut down.
+static RTC_SYNC_ON_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// The realtime clock, rounded to whole seconds as kept by the RTC.
+fn realtime_secs() -> u64 {
+    ((realtime() + NANOS_PER_SEC / 2) / NANOS_PER_SEC) as u64
+}
+
+pub fn rtc_sync_on_shutdown() -> bool {
+    RTC_SYNC_ON_SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// Write the RTC through `sys:rtc`. Accepts whitespace-separated `sync` to write the realtime
+/// clock to it, `set=<seconds>` to set it to a Unix time, and `sync_on_shutdown=<0|1>`.
+pub fn sys_rtc(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
+    let args = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    // Parse everything before applying anything.
+    let mut sync = false;
+    let mut set = None;
+    let mut sync_on_shutdown = None;
+    for arg in args.split_ascii_whitespace() {
+        match arg.split_once('=') {
+            None if arg == "sync" => sync = true,
+            Some(("set", value)) => {
+                set = Some(value.parse::<u64>().map_err(|_| Error::new(EINVAL))?);
+            }
+            Some(("sync_on_shutdown", "0")) => sync_on_shutdown = Some(false),
+            Some(("sync_on_shutdown", "1")) => sync_on_shutdown = Some(true),
+            _ => return Err(Error::new(EINVAL)),
+        }
+    }
+    if sync && set.is_some() {
+        return Err(Error::new(EINVAL));
+    }
+
+    if let Some(time) = set {
+        crate::arch::time::set_rtc_time(time)?;
+    }
+    if sync {
+        crate::arch::time::set_rtc_time(realtime_secs())?;
+    }
+    if let Some(sync_on_shutdown) = sync_on_shutdown {
+        RTC_SYNC_ON_SHUTDOWN.store(sync_on_shutdown, Ordering::Relaxed);
+    }
+    Ok(buf.len())
+}
+
+/// Write the realtime clock to the RTC if requested through `sys:rtc`. Called from `kstop`.
+pub fn sync_rtc_on_shutdown() {
+    if !rtc_sync_on_shutdown() {
+        return;
+    }
+    match crate::arch::time::set_rtc_time(realtime_secs()) {
+        Ok(()) => info!("Wrote realtime clock to RTC"),
+        Err(err) => warn!("Failed to write realtime clock to RTC: {}", err),
+    }
+}